@@ -1,7 +1,11 @@
 use std::collections::HashMap;
+use std::ops::Range;
 use super::error::BencodeError;
 use crate::bencode::bvalue::BValue;
 
+/// Byte ranges of a dictionary's values, keyed by dictionary key.
+pub type DictSpans = HashMap<String, Range<usize>>;
+
 pub fn decode_bencode(input: &[u8]) -> Result<(usize, BValue), BencodeError> {
     if input.is_empty() {
         return Err(BencodeError::UnexpectedEnd);
@@ -113,7 +117,24 @@ fn decode_list(encoded: &[u8]) -> Result<(usize, BValue), BencodeError> {
     Ok((idx + 1, BValue::List(items)))
 }
 
+/// Decodes a Bencoded dictionary of the form `d<key><value>...e`.
 fn decode_dict(encoded: &[u8]) -> Result<(usize, BValue), BencodeError> {
+    let (consumed, value, _spans) = decode_dict_with_spans(encoded)?;
+    Ok((consumed, value))
+}
+
+/// Decodes a Bencoded dictionary and additionally reports the byte span of each
+/// top-level value within `encoded`.
+///
+/// The spans let callers get at the exact original bytes of a value (e.g. the
+/// `info` dict of a .torrent), which a re-encode of the decoded `BValue` can't
+/// guarantee to reproduce.
+pub fn decode_dict_with_spans(
+    encoded: &[u8],
+) -> Result<(usize, BValue, DictSpans), BencodeError> {
+    if encoded.is_empty() {
+        return Err(BencodeError::UnexpectedEnd);
+    }
     if encoded[0] != b'd' {
 		return Err(BencodeError::InvalidFormat(format!(
 			"Dict must start with 'd' {} {}",
@@ -123,6 +144,7 @@ fn decode_dict(encoded: &[u8]) -> Result<(usize, BValue), BencodeError> {
 
     let mut idx = 1; // Skip the initial 'd'
     let mut map = HashMap::new();
+    let mut spans = HashMap::new();
 
     // Loop until we reach 'e' or run out of input
     while idx < encoded.len() && encoded[idx] != b'e' {
@@ -146,6 +168,7 @@ fn decode_dict(encoded: &[u8]) -> Result<(usize, BValue), BencodeError> {
 
         // Decode the value (can be int, string, list, or dict)
         let (consumed_val, value) = decode_bencode(&encoded[idx..])?;
+        spans.insert(key_str.clone(), idx..idx + consumed_val);
         idx += consumed_val;
 
         map.insert(key_str, value);
@@ -160,7 +183,7 @@ fn decode_dict(encoded: &[u8]) -> Result<(usize, BValue), BencodeError> {
     }
 
 	// add 1 to account for 'e'
-    Ok((idx + 1, BValue::Dict(map)))
+    Ok((idx + 1, BValue::Dict(map), spans))
 }
 
 
@@ -323,6 +346,17 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_decode_dict_with_spans() {
+        // d3:fooi42e4:infod1:xi1eee => spans point at "i42e" and "d1:xi1ee"
+        let input = b"d3:fooi42e4:infod1:xi1eee";
+        let (consumed, value, spans) = decode_dict_with_spans(input).unwrap();
+        assert_eq!(consumed, input.len());
+        assert_eq!(value, decode_bencode(input).unwrap().1);
+        assert_eq!(&input[spans["foo"].clone()], b"i42e");
+        assert_eq!(&input[spans["info"].clone()], b"d1:xi1ee");
+    }
+
     #[test]
     fn test_decode_dict_key_not_string() {
         // Suppose we try "i42e" as a key
@@ -70,7 +70,7 @@ pub fn bvalue_to_json(bv: &BValue) -> Value {
 		BValue::List(list_items) => {
             // Convert each element of the Vec<BValue> into JSON
             let json_items: Vec<Value> = list_items.iter()
-                .map(|item| bvalue_to_json(item))
+                .map(bvalue_to_json)
                 .collect();
             Value::Array(json_items)
         }
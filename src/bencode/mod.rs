@@ -4,6 +4,6 @@ pub mod encode;
 pub mod error;
 
 pub use bvalue::BValue;   // re-export
-pub use decode::decode_bencode;   // re-export
+pub use decode::{decode_bencode, decode_dict_with_spans};   // re-export
 pub use encode::{bvalue_to_json, encode_bvalue};   // re-export
 
@@ -0,0 +1,86 @@
+// bitfield.rs
+
+/// Which pieces a peer (or we) have, in the wire layout of the Bitfield
+/// message: the high bit of the first byte is piece 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bits: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    /// An empty bitfield for `len` pieces.
+    pub fn new(len: usize) -> Self {
+        Self {
+            bits: vec![0u8; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    pub fn has(&self, index: u32) -> bool {
+        let index = index as usize;
+        index < self.len && self.bits[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    /// Parses a Bitfield message payload for `len` pieces. Returns `None` if
+    /// the payload has the wrong size; spare bits at the end are cleared.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Option<Self> {
+        if bytes.len() != len.div_ceil(8) {
+            return None;
+        }
+        let mut bits = bytes.to_vec();
+        if !len.is_multiple_of(8) {
+            bits[len / 8] &= 0xff << (8 - len % 8);
+        }
+        Some(Self { bits, len })
+    }
+
+    /// Sets a piece; indices out of range are ignored.
+    pub fn set(&mut self, index: u32) {
+        let index = index as usize;
+        if index < self.len {
+            self.bits[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    /// Number of pieces the bitfield covers.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Number of pieces set.
+    pub fn count(&self) -> usize {
+        self.bits.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+
+    /// Indices of the pieces set.
+    pub fn pieces(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.len as u32).filter(|index| self.has(*index))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_and_wire_layout() {
+        let mut bitfield = Bitfield::new(10);
+        bitfield.set(0);
+        bitfield.set(9);
+        bitfield.set(10); // out of range
+        assert_eq!(bitfield.as_bytes(), &[0b1000_0000, 0b0100_0000]);
+        assert!(bitfield.has(9) && !bitfield.has(1) && !bitfield.has(10));
+        assert_eq!(bitfield.pieces().collect::<Vec<_>>(), vec![0, 9]);
+        assert_eq!(bitfield.count(), 2);
+        assert_eq!(bitfield.len(), 10);
+
+        let parsed = Bitfield::from_bytes(&[0b1000_0000, 0b0111_1111], 10).unwrap();
+        assert_eq!(parsed, bitfield);
+        assert!(Bitfield::from_bytes(&[0xff], 10).is_none());
+    }
+}
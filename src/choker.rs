@@ -0,0 +1,270 @@
+// choker.rs
+//! Tit-for-tat choking: decides which peers we upload to.
+//!
+//! Every `RECHOKE_INTERVAL` the interested peers that give us the most data
+//! (or, when seeding, take it the fastest) get the regular upload slots. One
+//! more, optimistic, slot goes to a random interested peer and rotates every
+//! `OPTIMISTIC_INTERVAL`, so new peers get a chance to prove themselves.
+//! Peers that stop sending us blocks (snub us) lose their regular slot.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+use tokio::sync::watch;
+
+/// How often the unchoked set is recomputed.
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often the optimistic unchoke moves to another peer.
+const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A peer we download from that sends no block for this long is snubbing us.
+const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct PeerStats {
+    downloaded: u64, // Bytes since the last rechoke
+    uploaded: u64,
+    download_rate: f64, // Bytes per second over the last round
+    upload_rate: f64,
+    last_block: Option<Instant>, // Set once we start downloading from the peer
+    interested: bool,
+    unchoke: Option<watch::Sender<bool>>, // Present while we have an upload connection
+}
+
+impl PeerStats {
+    fn is_unchoked(&self) -> bool {
+        self.unchoke.as_ref().is_some_and(|unchoke| *unchoke.borrow())
+    }
+
+    fn set_unchoked(&self, unchoked: bool) {
+        if let Some(unchoke) = &self.unchoke {
+            if *unchoke.borrow() != unchoked {
+                unchoke.send_replace(unchoked);
+            }
+        }
+    }
+}
+
+struct ChokerState {
+    peers: HashMap<[u8; 20], PeerStats>,
+    optimistic: Option<[u8; 20]>,
+    last_rechoke: Instant,
+    round: u64,
+}
+
+/// Per-torrent choker, keyed by the remote peer id so the blocks a download
+/// session receives count for the upload connection of the same peer.
+pub struct Choker {
+    slots: usize,
+    state: Mutex<ChokerState>,
+}
+
+impl Choker {
+    /// A choker with `slots` regular upload slots (plus one optimistic).
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots,
+            state: Mutex::new(ChokerState {
+                peers: HashMap::new(),
+                optimistic: None,
+                last_rechoke: Instant::now(),
+                round: 0,
+            }),
+        }
+    }
+
+    /// Registers an upload connection. The receiver tells the connection
+    /// whether the peer is unchoked; `None` if the peer is already connected.
+    pub fn connect(&self, peer_id: [u8; 20]) -> Option<watch::Receiver<bool>> {
+        let mut state = self.state.lock().unwrap();
+        let peer = state.peers.entry(peer_id).or_default();
+        if peer.unchoke.is_some() {
+            return None;
+        }
+        let (unchoke, receiver) = watch::channel(false);
+        peer.unchoke = Some(unchoke);
+        Some(receiver)
+    }
+
+    pub fn disconnect(&self, peer_id: &[u8; 20]) {
+        let mut state = self.state.lock().unwrap();
+        if state.optimistic == Some(*peer_id) {
+            state.optimistic = None;
+        }
+        if let Some(peer) = state.peers.get_mut(peer_id) {
+            peer.unchoke = None;
+            peer.interested = false;
+            if peer.last_block.is_none() {
+                state.peers.remove(peer_id);
+            }
+        }
+    }
+
+    /// Records the peer's interest. An interested peer is unchoked right away
+    /// while a regular slot is free; one that loses interest is choked.
+    pub fn set_interested(&self, peer_id: &[u8; 20], interested: bool) {
+        let mut state = self.state.lock().unwrap();
+        let unchoked = state.peers.values().filter(|peer| peer.is_unchoked()).count();
+        let Some(peer) = state.peers.get_mut(peer_id) else { return };
+        peer.interested = interested;
+        if !interested {
+            peer.set_unchoked(false);
+        } else if unchoked < self.slots {
+            peer.set_unchoked(true);
+        }
+    }
+
+    pub fn record_upload(&self, peer_id: &[u8; 20], bytes: u64) {
+        if let Some(peer) = self.state.lock().unwrap().peers.get_mut(peer_id) {
+            peer.uploaded += bytes;
+        }
+    }
+
+    /// Starts the snub timer for a peer we are about to download from.
+    pub fn start_download(&self, peer_id: [u8; 20]) {
+        let mut state = self.state.lock().unwrap();
+        state.peers.entry(peer_id).or_default().last_block.get_or_insert_with(Instant::now);
+    }
+
+    pub fn record_download(&self, peer_id: [u8; 20], bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        let peer = state.peers.entry(peer_id).or_default();
+        peer.downloaded += bytes;
+        peer.last_block = Some(Instant::now());
+    }
+
+    /// Recomputes the rates and the unchoked set. Called every
+    /// `RECHOKE_INTERVAL`; every third round also rotates the optimistic slot.
+    pub fn rechoke(&self, seeding: bool) {
+        let mut state = self.state.lock().unwrap();
+        let elapsed = state.last_rechoke.elapsed().max(Duration::from_millis(1)).as_secs_f64();
+        state.last_rechoke = Instant::now();
+        let rotate = state.round.is_multiple_of(OPTIMISTIC_INTERVAL.as_secs() / RECHOKE_INTERVAL.as_secs());
+        state.round += 1;
+
+        for peer in state.peers.values_mut() {
+            peer.download_rate = peer.downloaded as f64 / elapsed;
+            peer.upload_rate = peer.uploaded as f64 / elapsed;
+            peer.downloaded = 0;
+            peer.uploaded = 0;
+        }
+
+        let mut candidates: Vec<([u8; 20], f64, bool)> = state
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.interested && peer.unchoke.is_some())
+            .map(|(id, peer)| {
+                let snubbed = !seeding && peer.last_block.is_some_and(|last| last.elapsed() >= SNUB_TIMEOUT);
+                let rate = if seeding { peer.upload_rate } else { peer.download_rate };
+                (*id, rate, snubbed)
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        let regular: Vec<[u8; 20]> = candidates
+            .iter()
+            .filter(|(_, _, snubbed)| !snubbed)
+            .take(self.slots)
+            .map(|(id, _, _)| *id)
+            .collect();
+        let others: Vec<[u8; 20]> = candidates
+            .iter()
+            .map(|(id, _, _)| *id)
+            .filter(|id| !regular.contains(id))
+            .collect();
+
+        let current = state.optimistic;
+        let optimistic = current.filter(|id| !rotate && others.contains(id)).or_else(|| {
+            // Move on from the current optimistic peer when there is anyone else.
+            let fresh: Vec<_> = others.iter().filter(|id| Some(**id) != current).collect();
+            let pool = if fresh.is_empty() { others.iter().collect() } else { fresh };
+            pool.choose(&mut rand::thread_rng()).map(|id| **id)
+        });
+        state.optimistic = optimistic;
+        for (id, peer) in &state.peers {
+            peer.set_unchoked(regular.contains(id) || optimistic == Some(*id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unchoked(choker: &Choker) -> Vec<u8> {
+        let state = choker.state.lock().unwrap();
+        let mut ids: Vec<u8> = state.peers.iter().filter(|(_, peer)| peer.is_unchoked()).map(|(id, _)| id[0]).collect();
+        ids.sort();
+        ids
+    }
+
+    fn connect_interested(choker: &Choker, ids: &[u8]) -> Vec<watch::Receiver<bool>> {
+        ids.iter()
+            .map(|id| {
+                let receiver = choker.connect([*id; 20]).unwrap();
+                choker.set_interested(&[*id; 20], true);
+                receiver
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fastest_peers_win_and_snubbers_lose_their_slot() {
+        let choker = Choker::new(2);
+        let receivers = connect_interested(&choker, &[1, 2, 3, 4]);
+        // The first two interested peers are unchoked straight away.
+        assert_eq!(unchoked(&choker), vec![1, 2]);
+        assert!(*receivers[0].borrow() && !*receivers[2].borrow());
+        assert!(choker.connect([1; 20]).is_none());
+
+        choker.record_download([1; 20], 1_000);
+        choker.record_download([3; 20], 3_000);
+        choker.record_download([4; 20], 2_000);
+        // Peer 3 was fast once, but has sent nothing for over a minute since.
+        choker.state.lock().unwrap().peers.get_mut(&[3; 20]).unwrap().last_block =
+            Some(Instant::now() - SNUB_TIMEOUT - Duration::from_secs(1));
+
+        choker.rechoke(false);
+        let now_unchoked = unchoked(&choker);
+        assert_eq!(now_unchoked.len(), 3);
+        assert!(now_unchoked.contains(&4) && now_unchoked.contains(&1));
+        let optimistic = choker.state.lock().unwrap().optimistic.unwrap()[0];
+        assert!(optimistic == 2 || optimistic == 3);
+
+        // Once we seed, only upload rates count and nobody is snubbing us.
+        choker.record_upload(&[3; 20], 5_000);
+        choker.record_upload(&[2; 20], 4_000);
+        choker.rechoke(true);
+        let now_unchoked = unchoked(&choker);
+        assert!(now_unchoked.contains(&3) && now_unchoked.contains(&2));
+
+        // Losing interest chokes at once.
+        choker.set_interested(&[3; 20], false);
+        assert!(!unchoked(&choker).contains(&3));
+    }
+
+    #[test]
+    fn test_optimistic_slot_rotates_every_third_round() {
+        let choker = Choker::new(1);
+        let _receivers = connect_interested(&choker, &[1, 2, 3]);
+
+        let mut picks = Vec::new();
+        for _ in 0..4 {
+            choker.record_download([1; 20], 1_000);
+            choker.rechoke(false);
+            picks.push(choker.state.lock().unwrap().optimistic.unwrap()[0]);
+            assert_eq!(unchoked(&choker).len(), 2);
+            assert!(unchoked(&choker).contains(&1));
+        }
+        assert!(picks.iter().all(|id| *id == 2 || *id == 3));
+        assert_eq!(picks[0], picks[1]);
+        assert_eq!(picks[1], picks[2]);
+        assert_ne!(picks[2], picks[3]);
+
+        choker.disconnect(&[picks[3]; 20]);
+        choker.rechoke(false);
+        assert_eq!(unchoked(&choker), vec![1, 5 - picks[3]]);
+    }
+}
@@ -3,6 +3,7 @@ use std::fs;
 use std::path::Path;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub peer_id_prefix: String,
     pub listen_port: u16,
@@ -10,7 +11,19 @@ pub struct Config {
     pub piece_timeout: u64,
     pub request_timeout: u64,
     pub max_retries: u32,
+    pub pipeline_depth: usize,
+    pub max_pipeline_depth: usize,
+    pub upload_slots: usize,
     pub download_directory: String,
+    pub dht_enabled: bool,
+    pub dht_port: u16,
+    pub dht_bootstrap_nodes: Vec<String>,
+    pub dht_node_cache: String,
+    pub resume_directory: String,
+    pub metadata_cache: String,
+    pub lsd_enabled: bool,
+    pub lsd_port: u16,
+    pub lsd_interface: String,
 }
 
 impl Default for Config {
@@ -22,7 +35,23 @@ impl Default for Config {
             piece_timeout: 30, // seconds
             request_timeout: 10, // seconds
             max_retries: 3,
+            pipeline_depth: 32, // outstanding block requests per peer
+            max_pipeline_depth: 256,
+            upload_slots: 4, // regularly unchoked peers, plus one optimistic unchoke
             download_directory: ".".to_string(),
+            dht_enabled: true,
+            dht_port: 6881,
+            dht_bootstrap_nodes: vec![
+                "router.bittorrent.com:6881".to_string(),
+                "dht.transmissionbt.com:6881".to_string(),
+                "router.utorrent.com:6881".to_string(),
+            ],
+            dht_node_cache: "dht_nodes.dat".to_string(), // relative to download_directory
+            resume_directory: "resume".to_string(),      // relative to download_directory
+            metadata_cache: "metadata".to_string(),      // relative to download_directory
+            lsd_enabled: true,
+            lsd_port: 6771,
+            lsd_interface: "0.0.0.0".to_string(), // IPv4 address of the interface to announce on
         }
     }
 }
@@ -0,0 +1,21 @@
+use thiserror::Error;
+
+use crate::bencode::error::BencodeError;
+
+#[derive(Debug, Error)]
+pub enum DhtError {
+	#[error("Invalid KRPC message: {0}")]
+	InvalidMessage(String),
+
+	#[error("Bencode error: {0}")]
+	Bencode(#[from] BencodeError),
+
+	#[error("I/O error: {0}")]
+	Io(#[from] std::io::Error),
+
+	#[error("Query timed out")]
+	Timeout,
+
+	#[error("Remote error {code}: {message}")]
+	Remote { code: i64, message: String },
+}
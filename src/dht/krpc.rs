@@ -0,0 +1,318 @@
+// krpc.rs
+//! KRPC, the bencoded RPC protocol spoken between DHT nodes (BEP 5).
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use crate::bencode::{decode_bencode, encode_bvalue, BValue};
+use crate::dht::error::DhtError;
+use crate::dht::routing::NodeId;
+
+/// Size of a compact node info entry: 20-byte id, 4-byte IPv4, 2-byte port.
+pub const COMPACT_NODE_LEN: usize = 26;
+
+/// A DHT node we know how to reach.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+}
+
+/// The queries defined by BEP 5.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Ping,
+    FindNode { target: NodeId },
+    GetPeers { info_hash: [u8; 20] },
+    AnnouncePeer { info_hash: [u8; 20], port: u16, implied_port: bool, token: Vec<u8> },
+}
+
+/// The body of a response. Which fields are set depends on the query answered.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+    pub values: Vec<SocketAddrV4>,
+    pub token: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KrpcMessage {
+    Query { transaction_id: Vec<u8>, sender: NodeId, query: Query },
+    Response { transaction_id: Vec<u8>, response: Response },
+    Error { transaction_id: Vec<u8>, code: i64, message: String },
+}
+
+impl KrpcMessage {
+    pub fn transaction_id(&self) -> &[u8] {
+        match self {
+            KrpcMessage::Query { transaction_id, .. }
+            | KrpcMessage::Response { transaction_id, .. }
+            | KrpcMessage::Error { transaction_id, .. } => transaction_id,
+        }
+    }
+
+    /// Bencodes the message for the wire.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict = HashMap::new();
+        dict.insert("t".to_string(), BValue::ByteString(self.transaction_id().to_vec()));
+
+        match self {
+            KrpcMessage::Query { sender, query, .. } => {
+                let mut args = HashMap::new();
+                args.insert("id".to_string(), BValue::ByteString(sender.0.to_vec()));
+                let method = match query {
+                    Query::Ping => "ping",
+                    Query::FindNode { target } => {
+                        args.insert("target".to_string(), BValue::ByteString(target.0.to_vec()));
+                        "find_node"
+                    }
+                    Query::GetPeers { info_hash } => {
+                        args.insert("info_hash".to_string(), BValue::ByteString(info_hash.to_vec()));
+                        "get_peers"
+                    }
+                    Query::AnnouncePeer { info_hash, port, implied_port, token } => {
+                        args.insert("info_hash".to_string(), BValue::ByteString(info_hash.to_vec()));
+                        args.insert("port".to_string(), BValue::Integer(*port as i64));
+                        args.insert("implied_port".to_string(), BValue::Integer(*implied_port as i64));
+                        args.insert("token".to_string(), BValue::ByteString(token.clone()));
+                        "announce_peer"
+                    }
+                };
+                dict.insert("y".to_string(), BValue::ByteString(b"q".to_vec()));
+                dict.insert("q".to_string(), BValue::ByteString(method.as_bytes().to_vec()));
+                dict.insert("a".to_string(), BValue::Dict(args));
+            }
+            KrpcMessage::Response { response, .. } => {
+                let mut values = HashMap::new();
+                values.insert("id".to_string(), BValue::ByteString(response.id.0.to_vec()));
+                if !response.nodes.is_empty() {
+                    values.insert("nodes".to_string(), BValue::ByteString(encode_compact_nodes(&response.nodes)));
+                }
+                if !response.values.is_empty() {
+                    let peers = response
+                        .values
+                        .iter()
+                        .map(|addr| BValue::ByteString(encode_compact_peer(addr).to_vec()))
+                        .collect();
+                    values.insert("values".to_string(), BValue::List(peers));
+                }
+                if let Some(token) = &response.token {
+                    values.insert("token".to_string(), BValue::ByteString(token.clone()));
+                }
+                dict.insert("y".to_string(), BValue::ByteString(b"r".to_vec()));
+                dict.insert("r".to_string(), BValue::Dict(values));
+            }
+            KrpcMessage::Error { code, message, .. } => {
+                dict.insert("y".to_string(), BValue::ByteString(b"e".to_vec()));
+                dict.insert(
+                    "e".to_string(),
+                    BValue::List(vec![
+                        BValue::Integer(*code),
+                        BValue::ByteString(message.as_bytes().to_vec()),
+                    ]),
+                );
+            }
+        }
+        encode_bvalue(&BValue::Dict(dict))
+    }
+
+    /// Parses a datagram received from another node.
+    pub fn decode(bytes: &[u8]) -> Result<Self, DhtError> {
+        let (_consumed, value) = decode_bencode(bytes)?;
+        let dict = match value {
+            BValue::Dict(dict) => dict,
+            _ => return Err(invalid("Message must be a dictionary")),
+        };
+
+        let transaction_id = bytes_of(&dict, "t")?.to_vec();
+        match bytes_of(&dict, "y")? {
+            b"q" => {
+                let args = match dict.get("a") {
+                    Some(BValue::Dict(args)) => args,
+                    _ => return Err(invalid("Query without arguments")),
+                };
+                let sender = node_id_of(args, "id")?;
+                let query = match bytes_of(&dict, "q")? {
+                    b"ping" => Query::Ping,
+                    b"find_node" => Query::FindNode { target: node_id_of(args, "target")? },
+                    b"get_peers" => Query::GetPeers { info_hash: node_id_of(args, "info_hash")?.0 },
+                    b"announce_peer" => Query::AnnouncePeer {
+                        info_hash: node_id_of(args, "info_hash")?.0,
+                        port: match args.get("port") {
+                            Some(BValue::Integer(port)) => u16::try_from(*port)
+                                .map_err(|_| invalid("Port out of range"))?,
+                            _ => 0,
+                        },
+                        implied_port: matches!(args.get("implied_port"), Some(BValue::Integer(1))),
+                        token: bytes_of(args, "token")?.to_vec(),
+                    },
+                    other => {
+                        return Err(invalid(&format!(
+                            "Unknown method {}",
+                            String::from_utf8_lossy(other)
+                        )))
+                    }
+                };
+                Ok(KrpcMessage::Query { transaction_id, sender, query })
+            }
+            b"r" => {
+                let values = match dict.get("r") {
+                    Some(BValue::Dict(values)) => values,
+                    _ => return Err(invalid("Response without body")),
+                };
+                let nodes = match values.get("nodes") {
+                    Some(BValue::ByteString(compact)) => decode_compact_nodes(compact)?,
+                    _ => Vec::new(),
+                };
+                let peers = match values.get("values") {
+                    Some(BValue::List(items)) => items
+                        .iter()
+                        .filter_map(|item| match item {
+                            BValue::ByteString(compact) => decode_compact_peer(compact),
+                            _ => None,
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                let token = match values.get("token") {
+                    Some(BValue::ByteString(token)) => Some(token.clone()),
+                    _ => None,
+                };
+                let response = Response { id: node_id_of(values, "id")?, nodes, values: peers, token };
+                Ok(KrpcMessage::Response { transaction_id, response })
+            }
+            b"e" => {
+                let (code, message) = match dict.get("e") {
+                    Some(BValue::List(items)) => match items.as_slice() {
+                        [BValue::Integer(code), BValue::ByteString(message), ..] => {
+                            (*code, String::from_utf8_lossy(message).to_string())
+                        }
+                        _ => return Err(invalid("Malformed error list")),
+                    },
+                    _ => return Err(invalid("Error without body")),
+                };
+                Ok(KrpcMessage::Error { transaction_id, code, message })
+            }
+            _ => Err(invalid("Unknown message type")),
+        }
+    }
+}
+
+pub fn encode_compact_peer(addr: &SocketAddrV4) -> [u8; 6] {
+    let mut out = [0u8; 6];
+    out[..4].copy_from_slice(&addr.ip().octets());
+    out[4..].copy_from_slice(&addr.port().to_be_bytes());
+    out
+}
+
+pub fn decode_compact_peer(bytes: &[u8]) -> Option<SocketAddrV4> {
+    if bytes.len() != 6 {
+        return None;
+    }
+    let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+    Some(SocketAddrV4::new(ip, u16::from_be_bytes([bytes[4], bytes[5]])))
+}
+
+pub fn encode_compact_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(nodes.len() * COMPACT_NODE_LEN);
+    for node in nodes {
+        out.extend_from_slice(&node.id.0);
+        out.extend_from_slice(&encode_compact_peer(&node.addr));
+    }
+    out
+}
+
+pub fn decode_compact_nodes(bytes: &[u8]) -> Result<Vec<NodeInfo>, DhtError> {
+    if !bytes.len().is_multiple_of(COMPACT_NODE_LEN) {
+        return Err(invalid("Invalid compact nodes length"));
+    }
+    let nodes = bytes
+        .chunks_exact(COMPACT_NODE_LEN)
+        .filter_map(|chunk| {
+            let mut id = [0u8; 20];
+            id.copy_from_slice(&chunk[..20]);
+            decode_compact_peer(&chunk[20..]).map(|addr| NodeInfo { id: NodeId(id), addr })
+        })
+        .collect();
+    Ok(nodes)
+}
+
+fn invalid(message: &str) -> DhtError {
+    DhtError::InvalidMessage(message.to_string())
+}
+
+fn bytes_of<'a>(dict: &'a HashMap<String, BValue>, key: &str) -> Result<&'a [u8], DhtError> {
+    match dict.get(key) {
+        Some(BValue::ByteString(bytes)) => Ok(bytes),
+        _ => Err(invalid(&format!("Missing '{}'", key))),
+    }
+}
+
+fn node_id_of(dict: &HashMap<String, BValue>, key: &str) -> Result<NodeId, DhtError> {
+    let bytes = bytes_of(dict, key)?;
+    let id: [u8; 20] = bytes
+        .try_into()
+        .map_err(|_| invalid(&format!("'{}' must be 20 bytes", key)))?;
+    Ok(NodeId(id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(message: KrpcMessage) {
+        let encoded = message.encode();
+        assert_eq!(KrpcMessage::decode(&encoded).unwrap(), message);
+    }
+
+    #[test]
+    fn test_decode_bep5_ping() {
+        // Example straight from BEP 5.
+        let encoded = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        let message = KrpcMessage::decode(encoded).unwrap();
+        assert_eq!(message, KrpcMessage::Query {
+            transaction_id: b"aa".to_vec(),
+            sender: NodeId(*b"abcdefghij0123456789"),
+            query: Query::Ping,
+        });
+        assert_eq!(message.encode(), encoded);
+    }
+
+    #[test]
+    fn test_round_trip_queries() {
+        let sender = NodeId([1u8; 20]);
+        for query in [
+            Query::Ping,
+            Query::FindNode { target: NodeId([2u8; 20]) },
+            Query::GetPeers { info_hash: [3u8; 20] },
+            Query::AnnouncePeer { info_hash: [3u8; 20], port: 6881, implied_port: true, token: b"tok".to_vec() },
+        ] {
+            round_trip(KrpcMessage::Query { transaction_id: b"xy".to_vec(), sender, query });
+        }
+    }
+
+    #[test]
+    fn test_round_trip_responses_and_errors() {
+        round_trip(KrpcMessage::Response {
+            transaction_id: b"xy".to_vec(),
+            response: Response {
+                id: NodeId([9u8; 20]),
+                nodes: vec![NodeInfo { id: NodeId([4u8; 20]), addr: "10.0.0.1:6881".parse().unwrap() }],
+                values: vec!["127.0.0.1:5000".parse().unwrap()],
+                token: Some(b"secret".to_vec()),
+            },
+        });
+        round_trip(KrpcMessage::Error {
+            transaction_id: b"xy".to_vec(),
+            code: 201,
+            message: "A Generic Error Ocurred".to_string(),
+        });
+    }
+
+    #[test]
+    fn test_rejects_malformed() {
+        assert!(KrpcMessage::decode(b"le").is_err());
+        assert!(KrpcMessage::decode(b"d1:t2:aa1:y1:qe").is_err());
+        assert!(decode_compact_nodes(&[0u8; 25]).is_err());
+    }
+}
@@ -0,0 +1,521 @@
+// dht/mod.rs
+//! A Mainline DHT node (BEP 5) for trackerless peer discovery.
+//!
+//! The node answers `ping`, `find_node`, `get_peers` and `announce_peer`
+//! queries from others, and runs iterative lookups of its own to find the
+//! peers of an info hash.
+pub mod error;
+pub mod krpc;
+pub mod routing;
+
+pub use error::DhtError;
+pub use krpc::{KrpcMessage, NodeInfo, Query, Response};
+pub use routing::{NodeId, RoutingTable, K};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::Path;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::oneshot;
+use tokio::task::{JoinHandle, JoinSet};
+
+use crate::bencode::{decode_bencode, encode_bvalue, BValue};
+use krpc::{decode_compact_nodes, encode_compact_nodes};
+
+/// Number of queries in flight per lookup round.
+const ALPHA: usize = 3;
+
+/// How long to wait for a node to answer.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Tokens handed out in `get_peers` stay valid for up to two rotations.
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+/// Announced peers are forgotten after this long.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// KRPC error codes.
+const ERROR_PROTOCOL: i64 = 203;
+
+type PendingQueries = HashMap<Vec<u8>, oneshot::Sender<Result<Response, DhtError>>>;
+
+/// Peers announced to us, with the time they were announced.
+type PeerStorage = HashMap<[u8; 20], Vec<(SocketAddrV4, Instant)>>;
+
+struct TokenSecrets {
+    current: [u8; 16],
+    previous: [u8; 16],
+    rotated_at: Instant,
+}
+
+struct Inner {
+    socket: UdpSocket,
+    id: NodeId,
+    table: Mutex<RoutingTable>,
+    pending: Mutex<PendingQueries>,
+    storage: Mutex<PeerStorage>,
+    secrets: Mutex<TokenSecrets>,
+    next_transaction: AtomicU16,
+}
+
+/// A running DHT node. The receive loop stops when the node is dropped.
+pub struct Dht {
+    inner: Arc<Inner>,
+    recv_task: JoinHandle<()>,
+}
+
+/// What an iterative lookup found.
+struct Lookup {
+    /// Closest responding nodes, with the token each handed out (if any).
+    closest: Vec<(NodeInfo, Option<Vec<u8>>)>,
+    peers: Vec<SocketAddrV4>,
+}
+
+impl Dht {
+    /// Binds a node with a random id to `addr` (e.g. "0.0.0.0:6881").
+    pub async fn bind(addr: &str) -> Result<Self, DhtError> {
+        Self::bind_with_id(addr, NodeId::random()).await
+    }
+
+    /// Binds a node, reusing the id and nodes saved in `cache_path` if present.
+    pub async fn bind_with_cache(addr: &str, cache_path: &Path) -> Result<Self, DhtError> {
+        let (id, nodes) = match load_node_cache(cache_path) {
+            Ok(cache) => cache,
+            Err(e) => {
+                debug!("No usable DHT node cache at {}: {}", cache_path.display(), e);
+                (NodeId::random(), Vec::new())
+            }
+        };
+        let dht = Self::bind_with_id(addr, id).await?;
+        {
+            let mut table = dht.inner.table.lock().unwrap();
+            for node in nodes {
+                table.insert(node);
+            }
+        }
+        Ok(dht)
+    }
+
+    pub async fn bind_with_id(addr: &str, id: NodeId) -> Result<Self, DhtError> {
+        let socket = UdpSocket::bind(addr).await?;
+        let mut rng = rand::thread_rng();
+        let inner = Arc::new(Inner {
+            socket,
+            id,
+            table: Mutex::new(RoutingTable::new(id)),
+            pending: Mutex::new(HashMap::new()),
+            storage: Mutex::new(HashMap::new()),
+            secrets: Mutex::new(TokenSecrets {
+                current: rng.gen(),
+                previous: rng.gen(),
+                rotated_at: Instant::now(),
+            }),
+            next_transaction: AtomicU16::new(rng.gen()),
+        });
+        let recv_task = tokio::spawn(Arc::clone(&inner).recv_loop());
+        Ok(Self { inner, recv_task })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.inner.id
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, DhtError> {
+        Ok(self.inner.socket.local_addr()?)
+    }
+
+    /// Number of nodes in the routing table.
+    pub fn node_count(&self) -> usize {
+        self.inner.table.lock().unwrap().len()
+    }
+
+    /// Pings a node and returns its id.
+    pub async fn ping(&self, addr: SocketAddrV4) -> Result<NodeId, DhtError> {
+        Ok(self.inner.query(addr, Query::Ping).await?.id)
+    }
+
+    /// Joins the network through the given `host:port` nodes and any nodes
+    /// already in the routing table (e.g. from the node cache), then looks up
+    /// our own id to fill the table. Returns the routing table size.
+    pub async fn bootstrap(&self, bootstrap_nodes: &[String]) -> usize {
+        let mut addrs: Vec<SocketAddrV4> = self.inner.table.lock().unwrap().nodes().iter().map(|n| n.addr).collect();
+        for host in bootstrap_nodes {
+            match lookup_host(host.as_str()).await {
+                Ok(resolved) => addrs.extend(resolved.filter_map(|addr| match addr {
+                    SocketAddr::V4(v4) => Some(v4),
+                    SocketAddr::V6(_) => None,
+                })),
+                Err(e) => warn!("Could not resolve DHT bootstrap node {}: {}", host, e),
+            }
+        }
+
+        let target = self.inner.id;
+        let mut set = JoinSet::new();
+        for addr in addrs {
+            let inner = Arc::clone(&self.inner);
+            set.spawn(async move { inner.query(addr, Query::FindNode { target }).await });
+        }
+        while set.join_next().await.is_some() {}
+
+        self.find_node(target).await;
+        self.node_count()
+    }
+
+    /// Iteratively looks up the nodes closest to `target`.
+    pub async fn find_node(&self, target: NodeId) -> Vec<NodeInfo> {
+        let lookup = self.inner.lookup(target, false).await;
+        lookup.closest.into_iter().map(|(node, _)| node).collect()
+    }
+
+    /// Iteratively looks up peers for `info_hash`.
+    pub async fn get_peers(&self, info_hash: [u8; 20]) -> Vec<SocketAddrV4> {
+        self.inner.lookup(NodeId(info_hash), true).await.peers
+    }
+
+    /// Tells the nodes closest to `info_hash` that we are a peer for it.
+    ///
+    /// With `port` set to `None` the nodes use the source port of our
+    /// datagrams. Returns the peers found on the way and the number of nodes
+    /// that accepted the announce.
+    pub async fn announce_peer(&self, info_hash: [u8; 20], port: Option<u16>) -> (Vec<SocketAddrV4>, usize) {
+        let lookup = self.inner.lookup(NodeId(info_hash), true).await;
+
+        let mut set = JoinSet::new();
+        for (node, token) in lookup.closest {
+            let Some(token) = token else { continue };
+            let inner = Arc::clone(&self.inner);
+            let query = Query::AnnouncePeer {
+                info_hash,
+                port: port.unwrap_or(0),
+                implied_port: port.is_none(),
+                token,
+            };
+            set.spawn(async move { inner.query(node.addr, query).await });
+        }
+
+        let mut accepted = 0;
+        while let Some(result) = set.join_next().await {
+            if matches!(result, Ok(Ok(_))) {
+                accepted += 1;
+            }
+        }
+        (lookup.peers, accepted)
+    }
+
+    /// Writes our id and the routing table to `path` so the next run can
+    /// bootstrap without the public routers.
+    pub fn save_node_cache(&self, path: &Path) -> Result<(), DhtError> {
+        let nodes = self.inner.table.lock().unwrap().nodes();
+        let mut dict = HashMap::new();
+        dict.insert("id".to_string(), BValue::ByteString(self.inner.id.0.to_vec()));
+        dict.insert("nodes".to_string(), BValue::ByteString(encode_compact_nodes(&nodes)));
+        std::fs::write(path, encode_bvalue(&BValue::Dict(dict)))?;
+        Ok(())
+    }
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        self.recv_task.abort();
+    }
+}
+
+/// Reads a node cache written by `Dht::save_node_cache`.
+pub fn load_node_cache(path: &Path) -> Result<(NodeId, Vec<NodeInfo>), DhtError> {
+    let bytes = std::fs::read(path)?;
+    let (_consumed, value) = decode_bencode(&bytes)?;
+    let dict = match value {
+        BValue::Dict(dict) => dict,
+        _ => return Err(DhtError::InvalidMessage("Node cache must be a dictionary".to_string())),
+    };
+    let id = match dict.get("id") {
+        Some(BValue::ByteString(id)) if id.len() == 20 => {
+            let mut bytes = [0u8; 20];
+            bytes.copy_from_slice(id);
+            NodeId(bytes)
+        }
+        _ => return Err(DhtError::InvalidMessage("Node cache without id".to_string())),
+    };
+    let nodes = match dict.get("nodes") {
+        Some(BValue::ByteString(compact)) => decode_compact_nodes(compact)?,
+        _ => Vec::new(),
+    };
+    Ok((id, nodes))
+}
+
+impl Inner {
+    async fn recv_loop(self: Arc<Self>) {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    debug!("DHT receive failed: {}", e);
+                    continue;
+                }
+            };
+            let SocketAddr::V4(from) = from else { continue };
+
+            let message = match KrpcMessage::decode(&buf[..len]) {
+                Ok(message) => message,
+                Err(e) => {
+                    debug!("Dropping malformed DHT message from {}: {}", from, e);
+                    continue;
+                }
+            };
+
+            match message {
+                KrpcMessage::Query { transaction_id, sender, query } => {
+                    let reply = self.handle_query(transaction_id, sender, from, query);
+                    if let Err(e) = self.socket.send_to(&reply.encode(), from).await {
+                        debug!("DHT reply to {} failed: {}", from, e);
+                    }
+                }
+                KrpcMessage::Response { transaction_id, response } => {
+                    self.table.lock().unwrap().insert(NodeInfo { id: response.id, addr: from });
+                    if let Some(waiter) = self.pending.lock().unwrap().remove(&transaction_id) {
+                        let _ = waiter.send(Ok(response));
+                    }
+                }
+                KrpcMessage::Error { transaction_id, code, message } => {
+                    if let Some(waiter) = self.pending.lock().unwrap().remove(&transaction_id) {
+                        let _ = waiter.send(Err(DhtError::Remote { code, message }));
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle_query(&self, transaction_id: Vec<u8>, sender: NodeId, from: SocketAddrV4, query: Query) -> KrpcMessage {
+        self.table.lock().unwrap().insert(NodeInfo { id: sender, addr: from });
+
+        let mut response = Response { id: self.id, ..Default::default() };
+        match query {
+            Query::Ping => {}
+            Query::FindNode { target } => {
+                response.nodes = self.table.lock().unwrap().closest(&target, K);
+            }
+            Query::GetPeers { info_hash } => {
+                response.token = Some(self.make_token(from, false));
+                response.values = self.stored_peers(&info_hash);
+                response.nodes = self.table.lock().unwrap().closest(&NodeId(info_hash), K);
+            }
+            Query::AnnouncePeer { info_hash, port, implied_port, token } => {
+                if token != self.make_token(from, false) && token != self.make_token(from, true) {
+                    return KrpcMessage::Error {
+                        transaction_id,
+                        code: ERROR_PROTOCOL,
+                        message: "Bad token".to_string(),
+                    };
+                }
+                let port = if implied_port { from.port() } else { port };
+                let peer = SocketAddrV4::new(*from.ip(), port);
+                let mut storage = self.storage.lock().unwrap();
+                let peers = storage.entry(info_hash).or_default();
+                peers.retain(|(addr, _)| addr != &peer);
+                peers.push((peer, Instant::now()));
+            }
+        }
+        KrpcMessage::Response { transaction_id, response }
+    }
+
+    fn stored_peers(&self, info_hash: &[u8; 20]) -> Vec<SocketAddrV4> {
+        let mut storage = self.storage.lock().unwrap();
+        match storage.get_mut(info_hash) {
+            Some(peers) => {
+                peers.retain(|(_, added)| added.elapsed() < PEER_TTL);
+                peers.iter().map(|(addr, _)| *addr).collect()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Tokens bind a `get_peers` answer to the asker's IP.
+    fn make_token(&self, addr: SocketAddrV4, previous: bool) -> Vec<u8> {
+        let mut secrets = self.secrets.lock().unwrap();
+        if secrets.rotated_at.elapsed() > TOKEN_ROTATION {
+            secrets.previous = secrets.current;
+            secrets.current = rand::thread_rng().gen();
+            secrets.rotated_at = Instant::now();
+        }
+        let secret = if previous { secrets.previous } else { secrets.current };
+
+        let mut hasher = Sha1::new();
+        hasher.update(addr.ip().octets());
+        hasher.update(secret);
+        hasher.finalize()[..8].to_vec()
+    }
+
+    async fn query(&self, addr: SocketAddrV4, query: Query) -> Result<Response, DhtError> {
+        let transaction_id = self.next_transaction.fetch_add(1, Ordering::Relaxed).to_be_bytes().to_vec();
+        let message = KrpcMessage::Query { transaction_id: transaction_id.clone(), sender: self.id, query };
+
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(transaction_id.clone(), tx);
+        if let Err(e) = self.socket.send_to(&message.encode(), addr).await {
+            self.pending.lock().unwrap().remove(&transaction_id);
+            return Err(e.into());
+        }
+
+        match tokio::time::timeout(QUERY_TIMEOUT, rx).await {
+            Ok(Ok(result)) => result,
+            _ => {
+                self.pending.lock().unwrap().remove(&transaction_id);
+                self.table.lock().unwrap().remove(&addr);
+                Err(DhtError::Timeout)
+            }
+        }
+    }
+
+    /// Kademlia lookup: repeatedly query the `ALPHA` closest unqueried nodes
+    /// until none of the `K` closest known nodes is left unqueried.
+    async fn lookup(self: &Arc<Self>, target: NodeId, want_peers: bool) -> Lookup {
+        let mut candidates: BTreeMap<[u8; 20], NodeInfo> = self
+            .table
+            .lock()
+            .unwrap()
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| (node.id.distance(&target), node))
+            .collect();
+        let mut queried: HashSet<SocketAddrV4> = HashSet::new();
+        let mut responded: BTreeMap<[u8; 20], (NodeInfo, Option<Vec<u8>>)> = BTreeMap::new();
+        let mut peers = Vec::new();
+        let mut seen_peers = HashSet::new();
+
+        loop {
+            let batch: Vec<NodeInfo> = candidates
+                .values()
+                .take(K)
+                .filter(|node| !queried.contains(&node.addr))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let mut set = JoinSet::new();
+            for node in batch {
+                queried.insert(node.addr);
+                let inner = Arc::clone(self);
+                let query = if want_peers {
+                    Query::GetPeers { info_hash: target.0 }
+                } else {
+                    Query::FindNode { target }
+                };
+                set.spawn(async move { (node, inner.query(node.addr, query).await) });
+            }
+
+            while let Some(joined) = set.join_next().await {
+                let Ok((node, result)) = joined else { continue };
+                match result {
+                    Ok(response) => {
+                        let responder = NodeInfo { id: response.id, addr: node.addr };
+                        responded.insert(response.id.distance(&target), (responder, response.token));
+                        for found in response.nodes {
+                            if found.id != self.id {
+                                candidates.entry(found.id.distance(&target)).or_insert(found);
+                            }
+                        }
+                        for peer in response.values {
+                            if seen_peers.insert(peer) {
+                                peers.push(peer);
+                            }
+                        }
+                    }
+                    Err(_) => {
+                        candidates.remove(&node.id.distance(&target));
+                    }
+                }
+            }
+        }
+
+        Lookup {
+            closest: responded.into_values().take(K).collect(),
+            peers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v4(addr: SocketAddr) -> SocketAddrV4 {
+        match addr {
+            SocketAddr::V4(v4) => v4,
+            SocketAddr::V6(_) => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_local_swarm_announce_and_get_peers() {
+        let router = Dht::bind("127.0.0.1:0").await.unwrap();
+        let router_addr = router.local_addr().unwrap().to_string();
+
+        let mut nodes = Vec::new();
+        for _ in 0..4 {
+            let node = Dht::bind("127.0.0.1:0").await.unwrap();
+            node.bootstrap(std::slice::from_ref(&router_addr)).await;
+            nodes.push(node);
+        }
+        assert!(nodes.iter().all(|node| node.node_count() > 0));
+
+        let info_hash = [7u8; 20];
+        let (_peers, accepted) = nodes[0].announce_peer(info_hash, Some(5555)).await;
+        assert!(accepted > 0);
+
+        let (_peers, accepted) = nodes[1].announce_peer(info_hash, None).await;
+        assert!(accepted > 0);
+        let implied_port = nodes[1].local_addr().unwrap().port();
+
+        let peers = nodes[3].get_peers(info_hash).await;
+        assert!(peers.contains(&"127.0.0.1:5555".parse().unwrap()));
+        assert!(peers.contains(&SocketAddrV4::new([127, 0, 0, 1].into(), implied_port)));
+    }
+
+    #[tokio::test]
+    async fn test_ping_and_bad_token() {
+        let a = Dht::bind("127.0.0.1:0").await.unwrap();
+        let b = Dht::bind("127.0.0.1:0").await.unwrap();
+        let b_addr = v4(b.local_addr().unwrap());
+
+        assert_eq!(a.ping(b_addr).await.unwrap(), b.id());
+        assert_eq!(b.node_count(), 1);
+
+        let forged = Query::AnnouncePeer { info_hash: [1u8; 20], port: 1, implied_port: false, token: b"forged".to_vec() };
+        match a.inner.query(b_addr, forged).await {
+            Err(DhtError::Remote { code, .. }) => assert_eq!(code, ERROR_PROTOCOL),
+            other => panic!("expected a remote error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_node_cache_round_trip() {
+        let router = Dht::bind("127.0.0.1:0").await.unwrap();
+        let router_addr = router.local_addr().unwrap();
+        let node = Dht::bind("127.0.0.1:0").await.unwrap();
+        node.bootstrap(&[router_addr.to_string()]).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("dht_nodes.dat");
+        node.save_node_cache(&cache).unwrap();
+
+        let (id, nodes) = load_node_cache(&cache).unwrap();
+        assert_eq!(id, node.id());
+        assert_eq!(nodes, vec![NodeInfo { id: router.id(), addr: v4(router_addr) }]);
+
+        // A restarted node keeps its id and can bootstrap from the cache alone.
+        let restarted = Dht::bind_with_cache("127.0.0.1:0", &cache).await.unwrap();
+        assert_eq!(restarted.id(), node.id());
+        assert!(restarted.bootstrap(&[]).await > 0);
+    }
+}
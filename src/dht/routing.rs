@@ -0,0 +1,173 @@
+// routing.rs
+//! Kademlia routing table keyed by XOR distance to our own node id.
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::dht::krpc::NodeInfo;
+
+/// Number of nodes per bucket.
+pub const K: usize = 8;
+
+/// Nodes not heard from for this long may be replaced by fresh ones.
+const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// A 160-bit DHT node id, in the same space as info hashes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Serialize, Deserialize)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    pub fn random() -> Self {
+        let mut id = [0u8; 20];
+        rand::thread_rng().fill(&mut id);
+        NodeId(id)
+    }
+
+    /// XOR distance, comparable as a big-endian number.
+    pub fn distance(&self, other: &NodeId) -> [u8; 20] {
+        let mut out = [0u8; 20];
+        for (i, byte) in out.iter_mut().enumerate() {
+            *byte = self.0[i] ^ other.0[i];
+        }
+        out
+    }
+
+    /// Length of the common prefix with `other`, in bits (0..=160).
+    fn shared_prefix_len(&self, other: &NodeId) -> usize {
+        let distance = self.distance(other);
+        for (i, byte) in distance.iter().enumerate() {
+            if *byte != 0 {
+                return i * 8 + byte.leading_zeros() as usize;
+            }
+        }
+        160
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    node: NodeInfo,
+    last_seen: Instant,
+}
+
+/// One bucket per shared-prefix length with our own id, each holding up to `K` nodes.
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        Self {
+            own_id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    pub fn own_id(&self) -> NodeId {
+        self.own_id
+    }
+
+    /// Records that we heard from `node`.
+    ///
+    /// Known nodes are refreshed. New nodes go into their bucket if it has room
+    /// or if it holds a node that has gone quiet; otherwise they are dropped.
+    /// Returns whether the node is in the table afterwards.
+    pub fn insert(&mut self, node: NodeInfo) -> bool {
+        let prefix = self.own_id.shared_prefix_len(&node.id);
+        if prefix >= 160 {
+            return false; // that's us
+        }
+        let bucket = &mut self.buckets[prefix];
+
+        if let Some(pos) = bucket.iter().position(|e| e.node.id == node.id || e.node.addr == node.addr) {
+            let mut entry = bucket.remove(pos);
+            entry.node = node;
+            entry.last_seen = Instant::now();
+            bucket.push(entry);
+            return true;
+        }
+
+        if bucket.len() >= K {
+            match bucket.iter().position(|e| e.last_seen.elapsed() > QUESTIONABLE_AFTER) {
+                Some(pos) => {
+                    bucket.remove(pos);
+                }
+                None => return false,
+            }
+        }
+        bucket.push(Entry { node, last_seen: Instant::now() });
+        true
+    }
+
+    /// Forgets a node that stopped answering.
+    pub fn remove(&mut self, addr: &SocketAddrV4) {
+        for bucket in &mut self.buckets {
+            bucket.retain(|e| &e.node.addr != addr);
+        }
+    }
+
+    /// Returns up to `count` known nodes closest to `target`.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes = self.nodes();
+        nodes.sort_by_key(|node| node.id.distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.buckets.iter().flatten().map(|e| e.node).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(first_byte: u8, port: u16) -> NodeInfo {
+        let mut id = [0u8; 20];
+        id[0] = first_byte;
+        id[19] = port as u8;
+        NodeInfo { id: NodeId(id), addr: SocketAddrV4::new([127, 0, 0, 1].into(), port) }
+    }
+
+    #[test]
+    fn test_shared_prefix_len() {
+        let zero = NodeId([0u8; 20]);
+        let mut other = [0u8; 20];
+        other[0] = 0b0001_0000;
+        assert_eq!(zero.shared_prefix_len(&NodeId(other)), 3);
+        assert_eq!(zero.shared_prefix_len(&zero), 160);
+    }
+
+    #[test]
+    fn test_bucket_capacity_and_closest() {
+        let mut table = RoutingTable::new(NodeId([0u8; 20]));
+        // All of these share a 0-bit prefix with us and land in the same bucket.
+        for port in 0..(K as u16 + 4) {
+            table.insert(node(0x80, 1000 + port));
+        }
+        assert_eq!(table.len(), K);
+
+        // A node in a different bucket is still accepted.
+        assert!(table.insert(node(0x01, 2000)));
+        // Our own id never is.
+        assert!(!table.insert(NodeInfo { id: NodeId([0u8; 20]), addr: "127.0.0.1:1".parse().unwrap() }));
+
+        let closest = table.closest(&NodeId([0u8; 20]), 1);
+        assert_eq!(closest[0].addr.port(), 2000);
+
+        table.remove(&closest[0].addr);
+        assert_eq!(table.len(), K);
+    }
+}
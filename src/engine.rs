@@ -1,19 +1,47 @@
 // src/engine.rs
 use reqwest::Client;
-use std::collections::VecDeque;
+use std::collections::{HashSet, VecDeque};
 use std::error::Error;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::net::TcpStream;
-use log::{info, error};
+use log::{info, warn, error};
 
 use crate::bencode::{decode_bencode, bvalue_to_json};
-use crate::magnet::decode_magnet;
-use crate::torrent::Torrent;
+use crate::magnet::{decode_magnet, MagnetLink};
+use crate::magnet::metadata::{cache_metadata, fetch_metadata, load_cached_metadata, TorrentMetadata};
+use crate::torrent::{create_torrent, torrent_from_info, CreateOptions, Torrent, TorrentInfo};
 use crate::peer::Peer;
-use crate::tracker;
+use crate::tracker::TrackerTiers;
 use crate::utils;
+use crate::file_io;
 use crate::piece_queue::PieceQueue;
+use crate::pipeline::RequestPipeline;
+use crate::bitfield::Bitfield;
+use crate::listener::{ActiveTorrent, Listener};
+use crate::resume::{self, ResumeData};
+use crate::verify::{verify_pieces, PieceStatus};
+use crate::pex::Swarm;
+use rusbit_cli::config::Config;
+use rusbit_cli::dht::Dht;
+use rusbit_cli::lsd::{self, Lsd};
 use rusbit_cli::progress::ProgressTracker;
+use std::time::Duration;
+
+/// How often a seeding torrent is announced to its trackers again.
+const REANNOUNCE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// How often the state of a running download is saved for resuming.
+const RESUME_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Most peers on the local network remembered as already answered.
+const MAX_LSD_PEERS: usize = 1024;
+
+/// How long the trackers may take when peers were given up front; those peers
+/// are dialed without the trackers' peers once it passes.
+const DIRECT_PEERS_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub async fn decode_command(bencoded_string: String) -> Result<(), Box<dyn Error + Send + Sync>> {
     match decode_bencode(bencoded_string.as_bytes()) {
         Ok((_consumed, value)) => {
@@ -32,9 +60,20 @@ pub async fn info_command(torrent_file: String) -> Result<(), Box<dyn Error + Se
     match Torrent::from_file(&torrent_file) {
         Ok(torrent) => {
             println!("Info Hash: {}", hex::encode(torrent.info_hash));
-            println!("Tracker URL: {}", torrent.announce);
+            if let Some(announce) = &torrent.announce {
+                println!("Tracker URL: {}", announce);
+            }
+            for (tier, urls) in torrent.announce_list.iter().enumerate() {
+                println!("Tier {}: {}", tier, urls.join(", "));
+            }
             println!("File Name: {}", torrent.info.name);
             println!("Length: {}", torrent.info.length);
+            if let Some(files) = &torrent.info.files {
+                println!("Files:");
+                for file in files {
+                    println!("  {} ({} bytes)", file.path.join("/"), file.length);
+                }
+            }
             println!("Piece Length: {}", torrent.info.piece_length);
             println!("Number of Pieces: {}", torrent.info.pieces.len());
             for piece_hash in &torrent.info.pieces {
@@ -44,30 +83,19 @@ pub async fn info_command(torrent_file: String) -> Result<(), Box<dyn Error + Se
         }
         Err(err) => {
             error!("Error reading torrent: {:?}", err);
-            Err(err.into())
+            Err(err)
         }
     }
 }
 
 pub async fn peers_command(torrent_file: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let config = Config::load().map_err(|e| e.to_string())?;
     let http_client = Client::new();
     let peer_id = utils::generate_peer_id();
-    let uploaded = 0u64;
-    let downloaded = 0u64;
-    let port = 6881;
 
     let torrent = Torrent::from_file(&torrent_file)?;
-    let potential_peers = tracker::announce(
-        &http_client,
-        &torrent.announce,
-        &torrent.info_hash,
-        &peer_id,
-        uploaded,
-        downloaded,
-        torrent.info.length as u64,
-        port,
-    )
-    .await?;
+    let mut trackers = TrackerTiers::new(torrent.tracker_tiers());
+    let (potential_peers, _) = find_peers(&config, &http_client, &mut trackers, &torrent.info_hash, &peer_id, torrent.info.length as u64, config.listen_port, &[], torrent.info.is_private()).await?;
 
     for (ip, port) in potential_peers {
         let addr = format!("{}:{}", ip, port);
@@ -90,159 +118,231 @@ pub async fn handshake_command(torrent_file: String, peer_addr: String) -> Resul
 }
 
 pub async fn download_piece_command(output: String, torrent_file: String, piece_index: u32, _show_progress: bool) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let config = Config::load().map_err(|e| e.to_string())?;
     let http_client = Client::new();
     let peer_id = utils::generate_peer_id();
-    let port = 6881;
-    let uploaded = 0;
-    let downloaded = 0;
 
     let torrent = Torrent::from_file(&torrent_file)?;
-    let potential_peers = tracker::announce(
-        &http_client,
-        &torrent.announce,
-        &torrent.info_hash,
-        &peer_id,
-        uploaded,
-        downloaded,
-        torrent.info.length as u64,
-        port,
-    )
-    .await?;
+    let mut trackers = TrackerTiers::new(torrent.tracker_tiers());
+    let (potential_peers, _) = find_peers(&config, &http_client, &mut trackers, &torrent.info_hash, &peer_id, torrent.info.length as u64, config.listen_port, &[], torrent.info.is_private()).await?;
 
     if potential_peers.is_empty() {
         error!("No peers available");
         return Err("No peers available".into());
     }
 
-    let (ip, port) = &potential_peers[0];
-    let addr = format!("{}:{}", ip, port);
-
     // Create a piece queue containing only the one piece we want.
     let piece_queue = Arc::new(PieceQueue::new(VecDeque::from(vec![piece_index])));
 
-    let handle = tokio::spawn(async move {
-        while let Some(piece) = piece_queue.get_next_piece().await {
-            info!("Peer {} downloading piece {}", addr, piece);
-
-            match setup_peer(&torrent_file, &addr).await {
-                Ok((mut peer, stream)) => {
-                    if let Err(e) = peer
-                        .run_message_loop(stream, piece, &output, Arc::clone(&piece_queue), false, false, None)
-                        .await
-                    {
-                        error!("Error processing messages for {}: {}", addr, e);
-                    }
-                }
-                Err(e) => error!("Failed to setup peer {}: {}", addr, e),
-            }
-        }
-    });
-    handle.await?;
+    let job = DownloadJob {
+        info_hash: torrent.info_hash,
+        info: torrent.info,
+        output,
+        piece_queue,
+        full_file: false,
+        progress_tracker: None,
+        active_torrent: None,
+        swarm: None,
+    };
+    for handle in spawn_download_sessions(&config, &potential_peers[..1], &job) {
+        handle.await?;
+    }
     Ok(())
 }
 
-pub async fn download_command(output: String, torrent_file: String, show_progress: bool) -> Result<(), Box<dyn Error + Send + Sync>> {
+pub async fn download_command(output: String, torrent_file: String, peers: Vec<(String, u16)>, show_progress: bool) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let config = Config::load().map_err(|e| e.to_string())?;
     let http_client = Client::new();
     let peer_id = utils::generate_peer_id();
 
     let torrent = Torrent::from_file(&torrent_file)?;
-    let potential_peers = tracker::announce(
-        &http_client,
-        &torrent.announce,
-        &torrent.info_hash,
-        &peer_id,
-        0,
-        0,
-        torrent.info.length as u64,
-        6881,
-    )
-    .await?;
-
-    // Create a piece queue containing all piece indices.
-    let pieces: Vec<u32> = (0..torrent.info.pieces.len()).map(|i| i as u32).collect();
+    let num_pieces = torrent.info.pieces.len();
+
+    // Pick up where an earlier run stopped.
+    let resume = load_resume(&config, &torrent.info_hash);
+    let have = pieces_on_disk(resume.as_ref(), &torrent.info, &output).await;
+    if have.count() == num_pieces {
+        info!("All {} pieces are already downloaded", num_pieces);
+        return Ok(());
+    }
+    let left = missing_length(&torrent.info, &have);
+
+    // Serve the pieces we finish to other peers while downloading.
+    let listener = Listener::bind_port(config.listen_port, peer_id).await?;
+    // Peers are exchanged on incoming connections too.
+    let swarm = pex_swarm(&torrent.info);
+    let mut active_torrent = ActiveTorrent::new(
+        torrent.info_hash,
+        torrent.info.clone(),
+        torrent.info_bytes.clone(),
+        Path::new(&output),
+        have.clone(),
+        config.upload_slots,
+    );
+    active_torrent.swarm = swarm.clone();
+    let active_torrent = Arc::new(active_torrent);
+    listener.add_torrent(Arc::clone(&active_torrent));
+
+    let mut trackers = match &resume {
+        Some(resume) => TrackerTiers::restore(torrent.tracker_tiers(), &resume.trackers),
+        None => TrackerTiers::new(torrent.tracker_tiers()),
+    };
+    let (potential_peers, _dht) = find_peers(&config, &http_client, &mut trackers, &torrent.info_hash, &peer_id, left, listener.port(), &peers, torrent.info.is_private()).await?;
+
+    // Multi-file torrents are laid out in a directory named after the torrent.
+    file_io::create_file_layout(&torrent.info, Path::new(&output)).await?;
+    info!("Saving to {}", file_io::content_root(&torrent.info, Path::new(&output)).display());
+
+    // Create a piece queue containing the pieces we are missing.
+    let pieces: Vec<u32> = (0..num_pieces as u32).filter(|index| !have.has(*index)).collect();
     let piece_queue = Arc::new(PieceQueue::new(VecDeque::from(pieces)));
 
     // Create progress tracker
-    let progress_tracker = Arc::new(ProgressTracker::with_progress_bar(torrent.info.pieces.len(), show_progress));
+    let progress_tracker = Arc::new(ProgressTracker::with_progress_bar(num_pieces, show_progress));
+    progress_tracker.resume_from(have.count());
+
+    let job = DownloadJob {
+        info_hash: torrent.info_hash,
+        swarm,
+        info: torrent.info,
+        output,
+        piece_queue,
+        full_file: true,
+        progress_tracker: Some(Arc::clone(&progress_tracker)),
+        active_torrent: Some(active_torrent),
+    };
+    run_resumable_download(&config, &potential_peers, &job, &trackers, listener.port()).await?;
 
-    let mut handles: Vec<tokio::task::JoinHandle<()>> = Vec::new();
-    for (ip, port) in potential_peers {
-        let addr = format!("{}:{}", ip, port);
-        let torrent_path = torrent_file.clone();
-        let output_path = output.clone();
-        let pq = Arc::clone(&piece_queue);
-        let tracker = Arc::clone(&progress_tracker);
-
-        let handle: tokio::task::JoinHandle<()> = tokio::spawn(async move {
-            while let Some(piece) = pq.get_next_piece().await {
-                info!("Peer {} downloading piece {}", addr, piece);
-
-                match setup_peer(&torrent_path, &addr).await {
-                    Ok((mut peer, stream)) => {
-                        if let Err(e) = peer
-                            .run_message_loop(stream, piece, &output_path, Arc::clone(&pq), true, false, Some(Arc::clone(&tracker)))
-                            .await
-                        {
-                            error!("Error processing messages for {}: {}", addr, e);
-                        }
-                    }
-                    Err(e) => error!("Failed to setup peer {}: {}", addr, e),
-                }
+    // Finish progress tracking
+    progress_tracker.finish();
+    Ok(())
+}
+
+/// Seeds a torrent whose data is already on disk until interrupted.
+pub async fn seed_command(output: String, torrent_file: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let config = Config::load().map_err(|e| e.to_string())?;
+    let http_client = Client::new();
+    let peer_id = utils::generate_peer_id();
+
+    let torrent = Torrent::from_file(&torrent_file)?;
+    let have = verify_pieces(&torrent.info, Path::new(&output)).await.have();
+    info!("{}/{} pieces present in {}", have.count(), have.len(), file_io::content_root(&torrent.info, Path::new(&output)).display());
+    let left = missing_length(&torrent.info, &have);
+
+    let listener = Listener::bind_port(config.listen_port, peer_id).await?;
+    let mut active_torrent = ActiveTorrent::new(torrent.info_hash, torrent.info.clone(), torrent.info_bytes.clone(), Path::new(&output), have, config.upload_slots);
+    active_torrent.swarm = pex_swarm(&torrent.info);
+    listener.add_torrent(Arc::new(active_torrent));
+
+    let mut trackers = TrackerTiers::new(torrent.tracker_tiers());
+    let local_discovery = local_discovery(&config, &torrent.info, torrent.info_hash, listener.port(), None);
+    tokio::pin!(local_discovery);
+    loop {
+        if !trackers.is_empty() {
+            if let Err(e) = trackers.announce(&http_client, &torrent.info_hash, &peer_id, 0, 0, left, listener.port()).await {
+                warn!("Announce failed: {}", e);
             }
+        }
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            _ = tokio::time::sleep(REANNOUNCE_INTERVAL) => {}
+            _ = &mut local_discovery => {}
+        }
+    }
+    info!("Stopped seeding");
+    Ok(())
+}
+
+/// Hashes the data at `output` and reports which pieces are good, bad or
+/// missing, as text or as JSON.
+pub async fn verify_command(output: String, torrent_file: String, json: bool) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let torrent = Torrent::from_file(&torrent_file)?;
+    let report = verify_pieces(&torrent.info, Path::new(&output)).await;
+    let good = report.with_status(PieceStatus::Good);
+    let bad = report.with_status(PieceStatus::Bad);
+    let missing = report.with_status(PieceStatus::Missing);
+
+    if json {
+        let summary = serde_json::json!({
+            "info_hash": hex::encode(torrent.info_hash),
+            "path": file_io::content_root(&torrent.info, Path::new(&output)),
+            "pieces": report.pieces.len(),
+            "good": good.len(),
+            "bad": bad,
+            "missing": missing,
+            "complete": good.len() == report.pieces.len(),
         });
-        handles.push(handle);
+        println!("{}", serde_json::to_string(&summary)?);
+        return Ok(());
     }
-    for handle in handles {
-        handle.await?;
+
+    println!("Path: {}", file_io::content_root(&torrent.info, Path::new(&output)).display());
+    println!("Good: {}/{}", good.len(), report.pieces.len());
+    println!("Bad: {}", bad.len());
+    println!("Missing: {}", missing.len());
+    if !bad.is_empty() {
+        println!("Bad pieces: {}", join_indices(&bad));
     }
+    if !missing.is_empty() {
+        println!("Missing pieces: {}", join_indices(&missing));
+    }
+    Ok(())
+}
 
-    // Finish progress tracking
-    progress_tracker.finish();
+fn join_indices(indices: &[u32]) -> String {
+    indices.iter().map(|index| index.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+/// Writes a .torrent for the file or directory at `path` to `output`.
+pub async fn create_command(path: String, output: String, options: CreateOptions) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Hashing blocks, so keep it off the runtime.
+    let encoded = tokio::task::spawn_blocking(move || create_torrent(Path::new(&path), &options)).await??;
+    tokio::fs::write(&output, &encoded).await?;
+
+    let torrent = Torrent::from_bytes(&encoded)?;
+    println!("Created {}", output);
+    println!("Info Hash: {}", hex::encode(torrent.info_hash));
+    println!("Piece Length: {}", torrent.info.piece_length);
+    println!("Number of Pieces: {}", torrent.info.pieces.len());
+    Ok(())
+}
+
+pub async fn magnet_command(torrent_file: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let torrent = Torrent::from_file(&torrent_file)?;
+    println!("{}", torrent.to_magnet());
     Ok(())
 }
 
 pub async fn magnet_parse_command(magnet_link: String) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let magnet_map = decode_magnet(&magnet_link)?;
-    let info_hash = magnet_map.get("info_hash").unwrap();
-    let announce = magnet_map.get("announce").unwrap();
-    println!("Tracker URL: {}", announce);
-    println!("Info Hash: {}", info_hash);
+    let magnet = decode_magnet(&magnet_link)?;
+    for url in &magnet.trackers {
+        println!("Tracker URL: {}", url);
+    }
+    println!("Info Hash: {}", hex::encode(magnet.v1_info_hash()?));
     Ok(())
 }
 
 pub async fn magnet_handshake_command(magnet_link: String) -> Result<(), Box<dyn Error + Send + Sync>> {
     // Parse magnet link.
-    let magnet_map = decode_magnet(&magnet_link)?;
-    let info_hash = magnet_map.get("info_hash").unwrap();
-    let announce = magnet_map.get("announce").unwrap();
-    println!("Tracker URL: {}", announce);
+    let magnet = decode_magnet(&magnet_link)?;
+    let info_hash_bytes = magnet.v1_info_hash()?;
+    let announce_urls = &magnet.trackers;
+    for url in announce_urls {
+        println!("Tracker URL: {}", url);
+    }
+    let config = Config::load().map_err(|e| e.to_string())?;
     let http_client = Client::new();
     let peer_id = utils::generate_peer_id();
 
-    let info_hash_bytes: [u8; 20] = {
-        let mut bytes = [0u8; 20];
-        hex::decode_to_slice(info_hash, &mut bytes)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-        bytes
-    };
-
-    // Announce to tracker to get a list of potential peers.
-    let potential_peers = tracker::announce(
-        &http_client,
-        announce,
-        &info_hash_bytes,
-        &peer_id,
-        0,
-        0,
-        10,
-        6881,
-    )
-    .await?;
+    // Announce to the trackers (or fall back to the DHT) to get a list of potential peers.
+    // Each magnet tracker forms its own tier.
+    let mut trackers = TrackerTiers::new(announce_urls.iter().map(|url| vec![url.clone()]).collect());
+    let (potential_peers, _) = find_peers(&config, &http_client, &mut trackers, &info_hash_bytes, &peer_id, 10, config.listen_port, &magnet.peers, false).await?;
 
     // For metadata retrieval, connect to the first available peer.
     let (ip, port) = potential_peers
         .first()
-        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "No peers available"))?;
+        .ok_or_else(|| std::io::Error::other("No peers available"))?;
     let addr = format!("{}:{}", ip, port);
     println!("Using peer {} for metadata", addr);
 
@@ -250,15 +350,7 @@ pub async fn magnet_handshake_command(magnet_link: String) -> Result<(), Box<dyn
     let mut meta_peer = Peer::new(info_hash_bytes, utils::generate_peer_id(), None);
     let stream = meta_peer.connect_and_handshake(&addr, true).await?;
 
-    meta_peer.run_message_loop(
-        stream,
-        0,
-        "test.rs",
-        Arc::new(PieceQueue::new(VecDeque::new())),
-        false, 
-        false,
-        None
-    ).await?;
+    meta_peer.run_message_loop(stream).await?;
 
     if let Some(remote_id) = meta_peer.remote_peer_id {
         println!("Peer ID: {}", hex::encode(remote_id));
@@ -268,65 +360,36 @@ pub async fn magnet_handshake_command(magnet_link: String) -> Result<(), Box<dyn
     Ok(())
 }
 
-pub async fn magnet_info_command(magnet_link: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+pub async fn magnet_info_command(magnet_link: String, peers: Vec<(String, u16)>) -> Result<(), Box<dyn Error + Send + Sync>> {
     // Parse magnet link.
-    let magnet_map = decode_magnet(&magnet_link)?;
-    let info_hash = magnet_map.get("info_hash").unwrap();
-    let announce = magnet_map.get("announce").unwrap();
-    println!("Tracker URL: {}", announce);
+    let mut magnet = decode_magnet(&magnet_link)?;
+    magnet.peers.extend(peers);
+    let info_hash_bytes = magnet.v1_info_hash()?;
+    let announce_urls = &magnet.trackers;
+    for url in announce_urls {
+        println!("Tracker URL: {}", url);
+    }
+    let config = Config::load().map_err(|e| e.to_string())?;
     let http_client = Client::new();
     let peer_id = utils::generate_peer_id();
 
-    let info_hash_bytes: [u8; 20] = {
-        let mut bytes = [0u8; 20];
-        hex::decode_to_slice(info_hash, &mut bytes)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-        bytes
+    // Only go looking for peers if the metadata is not cached yet.
+    let metadata = match cached_metadata(&config, &info_hash_bytes) {
+        Some(metadata) => metadata,
+        None => {
+            // Announce to the trackers (or fall back to the DHT) to get a list of potential peers.
+            // Each magnet tracker forms its own tier.
+            let mut trackers = TrackerTiers::new(announce_urls.iter().map(|url| vec![url.clone()]).collect());
+            let (potential_peers, _) = find_peers(&config, &http_client, &mut trackers, &info_hash_bytes, &peer_id, 10, config.listen_port, &magnet.peers, false).await?;
+            fetch_and_cache_metadata(&config, &potential_peers, &info_hash_bytes).await?
+        }
     };
-
-    // Announce to tracker to get a list of potential peers.
-    let potential_peers = tracker::announce(
-        &http_client,
-        announce,
-        &info_hash_bytes,
-        &peer_id,
-        0,
-        0,
-        10,
-        6881,
-    )
-    .await?;
-
-    let (ip, port) = potential_peers
-        .first()
-        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "No peers available"))?;
-    let addr = format!("{}:{}", ip, port);
-    println!("Using peer {} for metadata", addr);
-
-    // Create a temporary peer instance to fetch metadata.
-    let mut meta_peer = Peer::new(info_hash_bytes, utils::generate_peer_id(), None);
-    let stream = meta_peer.connect_and_handshake(&addr, true).await?;
-
-    meta_peer.run_message_loop(
-        stream,
-        0,
-        "test.rs",
-        Arc::new(PieceQueue::new(VecDeque::new())),
-        false, 
-        true,
-        None
-    ).await?;
-
-    if let Some(remote_id) = meta_peer.remote_peer_id {
-        println!("Peer ID: {}", hex::encode(remote_id));
-    } else {
-        println!("Failed to fetch remote id");
+    print_metadata_source(&metadata);
+    let info = metadata.info;
+    println!("Info Hash: {}", hex::encode(info_hash_bytes));
+    for url in announce_urls {
+        println!("Tracker URL: {}", url);
     }
-
-    // Retrieve the torrent metadata.
-    let info = meta_peer.get_torrent_info()?;
-    println!("Info Hash: {}", hex::encode(meta_peer.info_hash));
-    println!("Tracker URL: {}", announce);
     println!("File Name: {}", info.name);
     println!("Length: {}", info.length);
     println!("Piece Length: {}", info.piece_length);
@@ -337,68 +400,63 @@ pub async fn magnet_info_command(magnet_link: String) -> Result<(), Box<dyn Erro
     Ok(())
 }
 
-pub async fn magnet_download_piece_command(output: String, magnet_link: String, piece_index: u32, _show_progress: bool) -> Result<(), Box<dyn Error + Send + Sync>> {
+pub async fn magnet_to_torrent_command(output: String, magnet_link: String) -> Result<(), Box<dyn Error + Send + Sync>> {
     // Parse magnet link.
-    let magnet_map = decode_magnet(&magnet_link)?;
-    let info_hash = magnet_map.get("info_hash").unwrap();
-    let announce = magnet_map.get("announce").unwrap();
-    println!("Tracker URL: {}", announce);
-
+    let magnet = decode_magnet(&magnet_link)?;
+    let info_hash_bytes = magnet.v1_info_hash()?;
+    let announce_urls = &magnet.trackers;
+    let config = Config::load().map_err(|e| e.to_string())?;
     let http_client = Client::new();
     let peer_id = utils::generate_peer_id();
 
-    let info_hash_bytes: [u8; 20] = {
-        let mut bytes = [0u8; 20];
-        hex::decode_to_slice(info_hash, &mut bytes)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-        bytes
+    // Only go looking for peers if the metadata is not cached yet.
+    let metadata = match cached_metadata(&config, &info_hash_bytes) {
+        Some(metadata) => metadata,
+        None => {
+            let mut trackers = TrackerTiers::new(announce_urls.iter().map(|url| vec![url.clone()]).collect());
+            let (potential_peers, _) = find_peers(&config, &http_client, &mut trackers, &info_hash_bytes, &peer_id, 10, config.listen_port, &magnet.peers, false).await?;
+            fetch_and_cache_metadata(&config, &potential_peers, &info_hash_bytes).await?
+        }
     };
+    print_metadata_source(&metadata);
+    save_magnet_torrent(&output, &magnet, &metadata).await?;
+    println!("Info Hash: {}", hex::encode(info_hash_bytes));
+    println!("File Name: {}", metadata.info.name);
+    Ok(())
+}
 
-    // Announce to tracker to get a list of potential peers.
-    let potential_peers = tracker::announce(
-        &http_client,
-        announce,
-        &info_hash_bytes,
-        &peer_id,
-        0,
-        0,
-        10,
-        6881,
-    )
-    .await?;
-
-    let (ip, port) = potential_peers
-        .first()
-        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "No peers available"))?;
-    let addr = format!("{}:{}", ip, port);
-    println!("Using peer {} for metadata", addr);
+pub async fn magnet_download_piece_command(output: String, magnet_link: String, piece_index: u32, save_torrent: Option<String>, _show_progress: bool) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Parse magnet link.
+    let magnet = decode_magnet(&magnet_link)?;
+    let info_hash_bytes = magnet.v1_info_hash()?;
+    let announce_urls = &magnet.trackers;
+    for url in announce_urls {
+        println!("Tracker URL: {}", url);
+    }
 
-    // Create a temporary peer instance to fetch metadata.
-    let mut meta_peer = Peer::new(info_hash_bytes, utils::generate_peer_id(), None);
-    let stream = meta_peer.connect_and_handshake(&addr, true).await?;
+    let config = Config::load().map_err(|e| e.to_string())?;
+    let http_client = Client::new();
+    let peer_id = utils::generate_peer_id();
 
-    meta_peer
-        .run_message_loop(
-            stream,
-            0,
-            "test.rs",
-            Arc::new(PieceQueue::new(VecDeque::new())),
-            false, 
-            true,
-            None
-        )
-        .await?;
+    // Announce to the trackers (or fall back to the DHT) to get a list of potential peers.
+    // Each magnet tracker forms its own tier.
+    let mut trackers = TrackerTiers::new(announce_urls.iter().map(|url| vec![url.clone()]).collect());
+    let (potential_peers, _) = find_peers(&config, &http_client, &mut trackers, &info_hash_bytes, &peer_id, 10, config.listen_port, &magnet.peers, false).await?;
 
-    if let Some(remote_id) = meta_peer.remote_peer_id {
-        println!("Peer ID: {}", hex::encode(remote_id));
-    } else {
-        println!("Failed to fetch remote id");
+    // Fetch the info dict from the first peer that can provide it.
+    let metadata = match cached_metadata(&config, &info_hash_bytes) {
+        Some(metadata) => metadata,
+        None => fetch_and_cache_metadata(&config, &potential_peers, &info_hash_bytes).await?,
+    };
+    print_metadata_source(&metadata);
+    if let Some(path) = &save_torrent {
+        save_magnet_torrent(path, &magnet, &metadata).await?;
+    }
+    let info = metadata.info;
+    println!("Info Hash: {}", hex::encode(info_hash_bytes));
+    for url in announce_urls {
+        println!("Tracker URL: {}", url);
     }
-
-    // Retrieve the torrent metadata.
-    let info = meta_peer.get_torrent_info()?;
-    println!("Info Hash: {}", hex::encode(meta_peer.info_hash));
-    println!("Tracker URL: {}", announce);
     println!("File Name: {}", info.name);
     println!("Length: {}", info.length);
     println!("Piece Length: {}", info.piece_length);
@@ -410,59 +468,18 @@ pub async fn magnet_download_piece_command(output: String, magnet_link: String,
     // Create a piece queue containing only the target piece.
     let full_piece_queue = Arc::new(PieceQueue::new(VecDeque::from(vec![piece_index])));
 
-    // Now spawn download tasks for each available peer.
-    let mut handles = Vec::new();
-    for (ip, port) in potential_peers {
-        let addr = format!("{}:{}", ip, port);
-        // Clone the owned data for use in the async task.
-        let info_clone = info.clone();
-        let piece_queue_clone = Arc::clone(&full_piece_queue);
-        let info_hash_bytes_clone = info_hash_bytes;
-        let addr_clone = addr.clone();
-        let output_path_clone = output.clone();
-
-        let handle: tokio::task::JoinHandle<()> = tokio::spawn(async move {
-            // For each piece, create a new connection.
-            while let Some(piece) = piece_queue_clone.get_next_piece().await {
-                info!("Peer {} downloading piece {}", addr_clone, piece);
-
-                // Establish a fresh connection for each piece.
-                match Peer::new(info_hash_bytes_clone, utils::generate_peer_id(), Some(info_clone.clone()))
-                    .connect_and_handshake(&addr_clone, false)
-                    .await
-                {
-                    Ok(stream) => {
-                        // Create a new peer instance for this connection.
-                        let mut download_peer = Peer::new(
-                            info_hash_bytes_clone,
-                            utils::generate_peer_id(),
-                            Some(info_clone.clone()),
-                        );
-
-                        if let Err(e) = download_peer
-                            .run_message_loop(
-                                stream,
-                                piece,
-                                &output_path_clone,
-                                Arc::clone(&piece_queue_clone),
-                                false,
-                                false,
-                                None
-                            )
-                            .await
-                        {
-                            error!("Error processing messages for {}: {}", addr_clone, e);
-                        }
-                    }
-                    Err(e) => {
-                        error!("Failed to setup peer {}: {}", addr_clone, e);
-                    }
-                }
-            }
-        });
-
-        handles.push(handle);
-    }
+    // Download over one connection per available peer.
+    let job = DownloadJob {
+        info_hash: info_hash_bytes,
+        info,
+        output,
+        piece_queue: full_piece_queue,
+        full_file: false,
+        progress_tracker: None,
+        active_torrent: None,
+        swarm: None,
+    };
+    let handles = spawn_download_sessions(&config, &potential_peers, &job);
 
     // Wait for all download tasks to complete.
     for handle in handles {
@@ -471,65 +488,49 @@ pub async fn magnet_download_piece_command(output: String, magnet_link: String,
     Ok(())
 }
 
-pub async fn magnet_download_command(output: String, magnet_link: String, show_progress: bool) -> Result<(), Box<dyn Error + Send + Sync>> {
+pub async fn magnet_download_command(output: String, magnet_link: String, save_torrent: Option<String>, peers: Vec<(String, u16)>, show_progress: bool) -> Result<(), Box<dyn Error + Send + Sync>> {
     // Parse magnet link.
-    let magnet_map = decode_magnet(&magnet_link)?;
-    let info_hash = magnet_map.get("info_hash").unwrap();
-    let announce = magnet_map.get("announce").unwrap();
-    println!("Tracker URL: {}", announce);
+    let mut magnet = decode_magnet(&magnet_link)?;
+    magnet.peers.extend(peers);
+    let info_hash_bytes = magnet.v1_info_hash()?;
+    let announce_urls = &magnet.trackers;
+    for url in announce_urls {
+        println!("Tracker URL: {}", url);
+    }
+    let config = Config::load().map_err(|e| e.to_string())?;
     let http_client = Client::new();
     let peer_id = utils::generate_peer_id();
 
-    let info_hash_bytes: [u8; 20] = {
-        let mut bytes = [0u8; 20];
-        hex::decode_to_slice(info_hash, &mut bytes)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-        bytes
-    };
-
-    // Announce to tracker to get a list of potential peers.
-    let potential_peers = tracker::announce(
-        &http_client,
-        announce,
-        &info_hash_bytes,
-        &peer_id,
-        0,
-        0,
-        10,
-        6881,
-    )
-    .await?;
-
-    let (ip, port) = potential_peers
-        .first()
-        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "No peers available"))?;
-    let addr = format!("{}:{}", ip, port);
-    println!("Using peer {} for metadata", addr);
-
-    // Create a temporary peer instance to fetch metadata.
-    let mut meta_peer = Peer::new(info_hash_bytes, utils::generate_peer_id(), None);
-    let stream = meta_peer.connect_and_handshake(&addr, true).await?;
+    // Serve the pieces we finish to other peers while downloading.
+    let listener = Listener::bind_port(config.listen_port, peer_id).await?;
 
-    meta_peer.run_message_loop(
-        stream,
-        0,
-        "test.rs",
-        Arc::new(PieceQueue::new(VecDeque::new())),
-        false, 
-        true,
-        None
-    ).await?;
+    // Announce to the trackers (or fall back to the DHT) to get a list of potential peers.
+    // Each magnet tracker forms its own tier, in the order an earlier run left them.
+    let resume = load_resume(&config, &info_hash_bytes);
+    let tiers = announce_urls.iter().map(|url| vec![url.clone()]).collect();
+    let mut trackers = match &resume {
+        Some(resume) => TrackerTiers::restore(tiers, &resume.trackers),
+        None => TrackerTiers::new(tiers),
+    };
+    let (potential_peers, dht) = find_peers(&config, &http_client, &mut trackers, &info_hash_bytes, &peer_id, 10, listener.port(), &magnet.peers, false).await?;
 
-    if let Some(remote_id) = meta_peer.remote_peer_id {
-        println!("Peer ID: {}", hex::encode(remote_id));
-    } else {
-        println!("Failed to fetch remote id");
+    // Fetch the info dict from the first peer that can provide it.
+    let metadata = match cached_metadata(&config, &info_hash_bytes) {
+        Some(metadata) => metadata,
+        None => fetch_and_cache_metadata(&config, &potential_peers, &info_hash_bytes).await?,
+    };
+    print_metadata_source(&metadata);
+    if let Some(path) = &save_torrent {
+        save_magnet_torrent(path, &magnet, &metadata).await?;
+    }
+    let info_bytes = metadata.bytes;
+    let info = metadata.info;
+    // Only now do we know whether the torrent is private and must leave the DHT.
+    let _dht = dht.filter(|_| !info.is_private());
+    println!("Info Hash: {}", hex::encode(info_hash_bytes));
+    for url in announce_urls {
+        println!("Tracker URL: {}", url);
     }
-
-    // Retrieve the torrent metadata.
-    let info = meta_peer.get_torrent_info()?;
-    println!("Info Hash: {}", hex::encode(meta_peer.info_hash));
-    println!("Tracker URL: {}", announce);
     println!("File Name: {}", info.name);
     println!("Length: {}", info.length);
     println!("Piece Length: {}", info.piece_length);
@@ -538,78 +539,495 @@ pub async fn magnet_download_command(output: String, magnet_link: String, show_p
         println!("{}", hex::encode(piece_hash));
     }
 
-    // Build a full piece queue from all piece indices.
+    // Pick up where an earlier run stopped.
     let num_pieces = info.pieces.len();
-    let pieces: Vec<u32> = (0..num_pieces).map(|i| i as u32).collect();
+    let have = pieces_on_disk(resume.as_ref(), &info, &output).await;
+    if have.count() == num_pieces {
+        info!("All {} pieces are already downloaded", num_pieces);
+        return Ok(());
+    }
+
+    // Multi-file torrents are laid out in a directory named after the torrent.
+    file_io::create_file_layout(&info, Path::new(&output)).await?;
+    info!("Saving to {}", file_io::content_root(&info, Path::new(&output)).display());
+
+    // Build a piece queue from the pieces we are missing.
+    let pieces: Vec<u32> = (0..num_pieces as u32).filter(|index| !have.has(*index)).collect();
     let full_piece_queue = Arc::new(PieceQueue::new(VecDeque::from(pieces)));
 
     // Create progress tracker
     let progress_tracker = Arc::new(ProgressTracker::with_progress_bar(num_pieces, show_progress));
+    progress_tracker.resume_from(have.count());
+
+    // Peers are exchanged on incoming connections too.
+    let swarm = pex_swarm(&info);
+    let mut active_torrent = ActiveTorrent::new(
+        info_hash_bytes,
+        info.clone(),
+        info_bytes,
+        Path::new(&output),
+        have,
+        config.upload_slots,
+    );
+    active_torrent.swarm = swarm.clone();
+    let active_torrent = Arc::new(active_torrent);
+    listener.add_torrent(Arc::clone(&active_torrent));
+
+    // Download over one connection per available peer.
+    let job = DownloadJob {
+        info_hash: info_hash_bytes,
+        swarm,
+        info,
+        output,
+        piece_queue: full_piece_queue,
+        full_file: true,
+        progress_tracker: Some(Arc::clone(&progress_tracker)),
+        active_torrent: Some(active_torrent),
+    };
+    run_resumable_download(&config, &potential_peers, &job, &trackers, listener.port()).await?;
 
-    // Now spawn download tasks for each available peer.
-    let mut handles = Vec::new();
-    for (ip, port) in potential_peers {
-        let addr = format!("{}:{}", ip, port);
-        // Clone the owned data for use in the async task.
-        let info_clone = info.clone();
-        let piece_queue_clone = Arc::clone(&full_piece_queue);
-        let info_hash_bytes_clone = info_hash_bytes;
-        let addr_clone = addr.clone();
-        let output_path_clone = output.clone();
-        let tracker = Arc::clone(&progress_tracker);
-
-        let handle: tokio::task::JoinHandle<()> = tokio::spawn(async move {
-            // For each piece, create a new connection.
-            while let Some(piece) = piece_queue_clone.get_next_piece().await {
-                info!("Peer {} downloading piece {}", addr_clone, piece);
-
-                // Establish a fresh connection for each piece.
-                match Peer::new(info_hash_bytes_clone, utils::generate_peer_id(), Some(info_clone.clone()))
-                    .connect_and_handshake(&addr_clone, false)
-                    .await
-                {
-                    Ok(stream) => {
-                        // Create a new peer instance for this connection.
-                        let mut download_peer = Peer::new(
-                            info_hash_bytes_clone,
-                            utils::generate_peer_id(),
-                            Some(info_clone.clone()),
-                        );
-
-                        if let Err(e) = download_peer
-                            .run_message_loop(
-                                stream,
-                                piece,
-                                &output_path_clone,
-                                Arc::clone(&piece_queue_clone),
-                                true,
-                                false,
-                                Some(Arc::clone(&tracker))
-                            )
-                            .await
-                        {
-                            error!("Error processing messages for {}: {}", addr_clone, e);
+    // Finish progress tracking
+    progress_tracker.finish();
+    Ok(())
+}
+
+fn metadata_cache(config: &Config) -> PathBuf {
+    Path::new(&config.download_directory).join(&config.metadata_cache)
+}
+
+/// The info dict of a torrent as fetched by an earlier run, if any.
+fn cached_metadata(config: &Config, info_hash: &[u8; 20]) -> Option<TorrentMetadata> {
+    let metadata = load_cached_metadata(&metadata_cache(config), info_hash)?;
+    info!("Using cached metadata for {}", hex::encode(info_hash));
+    Some(metadata)
+}
+
+/// Fetches the info dict from the peers and caches it, so later runs need
+/// not fetch it again.
+async fn fetch_and_cache_metadata(
+    config: &Config,
+    peers: &[(String, u16)],
+    info_hash: &[u8; 20],
+) -> Result<TorrentMetadata, Box<dyn Error + Send + Sync>> {
+    let metadata = fetch_metadata(peers, info_hash).await?;
+    if let Err(e) = cache_metadata(&metadata_cache(config), info_hash, &metadata.bytes) {
+        warn!("Could not cache metadata: {}", e);
+    }
+    Ok(metadata)
+}
+
+fn print_metadata_source(metadata: &TorrentMetadata) {
+    match &metadata.peer {
+        Some(peer) => println!("Using peer {} for metadata", peer),
+        None => println!("Using cached metadata"),
+    }
+}
+
+/// Writes a .torrent file for a magnet link: the info dict exactly as
+/// fetched, with each magnet tracker as its own tier and the magnet's web
+/// seeds.
+async fn save_magnet_torrent(path: &str, magnet: &MagnetLink, metadata: &TorrentMetadata) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let options = CreateOptions {
+        trackers: magnet.trackers.iter().map(|url| vec![url.clone()]).collect(),
+        web_seeds: magnet.web_seeds.clone(),
+        created_by: Some(concat!("rusbit-cli/", env!("CARGO_PKG_VERSION")).to_string()),
+        ..CreateOptions::default()
+    };
+    tokio::fs::write(path, torrent_from_info(&metadata.bytes, &options)).await?;
+    println!("Saved torrent to {}", path);
+    Ok(())
+}
+
+/// Total size of the pieces missing from `have`.
+fn missing_length(info: &TorrentInfo, have: &Bitfield) -> u64 {
+    (0..have.len() as u32)
+        .filter(|index| !have.has(*index))
+        .map(|index| info.piece_size(index))
+        .sum()
+}
+
+fn resume_path(config: &Config, info_hash: &[u8; 20]) -> PathBuf {
+    ResumeData::path(&Path::new(&config.download_directory).join(&config.resume_directory), info_hash)
+}
+
+/// Loads the resume data saved for a torrent by an earlier run, if any.
+fn load_resume(config: &Config, info_hash: &[u8; 20]) -> Option<ResumeData> {
+    match ResumeData::load(&resume_path(config, info_hash)) {
+        Ok(resume) => resume.filter(|resume| resume.info_hash == *info_hash),
+        Err(e) => {
+            warn!("Ignoring unreadable resume data: {}", e);
+            None
+        }
+    }
+}
+
+/// The pieces already on disk. They are taken from the resume data of an
+/// earlier run while the files are unchanged, and hashed otherwise, so data
+/// copied from elsewhere is reused as well.
+async fn pieces_on_disk(resume: Option<&ResumeData>, info: &TorrentInfo, output: &str) -> Bitfield {
+    match resume {
+        Some(resume) => match resume.verified_pieces(info, output) {
+            Some(have) => {
+                info!("Resuming with {}/{} pieces", have.count(), have.len());
+                return have;
+            }
+            None => info!("Files changed since the last run, rechecking pieces"),
+        },
+        None => {
+            if resume::file_states(info, Path::new(output)).iter().all(|file| file.size == 0) {
+                return Bitfield::new(info.pieces.len());
+            }
+            info!("Checking the data already on disk");
+        }
+    }
+    let have = verify_pieces(info, Path::new(output)).await.have();
+    info!("{}/{} pieces verified", have.count(), have.len());
+    have
+}
+
+/// How many pieces of a job are not downloaded and verified yet.
+fn missing_pieces(job: &DownloadJob) -> usize {
+    match &job.active_torrent {
+        Some(active_torrent) => job.info.pieces.len() - active_torrent.have().count(),
+        None => 0,
+    }
+}
+
+fn save_resume(config: &Config, job: &DownloadJob, trackers: &TrackerTiers) {
+    let Some(active_torrent) = &job.active_torrent else { return };
+    let resume = ResumeData::capture(job.info_hash, &job.info, &job.output, &active_torrent.have(), trackers.tiers());
+    if let Err(e) = resume.save(&resume_path(config, &job.info_hash)) {
+        warn!("Could not save resume data: {}", e);
+    }
+}
+
+/// Runs the download sessions of a job to the end, saving resume data every
+/// `RESUME_INTERVAL`, when done, and when interrupted with Ctrl-C. Fails when
+/// the sessions ended with pieces still missing. Peers
+/// learned through PEX or Local Service Discovery, where the job is
+/// announced as accepting connections on `listen_port`, get sessions of their
+/// own while pieces are left, up to `max_connections`; with Local Service
+/// Discovery on, the job keeps waiting for such peers when all others are gone.
+async fn run_resumable_download(
+    config: &Config,
+    peers: &[(String, u16)],
+    job: &DownloadJob,
+    trackers: &TrackerTiers,
+    listen_port: u16,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut sessions = tokio::task::JoinSet::new();
+    for (ip, port) in peers.iter().take(config.max_connections) {
+        sessions.spawn(download_session(config, format!("{}:{}", ip, port), job));
+    }
+
+    let local_discovery = local_discovery(config, &job.info, job.info_hash, listen_port, job.swarm.as_deref());
+    tokio::pin!(local_discovery);
+
+    // Peers on the local network may still turn up once the others are gone.
+    let wait_for_local_peers = config.lsd_enabled && job.swarm.is_some();
+    let mut waiting = false;
+    let mut save_timer = tokio::time::interval_at(tokio::time::Instant::now() + RESUME_INTERVAL, RESUME_INTERVAL);
+    loop {
+        if sessions.is_empty() {
+            if !wait_for_local_peers || job.piece_queue.is_empty().await {
+                save_resume(config, job, trackers);
+                return match missing_pieces(job) {
+                    0 => Ok(()),
+                    missing => Err(format!(
+                        "{} of {} pieces are still missing; run again to resume",
+                        missing,
+                        job.info.pieces.len()
+                    )
+                    .into()),
+                };
+            }
+            if !waiting {
+                info!("Waiting for peers on the local network; press Ctrl-C to stop");
+            }
+        }
+        waiting = sessions.is_empty();
+        tokio::select! {
+            Some(result) = sessions.join_next() => {
+                // A session that panicked only loses its own peer.
+                if let Err(e) = result {
+                    error!("Download session failed: {}", e);
+                }
+            }
+            discovered = next_discovered(job.swarm.as_deref()) => {
+                for addr in discovered {
+                    if sessions.len() >= config.max_connections || job.piece_queue.is_empty().await {
+                        break;
+                    }
+                    info!("Connecting to discovered peer {}", addr);
+                    sessions.spawn(download_session(config, addr.to_string(), job));
+                }
+            }
+            _ = &mut local_discovery => {}
+            _ = save_timer.tick() => save_resume(config, job, trackers),
+            _ = tokio::signal::ctrl_c() => {
+                sessions.abort_all();
+                save_resume(config, job, trackers);
+                return Err("Download interrupted; run again to resume".into());
+            }
+        }
+    }
+}
+
+/// Announces a torrent on the local network every `lsd::ANNOUNCE_INTERVAL`,
+/// and early when a new peer announces it too, but never twice within
+/// `lsd::MIN_ANNOUNCE_GAP`. The peers heard are queued in `swarm`, if given.
+/// Never resolves, and does nothing when Local Service Discovery is disabled,
+/// unavailable, or the torrent is private (BEP 27).
+async fn local_discovery(config: &Config, info: &TorrentInfo, info_hash: [u8; 20], listen_port: u16, swarm: Option<&Swarm>) {
+    if !config.lsd_enabled || info.is_private() {
+        return std::future::pending().await;
+    }
+    let lsd = match config.lsd_interface.parse::<Ipv4Addr>() {
+        Ok(interface) => Lsd::bind(interface, config.lsd_port).map_err(|e| e.to_string()),
+        Err(e) => Err(format!("invalid lsd_interface '{}': {}", config.lsd_interface, e)),
+    };
+    let lsd = match lsd {
+        Ok(lsd) => lsd,
+        Err(e) => {
+            warn!("Local Service Discovery is unavailable: {}", e);
+            return std::future::pending().await;
+        }
+    };
+
+    // Peers that announced the torrent. A new one is answered with an
+    // announcement of our own so it need not wait for our next one; the set
+    // starts over once it holds `MAX_LSD_PEERS`, so a host announcing from
+    // many ports cannot grow it without bound.
+    let mut heard = HashSet::new();
+    let mut reply_due = false;
+    let mut last_announce: Option<tokio::time::Instant> = None;
+    let mut announce_timer = tokio::time::interval(lsd::ANNOUNCE_INTERVAL);
+    loop {
+        let next_allowed = last_announce.map_or_else(tokio::time::Instant::now, |last| last + lsd::MIN_ANNOUNCE_GAP);
+        let announce = tokio::select! {
+            _ = announce_timer.tick() => next_allowed <= tokio::time::Instant::now(),
+            _ = tokio::time::sleep_until(next_allowed), if reply_due => true,
+            received = lsd.recv() => {
+                match received {
+                    Ok((addr, info_hashes)) if info_hashes.contains(&info_hash) => {
+                        if let Some(swarm) = swarm {
+                            swarm.add_discovered([addr]);
+                        }
+                        if heard.len() >= MAX_LSD_PEERS {
+                            heard.clear();
                         }
+                        reply_due |= heard.insert(addr);
                     }
+                    Ok(_) => {}
                     Err(e) => {
-                        error!("Failed to setup peer {}: {}", addr_clone, e);
+                        warn!("Local Service Discovery receive failed: {}", e);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
                     }
                 }
+                false
             }
-        });
+        };
+        if announce {
+            if let Err(e) = lsd.announce(listen_port, &[info_hash]).await {
+                warn!("Local Service Discovery announce failed: {}", e);
+            }
+            last_announce = Some(tokio::time::Instant::now());
+            reply_due = false;
+        }
+    }
+}
 
-        handles.push(handle);
+/// Peers learned through PEX or Local Service Discovery; never resolves
+/// without a swarm.
+async fn next_discovered(swarm: Option<&Swarm>) -> Vec<SocketAddr> {
+    match swarm {
+        Some(swarm) => swarm.next_discovered().await,
+        None => std::future::pending().await,
     }
+}
 
-    // Wait for all download tasks to complete.
-    for handle in handles {
-        handle.await?;
+/// Finds peers through the torrent's trackers and the DHT at the same time,
+/// and merges what both return. The DHT is never asked for private torrents
+/// (BEP 27); magnet links carry no info dict to tell, so their lookups pass
+/// `private` as false.
+///
+/// When the DHT was asked, `port` is announced there as well, and the node is
+/// returned so the caller can keep it answering for the rest of the session.
+#[allow(clippy::too_many_arguments)]
+async fn find_peers(
+    config: &Config,
+    http_client: &Client,
+    trackers: &mut TrackerTiers,
+    info_hash: &[u8; 20],
+    peer_id: &[u8; 20],
+    left: u64,
+    port: u16,
+    direct: &[(String, u16)],
+    private: bool,
+) -> Result<(Vec<(String, u16)>, Option<Dht>), Box<dyn Error + Send + Sync>> {
+    // Peers given up front (`--peer`, a magnet's `x.pe`) come first, and make
+    // the trackers and the DHT optional: neither is waited for long.
+    let mut peers = Vec::new();
+    for peer in direct {
+        if !peers.contains(peer) {
+            peers.push(peer.clone());
+        }
     }
 
-    // Finish progress tracking
-    progress_tracker.finish();
-    Ok(())
-}/// Sets up a peer connection given a torrent file and a peer address.
+    let dht = if config.dht_enabled && !private {
+        let cache_path = Path::new(&config.download_directory).join(&config.dht_node_cache);
+        let bound = match Dht::bind_with_cache(&format!("0.0.0.0:{}", config.dht_port), &cache_path).await {
+            Ok(dht) => Ok(dht),
+            // The configured port may be taken by another client.
+            Err(_) => Dht::bind_with_cache("0.0.0.0:0", &cache_path).await,
+        };
+        match bound {
+            Ok(dht) => Some(dht),
+            Err(e) => {
+                warn!("Could not start the DHT: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let announce = async {
+        if trackers.is_empty() {
+            return None;
+        }
+        let announce = trackers.announce(http_client, info_hash, peer_id, 0, 0, left, port);
+        Some(if direct.is_empty() {
+            announce.await
+        } else {
+            tokio::time::timeout(DIRECT_PEERS_ANNOUNCE_TIMEOUT, announce)
+                .await
+                .unwrap_or_else(|_| Err(format!("no answer within {:?}", DIRECT_PEERS_ANNOUNCE_TIMEOUT).into()))
+        })
+    };
+    let lookup = async {
+        let lookup = dht_lookup(config, dht.as_ref()?, info_hash, port);
+        if direct.is_empty() {
+            Some(lookup.await)
+        } else {
+            let found = tokio::time::timeout(DIRECT_PEERS_ANNOUNCE_TIMEOUT, lookup).await;
+            if found.is_err() {
+                warn!("DHT lookup took longer than {:?}, using the given peers", DIRECT_PEERS_ANNOUNCE_TIMEOUT);
+            }
+            found.ok()
+        }
+    };
+    let (announced, looked_up) = tokio::join!(announce, lookup);
+
+    let mut tracker_error = None;
+    match announced {
+        Some(Ok(tracker_peers)) => {
+            for peer in tracker_peers {
+                if !peers.contains(&peer) {
+                    peers.push(peer);
+                }
+            }
+        }
+        Some(Err(e)) if direct.is_empty() => tracker_error = Some(e),
+        Some(Err(e)) => warn!("Tracker announce failed, using the given peers: {}", e),
+        None => {}
+    }
+    for addr in looked_up.unwrap_or_default() {
+        let peer = (addr.ip().to_string(), addr.port());
+        if !peers.contains(&peer) {
+            peers.push(peer);
+        }
+    }
+
+    match tracker_error {
+        Some(e) if peers.is_empty() => Err(e),
+        _ => Ok((peers, dht)),
+    }
+}
+
+/// Bootstraps `dht` and looks up the peers of `info_hash` in it, announcing
+/// `port` to the closest nodes on the way.
+async fn dht_lookup(config: &Config, dht: &Dht, info_hash: &[u8; 20], port: u16) -> Vec<SocketAddrV4> {
+    info!("Looking up peers in the DHT");
+    let nodes = dht.bootstrap(&config.dht_bootstrap_nodes).await;
+    info!("DHT routing table has {} nodes", nodes);
+
+    // The lookup for peers also collects the tokens needed to announce.
+    let (peers, accepted) = dht.announce_peer(*info_hash, Some(port)).await;
+    info!("Announced to {} DHT nodes", accepted);
+
+    let cache_path = Path::new(&config.download_directory).join(&config.dht_node_cache);
+    if let Err(e) = dht.save_node_cache(&cache_path) {
+        warn!("Could not save DHT node cache: {}", e);
+    }
+    peers
+}
+
+/// What the download sessions of one torrent share.
+struct DownloadJob {
+    info_hash: [u8; 20],
+    info: TorrentInfo,
+    output: String,
+    piece_queue: Arc<PieceQueue>,
+    full_file: bool,
+    progress_tracker: Option<Arc<ProgressTracker>>,
+    /// Set when finished pieces are also served to other peers.
+    active_torrent: Option<Arc<ActiveTorrent>>,
+    /// Set when peers are exchanged (PEX) and looked for on the local
+    /// network, which lets the job connect to peers it learns about while
+    /// downloading.
+    swarm: Option<Arc<Swarm>>,
+}
+
+/// A swarm to exchange peers in, unless the torrent is private (BEP 27),
+/// whose peers must only come from its trackers.
+fn pex_swarm(info: &TorrentInfo) -> Option<Arc<Swarm>> {
+    (!info.is_private()).then(|| Arc::new(Swarm::new()))
+}
+
+/// Spawns one long-lived download session per peer. Each session keeps its
+/// connection and pulls pieces from the job's queue until the queue is empty,
+/// pipelining requests as configured.
+fn spawn_download_sessions(
+    config: &Config,
+    peers: &[(String, u16)],
+    job: &DownloadJob,
+) -> Vec<tokio::task::JoinHandle<()>> {
+    peers
+        .iter()
+        .map(|(ip, port)| tokio::spawn(download_session(config, format!("{}:{}", ip, port), job)))
+        .collect()
+}
+
+/// One download session of a job, from connecting to `addr` until the queue
+/// is empty or the peer fails.
+fn download_session(config: &Config, addr: String, job: &DownloadJob) -> impl std::future::Future<Output = ()> + Send + 'static {
+    let mut peer = Peer::new(job.info_hash, utils::generate_peer_id(), Some(job.info.clone()));
+    peer.active_torrent = job.active_torrent.clone();
+    peer.swarm = job.swarm.clone();
+    if let (Some(swarm), Ok(socket_addr)) = (&job.swarm, addr.parse()) {
+        swarm.add_known(socket_addr);
+    }
+    let output_path = job.output.clone();
+    let pq = Arc::clone(&job.piece_queue);
+    let tracker = job.progress_tracker.clone();
+    let full_file = job.full_file;
+    let pipeline = RequestPipeline::new(config.pipeline_depth, config.max_pipeline_depth);
+
+    async move {
+        match peer.connect_and_handshake(&addr, true).await {
+            Ok(stream) => {
+                info!("Downloading from peer {}", addr);
+                if let Err(e) = peer.run_download_session(stream, &output_path, pq, pipeline, full_file, tracker).await {
+                    error!("Error processing messages for {}: {}", addr, e);
+                }
+            }
+            Err(e) => error!("Failed to setup peer {}: {}", addr, e),
+        }
+    }
+}
+
+/// Sets up a peer connection given a torrent file and a peer address.
 async fn setup_peer(file_path: &str, addr: &str) -> Result<(Peer, TcpStream), Box<dyn Error + Send + Sync>> {
     let torrent = Torrent::from_file(file_path)?;
     let peer_id = utils::generate_peer_id();
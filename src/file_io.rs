@@ -1,37 +1,334 @@
 // src/file_io.rs
 use tokio::fs::OpenOptions;
-use tokio::io::{AsyncWriteExt, BufWriter, AsyncSeekExt, SeekFrom};
-use std::path::Path;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter, AsyncSeekExt, SeekFrom};
+use std::path::{Path, PathBuf};
 use std::io::Error;
 
+use crate::torrent::TorrentInfo;
+
 pub async fn write_piece_to_file_at_offset(
 	piece_data: &[u8],
 	piece_index: u32,
 	output_path: &str,
-	piece_length: u32,
+	piece_length: u64,
 	full_file: bool,
 
 ) -> Result<(), Error> {
 	let file_path = Path::new(output_path);
-	// Open the file in read/write mode; you may want to truncate or create it.
+	// Open the file in read/write mode without truncating pieces already written.
 	let file = OpenOptions::new()
 		.create(true)
 		.write(true)
+		.truncate(false)
 		.open(file_path)
 		.await?;
 	let mut writer = BufWriter::new(file);
 
 	// Compute the file offset for this piece.
 	let offset = if full_file {
-		 piece_index as u64 * piece_length as u64
+		 piece_index as u64 * piece_length
 	} else {
-		0 as u64
+		0_u64
 	};
 
 	writer.seek(SeekFrom::Start(offset)).await?;
 	writer.write_all(piece_data).await?;
 	writer.flush().await?;
-	
+
 	println!("Piece {} written to file at offset {}", piece_index, offset);
 	Ok(())
 }
+
+/// A contiguous run of a piece's bytes that lands in a single file on disk.
+#[derive(Debug, PartialEq)]
+pub struct FileSpan {
+	pub path: PathBuf,
+	pub file_offset: u64,    // Where the run starts inside the file
+	pub piece_offset: usize, // Where the run starts inside the piece
+	pub length: usize,
+}
+
+/// Returns the on-disk location of a torrent's content.
+///
+/// Single-file torrents are written to `output_path` itself, multi-file torrents
+/// into a root directory named after `info.name` below `output_path`.
+pub fn content_root(info: &TorrentInfo, output_path: &Path) -> PathBuf {
+	if info.is_multi_file() {
+		output_path.join(&info.name)
+	} else {
+		output_path.to_path_buf()
+	}
+}
+
+/// Returns the on-disk path of every file of a torrent, in torrent order.
+pub fn file_paths(info: &TorrentInfo, output_path: &Path) -> Vec<PathBuf> {
+	let root = content_root(info, output_path);
+	match &info.files {
+		Some(files) => files
+			.iter()
+			.map(|file| file.path.iter().fold(root.clone(), |path, component| path.join(component)))
+			.collect(),
+		None => vec![root],
+	}
+}
+
+/// Maps a piece onto the file(s) it covers, in file order.
+///
+/// A piece that straddles a file boundary yields one span per file it touches.
+pub fn piece_file_spans(info: &TorrentInfo, piece_index: u32, output_path: &Path) -> Vec<FileSpan> {
+	let piece_start = piece_index as u64 * info.piece_length as u64;
+	let piece_end = std::cmp::min(piece_start + info.piece_length as u64, info.length as u64);
+	if piece_start >= piece_end {
+		return Vec::new();
+	}
+
+	let files = match &info.files {
+		Some(files) => files,
+		None => {
+			return vec![FileSpan {
+				path: output_path.to_path_buf(),
+				file_offset: piece_start,
+				piece_offset: 0,
+				length: (piece_end - piece_start) as usize,
+			}];
+		}
+	};
+
+	let root = content_root(info, output_path);
+	let mut spans = Vec::new();
+	let mut file_start = 0u64;
+	for file in files {
+		let file_end = file_start + file.length as u64;
+		let start = std::cmp::max(piece_start, file_start);
+		let end = std::cmp::min(piece_end, file_end);
+		if start < end {
+			spans.push(FileSpan {
+				path: file.path.iter().fold(root.clone(), |path, component| path.join(component)),
+				file_offset: start - file_start,
+				piece_offset: (start - piece_start) as usize,
+				length: (end - start) as usize,
+			});
+		}
+		if file_end >= piece_end {
+			break;
+		}
+		file_start = file_end;
+	}
+	spans
+}
+
+/// Creates the directory tree of a multi-file torrent, including empty files,
+/// so that files not covered by any piece still exist after the download.
+pub async fn create_file_layout(info: &TorrentInfo, output_path: &Path) -> Result<(), Error> {
+	if !info.is_multi_file() {
+		return Ok(());
+	}
+
+	for path in file_paths(info, output_path) {
+		if let Some(parent) = path.parent() {
+			tokio::fs::create_dir_all(parent).await?;
+		}
+		OpenOptions::new()
+			.create(true)
+			.write(true)
+			.truncate(false)
+			.open(&path)
+			.await?;
+	}
+	Ok(())
+}
+
+/// Writes a verified piece to every file it overlaps.
+pub async fn write_piece_to_files(
+	piece_data: &[u8],
+	piece_index: u32,
+	output_path: &str,
+	info: &TorrentInfo,
+) -> Result<(), Error> {
+	for span in piece_file_spans(info, piece_index, Path::new(output_path)) {
+		if let Some(parent) = span.path.parent() {
+			if !parent.as_os_str().is_empty() {
+				tokio::fs::create_dir_all(parent).await?;
+			}
+		}
+		let file = OpenOptions::new()
+			.create(true)
+			.write(true)
+			.truncate(false)
+			.open(&span.path)
+			.await?;
+		let mut writer = BufWriter::new(file);
+
+		writer.seek(SeekFrom::Start(span.file_offset)).await?;
+		writer.write_all(&piece_data[span.piece_offset..span.piece_offset + span.length]).await?;
+		writer.flush().await?;
+	}
+	Ok(())
+}
+
+/// Reads `length` bytes starting `begin` bytes into a piece back from disk,
+/// e.g. to answer a peer's request.
+pub async fn read_piece_range(
+	info: &TorrentInfo,
+	piece_index: u32,
+	begin: u64,
+	length: u64,
+	output_path: &Path,
+) -> Result<Vec<u8>, Error> {
+	let end = begin + length;
+	let mut data = vec![0u8; length as usize];
+	let mut filled = 0u64;
+	for span in piece_file_spans(info, piece_index, output_path) {
+		let span_start = span.piece_offset as u64;
+		let span_end = span_start + span.length as u64;
+		let start = std::cmp::max(begin, span_start);
+		let stop = std::cmp::min(end, span_end);
+		if start >= stop {
+			continue;
+		}
+
+		let mut file = tokio::fs::File::open(&span.path).await?;
+		file.seek(SeekFrom::Start(span.file_offset + (start - span_start))).await?;
+		file.read_exact(&mut data[(start - begin) as usize..(stop - begin) as usize]).await?;
+		filled += stop - start;
+	}
+
+	if filled != length {
+		return Err(Error::new(std::io::ErrorKind::InvalidInput, "Range lies outside the piece"));
+	}
+	Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::HashMap;
+	use crate::torrent::TorrentFile;
+
+	fn file(length: usize, path: &[&str]) -> TorrentFile {
+		TorrentFile {
+			length,
+			path: path.iter().map(|component| component.to_string()).collect(),
+			extra: HashMap::new(),
+		}
+	}
+
+	fn multi_file_info() -> TorrentInfo {
+		TorrentInfo {
+			length: 25,
+			name: "dataset".to_string(),
+			piece_length: 10,
+			pieces: vec![[0u8; 20]; 3],
+			files: Some(vec![
+				file(4, &["a.txt"]),
+				file(0, &["empty"]),
+				file(13, &["sub", "b.bin"]),
+				file(8, &["c.txt"]),
+			]),
+			extra: HashMap::new(),
+		}
+	}
+
+	#[test]
+	fn test_single_file_spans() {
+		let info = TorrentInfo {
+			length: 25,
+			name: "file.bin".to_string(),
+			piece_length: 10,
+			pieces: vec![[0u8; 20]; 3],
+			files: None,
+			extra: HashMap::new(),
+		};
+		let spans = piece_file_spans(&info, 2, Path::new("out.bin"));
+		assert_eq!(spans, vec![FileSpan {
+			path: PathBuf::from("out.bin"),
+			file_offset: 20,
+			piece_offset: 0,
+			length: 5,
+		}]);
+	}
+
+	#[test]
+	fn test_piece_spanning_file_boundaries() {
+		let info = multi_file_info();
+		let root = Path::new("out").join("dataset");
+
+		// Piece 0 covers a.txt completely, skips the empty file and starts sub/b.bin.
+		let spans = piece_file_spans(&info, 0, Path::new("out"));
+		assert_eq!(spans, vec![
+			FileSpan { path: root.join("a.txt"), file_offset: 0, piece_offset: 0, length: 4 },
+			FileSpan { path: root.join("sub").join("b.bin"), file_offset: 0, piece_offset: 4, length: 6 },
+		]);
+
+		// Piece 1 finishes sub/b.bin and starts c.txt.
+		let spans = piece_file_spans(&info, 1, Path::new("out"));
+		assert_eq!(spans, vec![
+			FileSpan { path: root.join("sub").join("b.bin"), file_offset: 6, piece_offset: 0, length: 7 },
+			FileSpan { path: root.join("c.txt"), file_offset: 0, piece_offset: 7, length: 3 },
+		]);
+
+		// The last piece is short.
+		let spans = piece_file_spans(&info, 2, Path::new("out"));
+		assert_eq!(spans, vec![
+			FileSpan { path: root.join("c.txt"), file_offset: 3, piece_offset: 0, length: 5 },
+		]);
+	}
+
+	#[tokio::test]
+	async fn test_write_pieces_to_files() {
+		let info = multi_file_info();
+		let dir = tempfile::tempdir().unwrap();
+		let output = dir.path().to_str().unwrap();
+		let content: Vec<u8> = (0u8..25).collect();
+
+		create_file_layout(&info, dir.path()).await.unwrap();
+		// Write out of order to make sure offsets are honoured.
+		for piece_index in [2u32, 0, 1] {
+			let start = piece_index as usize * 10;
+			let end = std::cmp::min(start + 10, content.len());
+			write_piece_to_files(&content[start..end], piece_index, output, &info).await.unwrap();
+		}
+
+		let root = dir.path().join("dataset");
+		assert_eq!(std::fs::read(root.join("a.txt")).unwrap(), &content[0..4]);
+		assert_eq!(std::fs::read(root.join("empty")).unwrap(), Vec::<u8>::new());
+		assert_eq!(std::fs::read(root.join("sub").join("b.bin")).unwrap(), &content[4..17]);
+		assert_eq!(std::fs::read(root.join("c.txt")).unwrap(), &content[17..25]);
+
+		// Reading back across the a.txt / sub/b.bin boundary, and the short last piece.
+		let block = read_piece_range(&info, 0, 2, 6, dir.path()).await.unwrap();
+		assert_eq!(block, &content[2..8]);
+		let block = read_piece_range(&info, 2, 0, 5, dir.path()).await.unwrap();
+		assert_eq!(block, &content[20..25]);
+		assert!(read_piece_range(&info, 2, 3, 5, dir.path()).await.is_err());
+	}
+
+	#[test]
+	fn test_spans_beyond_4_gib() {
+		const GIB: usize = 1 << 30;
+		let piece_length = 1 << 20;
+		let info = TorrentInfo {
+			length: 5 * GIB + 100,
+			name: "big".to_string(),
+			piece_length,
+			pieces: vec![[0u8; 20]; (5 * GIB + 100).div_ceil(piece_length)],
+			files: Some(vec![file(3 * GIB + 100, &["a.bin"]), file(2 * GIB, &["b.bin"])]),
+			extra: HashMap::new(),
+		};
+		let root = Path::new("out").join("big");
+
+		// Piece 3072 starts at 3 GiB, 100 bytes before the end of a.bin.
+		let spans = piece_file_spans(&info, 3072, Path::new("out"));
+		assert_eq!(spans, vec![
+			FileSpan { path: root.join("a.bin"), file_offset: 3 << 30, piece_offset: 0, length: 100 },
+			FileSpan { path: root.join("b.bin"), file_offset: 0, piece_offset: 100, length: piece_length - 100 },
+		]);
+
+		// The last piece is short and ends exactly at the end of b.bin.
+		let last = info.pieces.len() as u32 - 1;
+		let spans = piece_file_spans(&info, last, Path::new("out"));
+		assert_eq!(spans, vec![
+			FileSpan { path: root.join("b.bin"), file_offset: (2 << 30) - 100, piece_offset: 0, length: 100 },
+		]);
+	}
+}
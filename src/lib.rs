@@ -2,4 +2,8 @@ pub mod torrent;
 pub mod bencode;
 pub mod config;
 pub mod progress;
-
+pub mod tracker;
+pub mod utils;
+pub mod dht;
+pub mod magnet_link;
+pub mod lsd;
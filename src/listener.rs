@@ -0,0 +1,602 @@
+// listener.rs
+//! Accepts incoming peer connections and serves blocks of the torrents we
+//! have from disk (seeding).
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+
+use crate::bitfield::Bitfield;
+use crate::choker::{Choker, RECHOKE_INTERVAL};
+use crate::file_io;
+use crate::magnet::metadata::{answer_request, ut_metadata_id, MetadataMessage};
+use crate::message::{
+    read_handshake, read_message, send_extended_handshake, send_handshake, send_message, Message, UT_METADATA_ID,
+    UT_PEX_ID,
+};
+use crate::pex::{listen_port, ut_pex_id, PexMessage, PexState, Swarm, PEX_INTERVAL};
+use crate::torrent::TorrentInfo;
+
+/// Largest block we serve; peers request 16 KiB, anything beyond this is abuse.
+const MAX_REQUEST_LENGTH: u32 = 1 << 17;
+
+/// Most block requests we queue per peer, advertised as `reqq`; requests
+/// beyond it are dropped.
+const MAX_QUEUED_REQUESTS: usize = 250;
+
+/// How long an incoming connection may take to send its handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often pieces completed in the meantime are announced with Have.
+const HAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Send a keep-alive when we have been silent this long.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+
+/// A torrent whose verified pieces we can serve to other peers.
+pub struct ActiveTorrent {
+    pub info_hash: [u8; 20],
+    pub info: TorrentInfo,
+    pub output_path: PathBuf, // As given to the download, see `file_io::content_root`
+    pub choker: Choker,
+    have: Mutex<Bitfield>,
+    metadata: Option<Vec<u8>>, // The bencoded info dict, served over ut_metadata
+    /// Set when peers are exchanged (PEX) with incoming peers as well.
+    pub swarm: Option<Arc<Swarm>>,
+}
+
+impl ActiveTorrent {
+    /// `info_bytes` is the info dict exactly as it was hashed; it is served
+    /// over ut_metadata in 16 KiB slices. Empty bytes serve no metadata.
+    pub fn new(info_hash: [u8; 20], info: TorrentInfo, info_bytes: Vec<u8>, output_path: &Path, have: Bitfield, upload_slots: usize) -> Self {
+        Self {
+            info_hash,
+            info,
+            output_path: output_path.to_path_buf(),
+            choker: Choker::new(upload_slots),
+            have: Mutex::new(have),
+            metadata: Some(info_bytes).filter(|bytes| !bytes.is_empty()),
+            swarm: None,
+        }
+    }
+
+    /// A snapshot of the pieces we have.
+    pub fn have(&self) -> Bitfield {
+        self.have.lock().unwrap().clone()
+    }
+
+    pub fn has_piece(&self, index: u32) -> bool {
+        self.have.lock().unwrap().has(index)
+    }
+
+    /// Records a piece that was downloaded and verified.
+    pub fn mark_have(&self, index: u32) {
+        self.have.lock().unwrap().set(index);
+    }
+
+    fn is_seeding(&self) -> bool {
+        self.have.lock().unwrap().count() == self.info.pieces.len()
+    }
+}
+
+type ActiveTorrents = Arc<Mutex<HashMap<[u8; 20], Arc<ActiveTorrent>>>>;
+
+/// Listens for incoming connections to the torrents added to it. Stops
+/// accepting when dropped.
+pub struct Listener {
+    torrents: ActiveTorrents,
+    local_addr: SocketAddr,
+    accept_task: JoinHandle<()>,
+    choke_tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Listener {
+    pub async fn bind(addr: &str, peer_id: [u8; 20]) -> Result<Self, IoError> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let torrents: ActiveTorrents = Arc::new(Mutex::new(HashMap::new()));
+        let accept_task = tokio::spawn(accept_loop(listener, Arc::clone(&torrents), peer_id));
+        info!("Listening for peers on {}", local_addr);
+        Ok(Self {
+            torrents,
+            local_addr,
+            accept_task,
+            choke_tasks: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Binds `port` on all interfaces, or any free port if it is taken.
+    pub async fn bind_port(port: u16, peer_id: [u8; 20]) -> Result<Self, IoError> {
+        match Self::bind(&format!("0.0.0.0:{}", port), peer_id).await {
+            Ok(listener) => Ok(listener),
+            Err(e) => {
+                warn!("Could not listen on port {} ({}), using a free port instead", port, e);
+                Self::bind("0.0.0.0:0", peer_id).await
+            }
+        }
+    }
+
+    /// The port we actually listen on, to be announced to trackers.
+    pub fn port(&self) -> u16 {
+        self.local_addr.port()
+    }
+
+    /// Serves `torrent` to incoming peers and starts choking for it.
+    pub fn add_torrent(&self, torrent: Arc<ActiveTorrent>) {
+        self.torrents.lock().unwrap().insert(torrent.info_hash, Arc::clone(&torrent));
+        self.choke_tasks.lock().unwrap().push(tokio::spawn(rechoke_loop(torrent)));
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+        for task in self.choke_tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+}
+
+async fn rechoke_loop(torrent: Arc<ActiveTorrent>) {
+    let mut ticker = tokio::time::interval(RECHOKE_INTERVAL);
+    loop {
+        ticker.tick().await;
+        torrent.choker.rechoke(torrent.is_seeding());
+    }
+}
+
+async fn accept_loop(listener: TcpListener, torrents: ActiveTorrents, peer_id: [u8; 20]) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                let torrents = Arc::clone(&torrents);
+                tokio::spawn(async move {
+                    if let Err(e) = handle_inbound(stream, torrents, peer_id).await {
+                        debug!("Incoming connection from {} ended: {}", addr, e);
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept a connection: {}", e),
+        }
+    }
+}
+
+/// Answers the handshake of an incoming connection and serves it if it asks
+/// for one of our torrents.
+async fn handle_inbound(
+    mut stream: TcpStream,
+    torrents: ActiveTorrents,
+    peer_id: [u8; 20],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, read_handshake(&mut stream))
+        .await
+        .map_err(|_| IoError::new(ErrorKind::TimedOut, "No handshake received"))??;
+    let torrent = torrents
+        .lock()
+        .unwrap()
+        .get(&handshake.info_hash)
+        .cloned()
+        .ok_or_else(|| IoError::other("Handshake for a torrent we do not serve"))?;
+
+    send_handshake(&mut stream, &torrent.info_hash, &peer_id, true).await?;
+    info!("Peer {} connected", hex::encode(handshake.peer_id));
+    let remote_ip = stream.peer_addr()?.ip();
+    serve_peer(stream, torrent, handshake.peer_id, handshake.supports_extensions, remote_ip).await
+}
+
+/// Uploads to a connected peer: sends our bitfield, chokes and unchokes the
+/// peer as the torrent's choker decides, answers its requests from disk and
+/// honours its cancels. A peer that supports the extension protocol may
+/// also fetch the torrent's metadata (BEP 9) and exchange peers (BEP 11);
+/// once its extended handshake gives the port it listens on, it joins the
+/// torrent's swarm at `remote_ip` for as long as it stays connected.
+pub async fn serve_peer<S>(
+    stream: S,
+    torrent: Arc<ActiveTorrent>,
+    peer_id: [u8; 20],
+    extensions: bool,
+    remote_ip: IpAddr,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let unchoke = torrent
+        .choker
+        .connect(peer_id)
+        .ok_or_else(|| IoError::other("Peer is already connected"))?;
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    // Read on a separate task so a half-read message is never lost while we
+    // are busy answering requests.
+    let (tx, rx) = mpsc::channel(64);
+    let reader_task = tokio::spawn(async move {
+        loop {
+            let message = read_message(&mut reader).await;
+            let failed = message.is_err();
+            if tx.send(message).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    let mut listen_addr = None;
+    let result = upload_loop(&mut writer, rx, &torrent, &peer_id, extensions, unchoke, remote_ip, &mut listen_addr).await;
+    reader_task.abort();
+    torrent.choker.disconnect(&peer_id);
+    if let (Some(swarm), Some(addr)) = (&torrent.swarm, listen_addr) {
+        swarm.disconnected(addr);
+    }
+    result
+}
+
+/// `listen_addr` is set to where the peer accepts connections once it has
+/// joined the swarm.
+#[allow(clippy::too_many_arguments)]
+async fn upload_loop<W>(
+    writer: &mut W,
+    mut messages: mpsc::Receiver<Result<Message, IoError>>,
+    torrent: &ActiveTorrent,
+    peer_id: &[u8; 20],
+    extensions: bool,
+    mut unchoke: watch::Receiver<bool>,
+    remote_ip: IpAddr,
+    listen_addr: &mut Option<SocketAddr>,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut announced = torrent.have();
+    if announced.count() > 0 {
+        send_message(writer, Message::Bitfield(announced.as_bytes().to_vec())).await?;
+    }
+    if extensions {
+        send_extended_handshake(writer, torrent.metadata.as_ref().map(Vec::len), torrent.swarm.is_some(), Some(MAX_QUEUED_REQUESTS)).await?;
+    }
+    // The ids the peer wants its ut_metadata and ut_pex messages sent with.
+    let mut peer_ut_metadata = None;
+    let mut peer_ut_pex = None;
+    let mut pex = PexState::new();
+    // The first tick is immediate, so the peer hears about the swarm as
+    // soon as its extended handshake arrives.
+    let mut pex_timer = tokio::time::interval(PEX_INTERVAL);
+
+    let mut requests: VecDeque<(u32, u32, u32)> = VecDeque::new();
+    let mut choking = true;
+    let mut last_sent = Instant::now();
+    let mut ticker = tokio::time::interval(HAVE_INTERVAL);
+
+    loop {
+        tokio::select! {
+            // Handle incoming messages first so cancels win over queued requests.
+            biased;
+            message = messages.recv() => {
+                let message = match message {
+                    Some(Ok(message)) => message,
+                    Some(Err(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                    Some(Err(e)) => return Err(e.into()),
+                    None => return Ok(()),
+                };
+                match message {
+                    Message::Interested => torrent.choker.set_interested(peer_id, true),
+                    Message::NotInterested => torrent.choker.set_interested(peer_id, false),
+                    Message::Request { index, begin, length } => {
+                        // Requests made while choked are dropped, as BEP 3 allows.
+                        if choking {
+                            continue;
+                        }
+                        let valid = (index as usize) < torrent.info.pieces.len()
+                            && length > 0
+                            && length <= MAX_REQUEST_LENGTH
+                            && begin as u64 + length as u64 <= torrent.info.piece_size(index);
+                        if !valid {
+                            return Err(IoError::new(ErrorKind::InvalidData, "Invalid block request").into());
+                        }
+                        // The peer may have asked before our Have for the piece went out.
+                        if !torrent.has_piece(index) {
+                            debug!("Ignoring request for piece {} we do not have", index);
+                            continue;
+                        }
+                        if requests.len() >= MAX_QUEUED_REQUESTS {
+                            debug!("Dropping request beyond the queue limit of {}", MAX_QUEUED_REQUESTS);
+                            continue;
+                        }
+                        requests.push_back((index, begin, length));
+                    }
+                    Message::Cancel { index, begin, length } => {
+                        requests.retain(|request| *request != (index, begin, length));
+                    }
+                    Message::ExtendedHandshake(handshake) => {
+                        peer_ut_metadata = ut_metadata_id(&handshake);
+                        if let Some(swarm) = &torrent.swarm {
+                            peer_ut_pex = ut_pex_id(&handshake);
+                            if let (None, Some(port)) = (*listen_addr, listen_port(&handshake)) {
+                                let addr = SocketAddr::new(remote_ip, port);
+                                swarm.add_known(addr);
+                                swarm.connected(addr);
+                                *listen_addr = Some(addr);
+                            }
+                        }
+                    }
+                    Message::ReceiveMetaData { ext_msg_id: UT_PEX_ID, dict, .. } => {
+                        if let Some(swarm) = &torrent.swarm {
+                            if pex.accept_incoming(Instant::now()) {
+                                swarm.discover(&PexMessage::parse(&dict));
+                            } else {
+                                debug!("Ignoring PEX message sent too soon");
+                            }
+                        }
+                    }
+                    Message::ReceiveMetaData { ext_msg_id: UT_METADATA_ID, dict, payload } => {
+                        let Some(reply_id) = peer_ut_metadata else { continue };
+                        if let MetadataMessage::Request { piece } = MetadataMessage::parse(&dict, &payload)? {
+                            let answer = answer_request(torrent.metadata.as_deref(), piece);
+                            send_message(writer, answer.to_message(reply_id)).await?;
+                            last_sent = Instant::now();
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            changed = unchoke.changed() => {
+                if changed.is_err() {
+                    return Ok(());
+                }
+                let unchoked = *unchoke.borrow_and_update();
+                if unchoked == choking {
+                    choking = !unchoked;
+                    send_message(writer, if choking { Message::Choke } else { Message::Unchoke }).await?;
+                    if choking {
+                        // A choke discards the requests still queued.
+                        requests.clear();
+                    }
+                    last_sent = Instant::now();
+                }
+            }
+            _ = pex_timer.tick(), if peer_ut_pex.is_some() => {
+                if let (Some(swarm), Some(ut_pex)) = (&torrent.swarm, peer_ut_pex) {
+                    if let Some(message) = pex.next_message(swarm, *listen_addr) {
+                        debug!("Sending PEX: {} added, {} dropped", message.added.len(), message.dropped.len());
+                        send_message(writer, message.to_message(ut_pex)).await?;
+                        last_sent = Instant::now();
+                    }
+                }
+            }
+            _ = ticker.tick() => {
+                let have = torrent.have();
+                for index in have.pieces().filter(|index| !announced.has(*index)) {
+                    send_message(writer, Message::Have { index }).await?;
+                    last_sent = Instant::now();
+                }
+                announced = have;
+                if last_sent.elapsed() >= KEEP_ALIVE_INTERVAL {
+                    send_message(writer, Message::KeepAlive).await?;
+                    last_sent = Instant::now();
+                }
+            }
+            _ = std::future::ready(()), if !requests.is_empty() => {
+                let (index, begin, length) = requests.pop_front().unwrap();
+                let block = file_io::read_piece_range(
+                    &torrent.info,
+                    index,
+                    begin as u64,
+                    length as u64,
+                    &torrent.output_path,
+                )
+                .await?;
+
+                let mut payload = Vec::with_capacity(8 + block.len());
+                payload.extend_from_slice(&index.to_be_bytes());
+                payload.extend_from_slice(&begin.to_be_bytes());
+                payload.extend_from_slice(&block);
+                send_message(writer, Message::Piece { payload }).await?;
+                torrent.choker.record_upload(peer_id, block.len() as u64);
+                last_sent = Instant::now();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use sha1::{Digest, Sha1};
+
+    use crate::bencode::BValue;
+    use crate::peer::Peer;
+    use crate::piece_queue::PieceQueue;
+    use crate::pipeline::RequestPipeline;
+
+    fn have_all(num_pieces: usize) -> Bitfield {
+        let mut have = Bitfield::new(num_pieces);
+        for index in 0..num_pieces as u32 {
+            have.set(index);
+        }
+        have
+    }
+
+    #[tokio::test]
+    async fn test_two_instances_exchange_a_file_on_loopback() {
+        let content: Vec<u8> = (0..100_000u32).map(|i| (i % 253) as u8).collect();
+        let piece_length = 32_768;
+        let info = TorrentInfo {
+            length: content.len(),
+            name: "shared.bin".to_string(),
+            piece_length,
+            pieces: content.chunks(piece_length).map(|piece| Sha1::digest(piece).into()).collect(),
+            files: None,
+            extra: HashMap::new(),
+        };
+        let info_hash = [7u8; 20];
+
+        // The seeding instance has the whole file on disk.
+        let seed_dir = tempfile::tempdir().unwrap();
+        let seed_path = seed_dir.path().join("shared.bin");
+        std::fs::write(&seed_path, &content).unwrap();
+        let seeder = Listener::bind("127.0.0.1:0", [1u8; 20]).await.unwrap();
+        let num_pieces = info.pieces.len();
+        seeder.add_torrent(Arc::new(ActiveTorrent::new(info_hash, info.clone(), Vec::new(), &seed_path, have_all(num_pieces), 4)));
+
+        // The leeching instance connects and downloads every piece.
+        let leech_dir = tempfile::tempdir().unwrap();
+        let leech_path = leech_dir.path().join("shared.bin");
+        let queue = Arc::new(PieceQueue::new((0..num_pieces as u32).collect::<VecDeque<_>>()));
+        let mut peer = Peer::new(info_hash, [2u8; 20], Some(info));
+        let stream = peer.connect_and_handshake(&format!("127.0.0.1:{}", seeder.port()), true).await.unwrap();
+        peer.run_download_session(stream, leech_path.to_str().unwrap(), queue, RequestPipeline::new(8, 32), true, None)
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read(&leech_path).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_info_hash_and_bad_requests_are_refused() {
+        let info = TorrentInfo {
+            length: 10,
+            name: "x".to_string(),
+            piece_length: 5,
+            pieces: vec![[0u8; 20]; 2],
+            files: None,
+            extra: HashMap::new(),
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("x");
+        std::fs::write(&path, b"0123456789").unwrap();
+        let seeder = Listener::bind("127.0.0.1:0", [1u8; 20]).await.unwrap();
+        let mut have = Bitfield::new(2);
+        have.set(1);
+        seeder.add_torrent(Arc::new(ActiveTorrent::new([7u8; 20], info, Vec::new(), &path, have, 4)));
+        let addr = format!("127.0.0.1:{}", seeder.port());
+
+        let mut stranger = Peer::new([8u8; 20], [2u8; 20], None);
+        assert!(stranger.connect_and_handshake(&addr, false).await.is_err());
+
+        let mut peer = Peer::new([7u8; 20], [2u8; 20], None);
+        let mut stream = peer.connect_and_handshake(&addr, false).await.unwrap();
+        assert_eq!(read_message(&mut stream).await.unwrap(), Message::Bitfield(vec![0b0100_0000]));
+        send_message(&mut stream, Message::Interested).await.unwrap();
+        assert_eq!(read_message(&mut stream).await.unwrap(), Message::Unchoke);
+
+        // A request for a piece we do not have is ignored, not punished.
+        send_message(&mut stream, Message::Request { index: 0, begin: 0, length: 5 }).await.unwrap();
+        send_message(&mut stream, Message::Request { index: 1, begin: 1, length: 3 }).await.unwrap();
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&1u32.to_be_bytes());
+        expected.extend_from_slice(&1u32.to_be_bytes());
+        expected.extend_from_slice(b"678");
+        assert_eq!(read_message(&mut stream).await.unwrap(), Message::Piece { payload: expected });
+
+        // A request past the end of the piece gets the connection closed.
+        send_message(&mut stream, Message::Request { index: 1, begin: 3, length: 5 }).await.unwrap();
+        assert!(read_message(&mut stream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_metadata_is_served_to_extension_peers() {
+        // Keys out of order: the dict must be served as is, not re-encoded.
+        let metadata = b"d4:name1:x6:lengthi10e12:piece lengthi5e6:pieces40:\
+            ccccccccccccccccccccdddddddddddddddddddde"
+            .to_vec();
+        let info = match crate::bencode::decode_bencode(&metadata).unwrap() {
+            (_, BValue::Dict(dict)) => TorrentInfo::from_bvalue(&dict).unwrap(),
+            _ => unreachable!(),
+        };
+        let info_hash = crate::torrent::calculate_info_hash(&metadata);
+        let seeder = Listener::bind("127.0.0.1:0", [1u8; 20]).await.unwrap();
+        seeder.add_torrent(Arc::new(ActiveTorrent::new(info_hash, info, metadata.clone(), Path::new("unused"), have_all(2), 4)));
+
+        let mut peer = Peer::new(info_hash, [2u8; 20], None);
+        let mut stream = peer.connect_and_handshake(&format!("127.0.0.1:{}", seeder.port()), true).await.unwrap();
+        assert!(peer.remote_supports_extensions);
+        assert!(matches!(read_message(&mut stream).await.unwrap(), Message::Bitfield(_)));
+        let Message::ExtendedHandshake(BValue::Dict(handshake)) = read_message(&mut stream).await.unwrap() else {
+            panic!("expected an extended handshake");
+        };
+        assert_eq!(handshake.get("metadata_size"), Some(&BValue::Integer(metadata.len() as i64)));
+        assert_eq!(handshake.get("reqq"), Some(&BValue::Integer(MAX_QUEUED_REQUESTS as i64)));
+        let seeder_id = ut_metadata_id(&BValue::Dict(handshake)).unwrap();
+        send_extended_handshake(&mut stream, None, false, None).await.unwrap();
+
+        let replies = [
+            (0, MetadataMessage::Data { piece: 0, total_size: metadata.len(), data: metadata.clone() }),
+            (1, MetadataMessage::Reject { piece: 1 }),
+        ];
+        for (piece, expected) in replies {
+            send_message(&mut stream, MetadataMessage::Request { piece }.to_message(seeder_id)).await.unwrap();
+            let Message::ReceiveMetaData { ext_msg_id: UT_METADATA_ID, dict, payload } = read_message(&mut stream).await.unwrap() else {
+                panic!("expected a ut_metadata reply");
+            };
+            assert_eq!(MetadataMessage::parse(&dict, &payload).unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_incoming_peers_exchange_peers() {
+        let info = TorrentInfo {
+            length: 10,
+            name: "x".to_string(),
+            piece_length: 5,
+            pieces: vec![[3u8; 20]; 2],
+            files: None,
+            extra: HashMap::new(),
+        };
+        let swarm = Arc::new(Swarm::new());
+        let other: SocketAddr = "10.0.0.5:6881".parse().unwrap();
+        swarm.connected(other);
+        let mut torrent = ActiveTorrent::new([7u8; 20], info, Vec::new(), Path::new("unused"), have_all(2), 4);
+        torrent.swarm = Some(Arc::clone(&swarm));
+        let seeder = Listener::bind("127.0.0.1:0", [1u8; 20]).await.unwrap();
+        seeder.add_torrent(Arc::new(torrent));
+
+        let mut peer = Peer::new([7u8; 20], [2u8; 20], None);
+        let mut stream = peer.connect_and_handshake(&format!("127.0.0.1:{}", seeder.port()), true).await.unwrap();
+        assert!(matches!(read_message(&mut stream).await.unwrap(), Message::Bitfield(_)));
+        let Message::ExtendedHandshake(handshake) = read_message(&mut stream).await.unwrap() else {
+            panic!("expected an extended handshake");
+        };
+        assert_eq!(ut_pex_id(&handshake), Some(UT_PEX_ID));
+
+        // We take ut_pex as id 7, listen on port 7000 and know another peer.
+        let mut handshake = HashMap::new();
+        let mut m = HashMap::new();
+        m.insert("ut_pex".to_string(), BValue::Integer(7));
+        handshake.insert("m".to_string(), BValue::Dict(m));
+        handshake.insert("p".to_string(), BValue::Integer(7000));
+        send_message(&mut stream, Message::ExtendedHandshake(BValue::Dict(handshake))).await.unwrap();
+        let learned = PexMessage { added: vec![("10.0.0.7:6881".parse().unwrap(), 0)], dropped: Vec::new() };
+        send_message(&mut stream, learned.to_message(UT_PEX_ID)).await.unwrap();
+
+        let heard = loop {
+            if let Message::ReceiveMetaData { ext_msg_id: 7, dict, .. } = read_message(&mut stream).await.unwrap() {
+                break PexMessage::parse(&dict);
+            }
+        };
+        assert_eq!(heard.added, vec![(other, crate::pex::FLAG_REACHABLE)]);
+        assert_eq!(swarm.next_discovered().await, vec!["10.0.0.7:6881".parse().unwrap()]);
+
+        // The incoming peer is told to others while connected, and not after.
+        let us: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        let mut observer = PexState::new();
+        assert!(observer.next_message(&swarm, None).unwrap().added.iter().any(|(addr, _)| *addr == us));
+        drop(stream);
+        let dropped = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(message) = observer.next_message(&swarm, None) {
+                    break message.dropped;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        assert_eq!(dropped.await.unwrap(), vec![us]);
+    }
+}
@@ -0,0 +1,241 @@
+// lsd.rs
+//! Local Service Discovery (BEP 14): peers on the same network announce the
+//! torrents they are active in over multicast, so they find each other
+//! without a tracker.
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+use log::warn;
+use rand::Rng;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+pub const LSD_PORT: u16 = 6771;
+pub const LSD_IPV4_GROUP: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+pub const LSD_IPV6_GROUP: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+
+/// How often an active torrent is announced; BEP 14 asks for no more than
+/// one announcement a minute.
+pub const ANNOUNCE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+/// Least time between two announcements of a torrent, including the ones
+/// made in reply to peers we just heard of.
+pub const MIN_ANNOUNCE_GAP: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Info hashes per message, which keeps a message well under 1400 bytes.
+const MAX_INFOHASHES: usize = 20;
+
+/// A `BT-SEARCH` message: the listen port of the sender and the torrents it
+/// is active in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Announcement {
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    /// Lets a client recognize its own announcements when they loop back.
+    pub cookie: Option<String>,
+}
+
+impl Announcement {
+    pub fn encode(&self, host: &SocketAddr) -> Vec<u8> {
+        let mut message = format!("BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n", host, self.port);
+        for info_hash in &self.info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {}\r\n", cookie));
+        }
+        message.push_str("\r\n\r\n");
+        message.into_bytes()
+    }
+
+    /// Parses a `BT-SEARCH` message; headers are matched case-insensitively
+    /// and malformed info hashes are skipped. `None` if the message is not an
+    /// announcement or names no valid port or info hash.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(bytes).ok()?;
+        let mut lines = text.split("\r\n");
+        if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+
+        let (mut port, mut info_hashes, mut cookie) = (None, Vec::new(), None);
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else { continue };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = value.parse().ok().filter(|port| *port != 0),
+                "infohash" => {
+                    let mut info_hash = [0u8; 20];
+                    if hex::decode_to_slice(value, &mut info_hash).is_ok() {
+                        info_hashes.push(info_hash);
+                    }
+                }
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        if info_hashes.is_empty() {
+            return None;
+        }
+        Some(Self { port: port?, info_hashes, cookie })
+    }
+}
+
+/// An LSD endpoint: a socket in the IPv4 group, and one in the IPv6 group
+/// where the host supports it.
+pub struct Lsd {
+    v4: (UdpSocket, SocketAddr),
+    v6: Option<(UdpSocket, SocketAddr)>,
+    cookie: String,
+}
+
+impl Lsd {
+    /// Joins both multicast groups on `port`: the IPv4 group on `interface`
+    /// (unspecified for the system's choice), the IPv6 group on the default
+    /// interface. Only the IPv4 group is required.
+    pub fn bind(interface: Ipv4Addr, port: u16) -> io::Result<Self> {
+        let mut lsd = Self::bind_v4(interface, port)?;
+        match bind_v6(port) {
+            Ok(v6) => lsd.v6 = Some(v6),
+            Err(e) => warn!("LSD is IPv4 only: {}", e),
+        }
+        Ok(lsd)
+    }
+
+    /// Joins only the IPv4 group. With `interface` set to 127.0.0.1 the
+    /// announcements stay on this machine.
+    pub fn bind_v4(interface: Ipv4Addr, port: u16) -> io::Result<Self> {
+        let socket = reusable_socket(Domain::IPV4, SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))?;
+        socket.join_multicast_v4(&LSD_IPV4_GROUP, &interface)?;
+        if !interface.is_unspecified() {
+            socket.set_multicast_if_v4(&interface)?;
+        }
+        socket.set_multicast_loop_v4(true)?;
+        let port = socket.local_addr()?.as_socket().map_or(port, |addr| addr.port());
+        let group = SocketAddr::V4(SocketAddrV4::new(LSD_IPV4_GROUP, port));
+        Ok(Self {
+            v4: (UdpSocket::from_std(socket.into())?, group),
+            v6: None,
+            cookie: format!("{:08x}", rand::thread_rng().gen::<u32>()),
+        })
+    }
+
+    /// The port the groups were joined on.
+    pub fn port(&self) -> u16 {
+        self.v4.0.local_addr().map_or(0, |addr| addr.port())
+    }
+
+    /// Announces that we accept connections on `port` for `info_hashes`,
+    /// in every group joined. Fails only if no group could be reached.
+    pub async fn announce(&self, port: u16, info_hashes: &[[u8; 20]]) -> io::Result<()> {
+        let mut result = Ok(());
+        for chunk in info_hashes.chunks(MAX_INFOHASHES) {
+            let announcement = Announcement {
+                port,
+                info_hashes: chunk.to_vec(),
+                cookie: Some(self.cookie.clone()),
+            };
+            let mut sent = false;
+            for (socket, group) in std::iter::once(&self.v4).chain(self.v6.as_ref()) {
+                match socket.send_to(&announcement.encode(group), group).await {
+                    Ok(_) => sent = true,
+                    Err(e) => result = Err(e),
+                }
+            }
+            if sent {
+                result = Ok(());
+            }
+        }
+        result
+    }
+
+    /// Waits for the next announcement from another client, and returns the
+    /// address it accepts connections on with the info hashes it announced.
+    pub async fn recv(&self) -> io::Result<(SocketAddr, Vec<[u8; 20]>)> {
+        let (mut buf_v4, mut buf_v6) = ([0u8; 1500], [0u8; 1500]);
+        loop {
+            let (len, from, buf) = tokio::select! {
+                result = self.v4.0.recv_from(&mut buf_v4) => {
+                    let (len, from) = result?;
+                    (len, from, &buf_v4)
+                }
+                result = recv_v6(self.v6.as_ref(), &mut buf_v6) => {
+                    let (len, from) = result?;
+                    (len, from, &buf_v6)
+                }
+            };
+            let Some(announcement) = Announcement::parse(&buf[..len]) else { continue };
+            if announcement.cookie.as_deref() == Some(self.cookie.as_str()) {
+                continue;
+            }
+            return Ok((SocketAddr::new(from.ip(), announcement.port), announcement.info_hashes));
+        }
+    }
+}
+
+async fn recv_v6(v6: Option<&(UdpSocket, SocketAddr)>, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    match v6 {
+        Some((socket, _)) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+fn bind_v6(port: u16) -> io::Result<(UdpSocket, SocketAddr)> {
+    let socket = reusable_socket(Domain::IPV6, SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)))?;
+    socket.join_multicast_v6(&LSD_IPV6_GROUP, 0)?;
+    socket.set_multicast_loop_v6(true)?;
+    let group = SocketAddr::V6(SocketAddrV6::new(LSD_IPV6_GROUP, port, 0, 0));
+    Ok((UdpSocket::from_std(socket.into())?, group))
+}
+
+/// A non-blocking UDP socket bound to `addr` that other LSD clients on this
+/// machine can bind as well.
+fn reusable_socket(domain: Domain, addr: SocketAddr) -> io::Result<Socket> {
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    if matches!(addr.ip(), IpAddr::V6(_)) {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_announcement_round_trip() {
+        let announcement = Announcement {
+            port: 6881,
+            info_hashes: vec![[0xab; 20], [0x01; 20]],
+            cookie: Some("c00k1e".to_string()),
+        };
+        let host = SocketAddr::V4(SocketAddrV4::new(LSD_IPV4_GROUP, LSD_PORT));
+        let encoded = announcement.encode(&host);
+        assert!(encoded.starts_with(b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\n"));
+        assert_eq!(Announcement::parse(&encoded), Some(announcement));
+
+        let other_client = b"BT-SEARCH * HTTP/1.1\r\nhost: [ff15::efc0:988f]:6771\r\nport: 51413\r\ninfohash: nothex\r\nINFOHASH: ABABABABABABABABABABABABABABABABABABABAB\r\n\r\n\r\n";
+        let parsed = Announcement::parse(other_client).unwrap();
+        assert_eq!((parsed.port, parsed.info_hashes, parsed.cookie), (51413, vec![[0xab; 20]], None));
+        assert_eq!(Announcement::parse(b"BT-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n\r\n"), None);
+        assert_eq!(Announcement::parse(b"M-SEARCH * HTTP/1.1\r\n\r\n"), None);
+    }
+
+    #[tokio::test]
+    async fn test_peers_find_each_other_on_loopback() {
+        let a = Lsd::bind_v4(Ipv4Addr::LOCALHOST, 0).unwrap();
+        let b = Lsd::bind_v4(Ipv4Addr::LOCALHOST, a.port()).unwrap();
+
+        a.announce(7000, &[[7u8; 20]]).await.unwrap();
+        let (from, info_hashes) = tokio::time::timeout(Duration::from_secs(5), b.recv()).await.unwrap().unwrap();
+        assert_eq!(from, "127.0.0.1:7000".parse().unwrap());
+        assert_eq!(info_hashes, vec![[7u8; 20]]);
+
+        // A client does not discover itself.
+        assert!(tokio::time::timeout(Duration::from_millis(200), a.recv()).await.is_err());
+    }
+}
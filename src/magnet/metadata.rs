@@ -0,0 +1,357 @@
+// metadata.rs
+//! The ut_metadata extension (BEP 9): the raw info dict of a torrent,
+//! exchanged in 16 KiB pieces over the extension protocol.
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{info, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::bencode::{decode_bencode, BValue};
+use crate::message::{read_message, send_extended_handshake, send_message, Message, UT_METADATA_ID};
+use crate::peer::Peer;
+use crate::torrent::{calculate_info_hash, TorrentInfo};
+use crate::utils;
+
+/// Size of every metadata piece but the last.
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+
+/// Largest info dict we accept from a peer.
+const MAX_METADATA_SIZE: usize = 8 * 1024 * 1024;
+
+/// How long one peer gets to deliver the whole info dict.
+const METADATA_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A torrent's info dict, checked against the info hash.
+#[derive(Debug)]
+pub struct TorrentMetadata {
+    pub bytes: Vec<u8>, // The bencoded info dict
+    pub info: TorrentInfo,
+    pub peer: Option<String>, // Address of the peer that sent it; `None` if cached
+}
+
+impl TorrentMetadata {
+    /// Checks and parses a bencoded info dict.
+    pub fn from_bytes(bytes: Vec<u8>, info_hash: &[u8; 20], peer: Option<String>) -> Result<Self, Box<dyn StdError + Send + Sync>> {
+        if calculate_info_hash(&bytes) != *info_hash {
+            return Err("Metadata does not match the info hash".into());
+        }
+        let info = match decode_bencode(&bytes)? {
+            (_, BValue::Dict(dict)) => TorrentInfo::from_bvalue(&dict)?,
+            _ => return Err("Metadata must be a dictionary".into()),
+        };
+        Ok(Self { bytes, info, peer })
+    }
+}
+
+/// A ut_metadata message, told apart by its `msg_type`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataMessage {
+    Request { piece: u32 },
+    Data { piece: u32, total_size: usize, data: Vec<u8> },
+    Reject { piece: u32 },
+}
+
+impl MetadataMessage {
+    /// Parses the bencoded dict of a ut_metadata message and the bytes that
+    /// follow it (the piece itself, for `Data`).
+    pub fn parse(dict: &BValue, payload: &[u8]) -> Result<Self, Error> {
+        let BValue::Dict(dict) = dict else {
+            return Err(Error::new(ErrorKind::InvalidData, "ut_metadata message must be a dictionary"));
+        };
+        let integer = |key: &str| match dict.get(key) {
+            Some(BValue::Integer(value)) if *value >= 0 => Ok(*value),
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("ut_metadata message lacks '{}'", key))),
+        };
+        let piece = u32::try_from(integer("piece")?)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "ut_metadata piece out of range"))?;
+        match integer("msg_type")? {
+            0 => Ok(Self::Request { piece }),
+            1 => Ok(Self::Data {
+                piece,
+                total_size: integer("total_size")? as usize,
+                data: payload.to_vec(),
+            }),
+            2 => Ok(Self::Reject { piece }),
+            other => Err(Error::new(ErrorKind::InvalidData, format!("Unknown ut_metadata msg_type {}", other))),
+        }
+    }
+
+    /// The wire message, for a peer that assigned ut_metadata the id `ext_msg_id`.
+    pub fn to_message(&self, ext_msg_id: u8) -> Message {
+        let mut dict = HashMap::new();
+        let (msg_type, piece, payload) = match self {
+            Self::Request { piece } => (0, piece, Vec::new()),
+            Self::Data { piece, total_size, data } => {
+                dict.insert("total_size".to_string(), BValue::Integer(*total_size as i64));
+                (1, piece, data.clone())
+            }
+            Self::Reject { piece } => (2, piece, Vec::new()),
+        };
+        dict.insert("msg_type".to_string(), BValue::Integer(msg_type));
+        dict.insert("piece".to_string(), BValue::Integer(*piece as i64));
+        Message::ReceiveMetaData { ext_msg_id, dict: BValue::Dict(dict), payload }
+    }
+}
+
+/// The id a peer's extended handshake assigns to ut_metadata, if it
+/// supports the extension.
+pub fn ut_metadata_id(handshake: &BValue) -> Option<u8> {
+    let BValue::Dict(handshake) = handshake else { return None };
+    match handshake.get("m") {
+        Some(BValue::Dict(m)) => match m.get("ut_metadata") {
+            Some(BValue::Integer(id)) => u8::try_from(*id).ok().filter(|id| *id != 0),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The `metadata_size` of a peer's extended handshake, if present and sane.
+pub fn metadata_size(handshake: &BValue) -> Option<usize> {
+    let BValue::Dict(handshake) = handshake else { return None };
+    match handshake.get("metadata_size") {
+        Some(BValue::Integer(size)) if (1..=MAX_METADATA_SIZE as i64).contains(size) => Some(*size as usize),
+        _ => None,
+    }
+}
+
+/// Fetches the info dict from the first of `peers` able to provide it. A
+/// peer that is unreachable, rejects a piece, sends bad data or takes longer
+/// than `METADATA_TIMEOUT` is skipped in favour of the next one.
+pub async fn fetch_metadata(
+    peers: &[(String, u16)],
+    info_hash: &[u8; 20],
+) -> Result<TorrentMetadata, Box<dyn StdError + Send + Sync>> {
+    for (ip, port) in peers {
+        let addr = format!("{}:{}", ip, port);
+        let attempt = async {
+            let mut peer = Peer::new(*info_hash, utils::generate_peer_id(), None);
+            let mut stream = peer.connect_and_handshake(&addr, true).await?;
+            if !peer.remote_supports_extensions {
+                return Err::<_, Box<dyn StdError + Send + Sync>>("Peer does not support the extension protocol".into());
+            }
+            let bytes = download_metadata(&mut stream, info_hash).await?;
+            TorrentMetadata::from_bytes(bytes, info_hash, Some(addr.clone()))
+        };
+        match tokio::time::timeout(METADATA_TIMEOUT, attempt).await {
+            Ok(Ok(metadata)) => {
+                info!("Fetched metadata from {}", addr);
+                return Ok(metadata);
+            }
+            Ok(Err(e)) => warn!("Could not fetch metadata from {}: {}", addr, e),
+            Err(_) => warn!("Timed out fetching metadata from {}", addr),
+        }
+    }
+    Err("No peer provided the torrent metadata".into())
+}
+
+/// Fetches the whole info dict over a connection whose handshake enabled
+/// the extension protocol: exchanges extended handshakes, requests every
+/// piece the peer's `metadata_size` calls for, and checks the reassembled
+/// dict against `info_hash`.
+pub async fn download_metadata<S>(stream: &mut S, info_hash: &[u8; 20]) -> Result<Vec<u8>, Box<dyn StdError + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    send_extended_handshake(stream, None, false, None).await?;
+    let handshake = loop {
+        if let Message::ExtendedHandshake(handshake) = read_message(stream).await? {
+            break handshake;
+        }
+    };
+    let ut_metadata = ut_metadata_id(&handshake).ok_or("Peer does not support ut_metadata")?;
+    let size = metadata_size(&handshake).ok_or("Peer did not announce a usable metadata_size")?;
+
+    let count = size.div_ceil(METADATA_PIECE_SIZE);
+    for piece in 0..count as u32 {
+        send_message(stream, MetadataMessage::Request { piece }.to_message(ut_metadata)).await?;
+    }
+
+    let mut pieces: Vec<Option<Vec<u8>>> = vec![None; count];
+    while pieces.iter().any(Option::is_none) {
+        let Message::ReceiveMetaData { ext_msg_id: UT_METADATA_ID, dict, payload } = read_message(stream).await? else {
+            continue;
+        };
+        match MetadataMessage::parse(&dict, &payload)? {
+            MetadataMessage::Data { piece, total_size, data } => {
+                let index = piece as usize;
+                let expected = METADATA_PIECE_SIZE.min(size.saturating_sub(index * METADATA_PIECE_SIZE));
+                if index >= count || total_size != size || data.len() != expected {
+                    return Err(format!("Malformed metadata piece {}", piece).into());
+                }
+                pieces[index] = Some(data);
+            }
+            MetadataMessage::Reject { piece } => return Err(format!("Peer rejected metadata piece {}", piece).into()),
+            // We have no metadata to give.
+            MetadataMessage::Request { piece } => {
+                send_message(stream, answer_request(None, piece).to_message(ut_metadata)).await?;
+            }
+        }
+    }
+
+    let metadata: Vec<u8> = pieces.into_iter().flatten().flatten().collect();
+    if calculate_info_hash(&metadata) != *info_hash {
+        return Err("Metadata does not match the info hash".into());
+    }
+    Ok(metadata)
+}
+
+/// Where the info dict of a torrent is cached inside `directory`.
+fn cache_path(directory: &Path, info_hash: &[u8; 20]) -> PathBuf {
+    directory.join(format!("{}.info", hex::encode(info_hash)))
+}
+
+/// The cached info dict of a torrent, if there is one that still matches
+/// the info hash.
+pub fn load_cached_metadata(directory: &Path, info_hash: &[u8; 20]) -> Option<TorrentMetadata> {
+    let bytes = std::fs::read(cache_path(directory, info_hash)).ok()?;
+    match TorrentMetadata::from_bytes(bytes, info_hash, None) {
+        Ok(metadata) => Some(metadata),
+        Err(e) => {
+            warn!("Ignoring cached metadata for {}: {}", hex::encode(info_hash), e);
+            None
+        }
+    }
+}
+
+pub fn cache_metadata(directory: &Path, info_hash: &[u8; 20], metadata: &[u8]) -> std::io::Result<()> {
+    std::fs::create_dir_all(directory)?;
+    std::fs::write(cache_path(directory, info_hash), metadata)
+}
+
+/// Answers a metadata request from the raw info dict, or rejects it when we
+/// have no metadata or the piece does not exist.
+pub fn answer_request(metadata: Option<&[u8]>, piece: u32) -> MetadataMessage {
+    let start = piece as usize * METADATA_PIECE_SIZE;
+    match metadata {
+        Some(metadata) if start < metadata.len() => {
+            let end = (start + METADATA_PIECE_SIZE).min(metadata.len());
+            MetadataMessage::Data {
+                piece,
+                total_size: metadata.len(),
+                data: metadata[start..end].to_vec(),
+            }
+        }
+        _ => MetadataMessage::Reject { piece },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::io::DuplexStream;
+
+    use crate::bencode::encode_bvalue;
+    use crate::bitfield::Bitfield;
+    use crate::listener::{ActiveTorrent, Listener};
+    use crate::torrent::infohash::info_to_bvalue;
+
+    /// An info dict of about 40 KiB, so three metadata pieces.
+    fn large_info(name: &str) -> TorrentInfo {
+        TorrentInfo {
+            length: 2000 * 16_384,
+            name: name.to_string(),
+            piece_length: 16_384,
+            pieces: (0..2000u32).map(|i| [(i % 251) as u8; 20]).collect(),
+            files: None,
+            extra: HashMap::new(),
+        }
+    }
+
+    /// Plays a peer that serves `metadata`, replacing piece `corrupt` with junk.
+    async fn serve_metadata(mut stream: DuplexStream, metadata: Vec<u8>, corrupt: Option<u32>) {
+        let mut handshake = HashMap::new();
+        let mut m = HashMap::new();
+        m.insert("ut_metadata".to_string(), BValue::Integer(3));
+        handshake.insert("m".to_string(), BValue::Dict(m));
+        handshake.insert("metadata_size".to_string(), BValue::Integer(metadata.len() as i64));
+        send_message(&mut stream, Message::Bitfield(vec![0xff])).await.unwrap();
+        send_message(&mut stream, Message::ExtendedHandshake(BValue::Dict(handshake))).await.unwrap();
+        while let Ok(message) = read_message(&mut stream).await {
+            let Message::ReceiveMetaData { ext_msg_id: 3, dict, payload } = message else { continue };
+            let MetadataMessage::Request { piece } = MetadataMessage::parse(&dict, &payload).unwrap() else { continue };
+            let mut answer = answer_request(Some(&metadata), piece);
+            if let (Some(bad), MetadataMessage::Data { data, .. }) = (corrupt, &mut answer) {
+                if bad == piece {
+                    data.fill(b'x');
+                }
+            }
+            send_message(&mut stream, answer.to_message(UT_METADATA_ID)).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reassembles_metadata_of_several_pieces() {
+        let info = large_info("big");
+        let metadata = encode_bvalue(&info_to_bvalue(&info));
+        assert!(metadata.len() > 2 * METADATA_PIECE_SIZE);
+        let info_hash = calculate_info_hash(&metadata);
+
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        tokio::spawn(serve_metadata(server, metadata.clone(), None));
+        assert_eq!(download_metadata(&mut client, &info_hash).await.unwrap(), metadata);
+
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        tokio::spawn(serve_metadata(server, metadata, Some(1)));
+        let err = download_metadata(&mut client, &info_hash).await.unwrap_err();
+        assert!(err.to_string().contains("info hash"));
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_to_the_next_peer_after_a_reject() {
+        let info = large_info("big");
+        let metadata = encode_bvalue(&info_to_bvalue(&info));
+        let info_hash = calculate_info_hash(&metadata);
+
+        // This seeder has no metadata to give and rejects every request.
+        let rejecting = Listener::bind("127.0.0.1:0", [1u8; 20]).await.unwrap();
+        rejecting.add_torrent(Arc::new(ActiveTorrent::new(info_hash, info.clone(), Vec::new(), Path::new("unused"), Bitfield::new(2000), 4)));
+        let serving = Listener::bind("127.0.0.1:0", [2u8; 20]).await.unwrap();
+        serving.add_torrent(Arc::new(ActiveTorrent::new(info_hash, info.clone(), metadata, Path::new("unused"), Bitfield::new(2000), 4)));
+
+        let peers = vec![
+            ("127.0.0.1".to_string(), rejecting.port()),
+            ("127.0.0.1".to_string(), serving.port()),
+        ];
+        let fetched = fetch_metadata(&peers, &info_hash).await.unwrap();
+        assert_eq!(fetched.peer, Some(format!("127.0.0.1:{}", serving.port())));
+        assert_eq!(fetched.info.pieces, info.pieces);
+
+        assert!(fetch_metadata(&peers[..1], &info_hash).await.is_err());
+
+        // Once cached, the metadata needs no peers; a corrupt cache is ignored.
+        let dir = tempfile::tempdir().unwrap();
+        cache_metadata(dir.path(), &info_hash, &fetched.bytes).unwrap();
+        let cached = load_cached_metadata(dir.path(), &info_hash).unwrap();
+        assert_eq!((cached.bytes, cached.peer), (fetched.bytes, None));
+        std::fs::write(cache_path(dir.path(), &info_hash), b"d4:name1:xe").unwrap();
+        assert!(load_cached_metadata(dir.path(), &info_hash).is_none());
+    }
+
+    #[test]
+    fn test_requests_are_answered_in_16_kib_slices_or_rejected() {
+        let metadata: Vec<u8> = (0..40_000u32).map(|i| (i % 256) as u8).collect();
+        let last = answer_request(Some(&metadata), 2);
+        assert_eq!(
+            last,
+            MetadataMessage::Data { piece: 2, total_size: 40_000, data: metadata[32_768..].to_vec() }
+        );
+        assert_eq!(answer_request(Some(&metadata), 3), MetadataMessage::Reject { piece: 3 });
+        assert_eq!(answer_request(None, 0), MetadataMessage::Reject { piece: 0 });
+
+        // Every message survives the trip through its wire form.
+        for message in [MetadataMessage::Request { piece: 1 }, last, MetadataMessage::Reject { piece: 3 }] {
+            match message.to_message(7) {
+                Message::ReceiveMetaData { ext_msg_id: 7, dict, payload } => {
+                    assert_eq!(MetadataMessage::parse(&dict, &payload).unwrap(), message);
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+}
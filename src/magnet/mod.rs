@@ -1,4 +1,3 @@
-pub mod decode_magnet;
-pub mod error;
+pub mod metadata;
 
-pub use decode_magnet::decode_magnet;   // re-export
+pub use rusbit_cli::magnet_link::{decode_magnet, MagnetLink};   // re-export
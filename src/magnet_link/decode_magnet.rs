@@ -0,0 +1,302 @@
+use crate::magnet_link::error::MagnetError;
+use crate::utils::url_encode_bytes;
+use core::str;
+use std::ops::RangeInclusive;
+
+/// A magnet link with every parameter it carries (BEP 9, plus the `btmh`
+/// hash and `so` selection of BEP 53). Repeated parameters keep all their
+/// values, in order of appearance.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MagnetLink {
+	pub info_hash: Option<[u8; 20]>,           // xt=urn:btih:, hex or base32
+	pub info_hash_v2: Option<[u8; 32]>,        // xt=urn:btmh:, a SHA-256 multihash
+	pub display_name: Option<String>,          // dn
+	pub length: Option<u64>,                   // xl
+	pub trackers: Vec<String>,                 // tr
+	pub web_seeds: Vec<String>,                // ws
+	pub peers: Vec<(String, u16)>,             // x.pe; IPv6 hosts keep their brackets
+	pub select_only: Vec<RangeInclusive<usize>>, // so, file indices
+	pub extra: Vec<(String, String)>,          // Any other parameter
+}
+
+impl MagnetLink {
+	/// The v1 info hash, which everything on the wire is keyed by.
+	pub fn v1_info_hash(&self) -> Result<[u8; 20], MagnetError> {
+		self.info_hash.ok_or(MagnetError::MissingInfoHash)
+	}
+
+	/// Builds the magnet URI. Decoding it gives back an equal `MagnetLink`.
+	pub fn to_uri(&self) -> String {
+		let mut params = Vec::new();
+		if let Some(info_hash) = &self.info_hash {
+			params.push(format!("xt=urn:btih:{}", hex::encode(info_hash)));
+		}
+		if let Some(info_hash) = &self.info_hash_v2 {
+			params.push(format!("xt=urn:btmh:1220{}", hex::encode(info_hash)));
+		}
+		if let Some(name) = &self.display_name {
+			params.push(format!("dn={}", url_encode_bytes(name.as_bytes())));
+		}
+		if let Some(length) = self.length {
+			params.push(format!("xl={}", length));
+		}
+		for tracker in &self.trackers {
+			params.push(format!("tr={}", url_encode_bytes(tracker.as_bytes())));
+		}
+		for web_seed in &self.web_seeds {
+			params.push(format!("ws={}", url_encode_bytes(web_seed.as_bytes())));
+		}
+		for (host, port) in &self.peers {
+			params.push(format!("x.pe={}:{}", url_encode_bytes(host.as_bytes()), port));
+		}
+		if !self.select_only.is_empty() {
+			let ranges: Vec<String> = self
+				.select_only
+				.iter()
+				.map(|range| match range.start() == range.end() {
+					true => range.start().to_string(),
+					false => format!("{}-{}", range.start(), range.end()),
+				})
+				.collect();
+			params.push(format!("so={}", ranges.join(",")));
+		}
+		for (key, value) in &self.extra {
+			params.push(format!("{}={}", url_encode_bytes(key.as_bytes()), url_encode_bytes(value.as_bytes())));
+		}
+		format!("magnet:?{}", params.join("&"))
+	}
+}
+
+/// Parses a magnet link. It must carry a `btih` or `btmh` info hash; every
+/// value is percent-decoded.
+pub fn decode_magnet(input: &str) -> Result<MagnetLink, MagnetError> {
+	if input.is_empty() {
+		return Err(MagnetError::UnexpectedEnd);
+	}
+	let query = input
+		.strip_prefix("magnet:?")
+		.ok_or_else(|| MagnetError::InvalidFormat("Magnet links must start with 'magnet:?'".into()))?;
+
+	let mut magnet = MagnetLink::default();
+	for (key, value) in decode_magnet_parameters(query)? {
+		match key.as_str() {
+			"xt" => {
+				if let Some(hash) = value.strip_prefix("urn:btih:") {
+					magnet.info_hash = Some(decode_btih(hash)?);
+				} else if let Some(hash) = value.strip_prefix("urn:btmh:") {
+					magnet.info_hash_v2 = Some(decode_btmh(hash)?);
+				} else {
+					magnet.extra.push((key, value));
+				}
+			}
+			"dn" => magnet.display_name = Some(value),
+			"xl" => {
+				let length = value.parse().map_err(|_| MagnetError::InvalidLength(value))?;
+				magnet.length = Some(length);
+			}
+			"tr" => magnet.trackers.push(value),
+			"ws" => magnet.web_seeds.push(value),
+			"x.pe" => magnet.peers.push(decode_peer(&value)?),
+			"so" => magnet.select_only.extend(decode_selection(&value)?),
+			_ => magnet.extra.push((key, value)),
+		}
+	}
+
+	if magnet.info_hash.is_none() && magnet.info_hash_v2.is_none() {
+		return Err(MagnetError::MissingInfoHash);
+	}
+	Ok(magnet)
+}
+
+/// Splits the query into percent-decoded key/value pairs.
+fn decode_magnet_parameters(input: &str) -> Result<Vec<(String, String)>, MagnetError> {
+	let mut params = Vec::new();
+
+	for param in input.split('&') {
+		if param.is_empty() {
+			continue;
+		}
+
+		let (key, value) = param.split_once('=').ok_or_else(||
+			MagnetError::InvalidFormat(format!("Missing value in magnet parameter '{}'", param))
+		)?;
+		if key.is_empty() {
+			return Err(MagnetError::InvalidFormat("Missing key in magnet parameter".to_string()));
+		}
+
+		params.push((percent_decode(key)?, percent_decode(value)?));
+	}
+	Ok(params)
+}
+
+/// Decodes `%XX` escapes, and `+` as a space, into a UTF-8 string.
+fn percent_decode(input: &str) -> Result<String, MagnetError> {
+	let bytes = input.as_bytes();
+	let mut decoded = Vec::with_capacity(bytes.len());
+	let mut i = 0;
+	while i < bytes.len() {
+		match bytes[i] {
+			b'%' => {
+				let byte = bytes
+					.get(i + 1..i + 3)
+					.and_then(|hex| str::from_utf8(hex).ok())
+					.and_then(|hex| u8::from_str_radix(hex, 16).ok())
+					.ok_or_else(|| MagnetError::InvalidPercentEncoding(input.to_string()))?;
+				decoded.push(byte);
+				i += 3;
+			}
+			b'+' => {
+				decoded.push(b' ');
+				i += 1;
+			}
+			byte => {
+				decoded.push(byte);
+				i += 1;
+			}
+		}
+	}
+	Ok(str::from_utf8(&decoded)?.to_string())
+}
+
+/// A `btih` hash: 40 hex digits or 32 base32 characters.
+fn decode_btih(input: &str) -> Result<[u8; 20], MagnetError> {
+	let invalid = || MagnetError::InvalidInfoHash(input.to_string());
+	match input.len() {
+		40 => {
+			let mut hash = [0u8; 20];
+			hex::decode_to_slice(input, &mut hash).map_err(|_| invalid())?;
+			Ok(hash)
+		}
+		32 => decode_base32(input).ok_or_else(invalid),
+		_ => Err(invalid()),
+	}
+}
+
+/// A `btmh` hash: a hex multihash, which for v2 torrents is SHA-256 (0x12)
+/// of 32 bytes (0x20).
+fn decode_btmh(input: &str) -> Result<[u8; 32], MagnetError> {
+	let invalid = || MagnetError::InvalidInfoHash(input.to_string());
+	let digest = input.strip_prefix("1220").ok_or_else(invalid)?;
+	let mut hash = [0u8; 32];
+	hex::decode_to_slice(digest, &mut hash).map_err(|_| invalid())?;
+	Ok(hash)
+}
+
+/// RFC 4648 base32 without padding, case-insensitive: 32 characters hold
+/// exactly 20 bytes.
+fn decode_base32(input: &str) -> Option<[u8; 20]> {
+	let mut hash = [0u8; 20];
+	let (mut buffer, mut bits, mut len) = (0u32, 0, 0);
+	for c in input.bytes() {
+		let value = match c.to_ascii_uppercase() {
+			c @ b'A'..=b'Z' => c - b'A',
+			c @ b'2'..=b'7' => c - b'2' + 26,
+			_ => return None,
+		};
+		buffer = ((buffer << 5) | value as u32) & 0xfff;
+		bits += 5;
+		if bits >= 8 {
+			bits -= 8;
+			hash[len] = (buffer >> bits) as u8;
+			len += 1;
+		}
+	}
+	Some(hash)
+}
+
+/// An `x.pe` peer: `host:port`, with IPv6 literals in brackets.
+fn decode_peer(input: &str) -> Result<(String, u16), MagnetError> {
+	let invalid = || MagnetError::InvalidPeer(input.to_string());
+	let (host, port) = input.rsplit_once(':').ok_or_else(invalid)?;
+	let port = port.parse().map_err(|_| invalid())?;
+	let bracketed = host.starts_with('[') && host.ends_with(']');
+	if host.is_empty() || port == 0 || (host.contains(':') && !bracketed) {
+		return Err(invalid());
+	}
+	Ok((host.to_string(), port))
+}
+
+/// An `so` selection: comma-separated file indices and inclusive ranges,
+/// such as `0,2,4-6`.
+fn decode_selection(input: &str) -> Result<Vec<RangeInclusive<usize>>, MagnetError> {
+	let invalid = || MagnetError::InvalidSelection(input.to_string());
+	input
+		.split(',')
+		.map(|item| {
+			let (start, end) = item.split_once('-').unwrap_or((item, item));
+			let start: usize = start.parse().map_err(|_| invalid())?;
+			let end: usize = end.parse().map_err(|_| invalid())?;
+			if start > end {
+				return Err(invalid());
+			}
+			Ok(start..=end)
+		})
+		.collect()
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_magnet() {
+        let input = "magnet:?xt=urn:btih:c5fb9894bdaba464811b088d806bdd611ba490af&dn=magnet1.gif&tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce";
+        let magnet = decode_magnet(input).unwrap();
+
+		assert_eq!(hex::encode(magnet.v1_info_hash().unwrap()), "c5fb9894bdaba464811b088d806bdd611ba490af");
+        assert_eq!(magnet.trackers, vec!["http://bittorrent-test-tracker.codecrafters.io/announce"]);
+        assert_eq!(magnet.display_name.as_deref(), Some("magnet1.gif"));
+    }
+
+    #[test]
+    fn test_magnet_trackers() {
+        let input = "magnet:?xt=urn:btih:c5fb9894bdaba464811b088d806bdd611ba490af&tr=http%3A%2F%2Fa%2Fannounce&tr=udp%3A%2F%2Fb%3A80";
+        let magnet = decode_magnet(input).unwrap();
+        assert_eq!(magnet.trackers, vec!["http://a/announce", "udp://b:80"]);
+        assert!(magnet.web_seeds.is_empty());
+    }
+
+	#[test]
+	fn test_every_parameter_and_round_trip() {
+		// The base32 form of c5fb9894bdaba464811b088d806bdd611ba490af.
+		let input = "magnet:?xt=urn:btih:yx5zrff5vosgjai3bcgya265men2jefp\
+			&xt=urn:btmh:1220caf1e1c30e81cb361b9ee167c4aa64228a7fa4fa9f6105232b28ad099f3a302e\
+			&dn=Caf%C3%A9+menu.txt&xl=1234&tr=http%3A%2F%2Fa%2Fannounce&tr=udp%3A%2F%2Fb%3A80\
+			&ws=http%3A%2F%2Fseed%2Fx&x.pe=10.0.0.1:6881&x.pe=%5B%3A%3A1%5D:51413&so=0,2,4-6&kt=cats";
+		let magnet = decode_magnet(input).unwrap();
+		assert_eq!(hex::encode(magnet.info_hash.unwrap()), "c5fb9894bdaba464811b088d806bdd611ba490af");
+		assert_eq!(
+			hex::encode(magnet.info_hash_v2.unwrap()),
+			"caf1e1c30e81cb361b9ee167c4aa64228a7fa4fa9f6105232b28ad099f3a302e"
+		);
+		assert_eq!(magnet.display_name.as_deref(), Some("Café menu.txt"));
+		assert_eq!(magnet.length, Some(1234));
+		assert_eq!(magnet.trackers, vec!["http://a/announce", "udp://b:80"]);
+		assert_eq!(magnet.web_seeds, vec!["http://seed/x"]);
+		assert_eq!(magnet.peers, vec![("10.0.0.1".to_string(), 6881), ("[::1]".to_string(), 51413)]);
+		assert_eq!(magnet.select_only, vec![0..=0, 2..=2, 4..=6]);
+		assert_eq!(magnet.extra, vec![("kt".to_string(), "cats".to_string())]);
+
+		assert_eq!(decode_magnet(&magnet.to_uri()).unwrap(), magnet);
+	}
+
+	#[test]
+	fn test_errors() {
+		let hash = "c5fb9894bdaba464811b088d806bdd611ba490af";
+		let error = |input: &str| decode_magnet(input).unwrap_err();
+		assert!(matches!(error(""), MagnetError::UnexpectedEnd));
+		assert!(matches!(error("http://x?xt=1"), MagnetError::InvalidFormat(_)));
+		assert!(matches!(error("magnet:?dn=x"), MagnetError::MissingInfoHash));
+		assert!(matches!(error("magnet:?xt=urn:btih:c5fb"), MagnetError::InvalidInfoHash(_)));
+		assert!(matches!(error("magnet:?xt=urn:btih:1X5ZRFF5VOSGJAI3BCGYA265MEN2JEFP"), MagnetError::InvalidInfoHash(_)));
+		assert!(matches!(error("magnet:?xt=urn:btmh:1114aa"), MagnetError::InvalidInfoHash(_)));
+		assert!(matches!(error(&format!("magnet:?xt=urn:btih:{}&xl=-1", hash)), MagnetError::InvalidLength(_)));
+		assert!(matches!(error(&format!("magnet:?xt=urn:btih:{}&x.pe=::1:80", hash)), MagnetError::InvalidPeer(_)));
+		assert!(matches!(error(&format!("magnet:?xt=urn:btih:{}&so=3-1", hash)), MagnetError::InvalidSelection(_)));
+		assert!(matches!(error(&format!("magnet:?xt=urn:btih:{}&dn=%zz", hash)), MagnetError::InvalidPercentEncoding(_)));
+		assert!(matches!(error(&format!("magnet:?xt=urn:btih:{}&dn=%FF", hash)), MagnetError::Utf8Error(_)));
+		assert!(matches!(error(&format!("magnet:?xt=urn:btih:{}&dn", hash)), MagnetError::InvalidFormat(_)));
+	}
+}
@@ -0,0 +1,39 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum MagnetError {
+    UnexpectedEnd,
+    InvalidFormat(String),
+    Utf8Error(std::str::Utf8Error),
+    /// Neither a `btih` nor a `btmh` exact topic.
+    MissingInfoHash,
+    InvalidInfoHash(String),
+    InvalidLength(String),
+    InvalidPeer(String),
+    InvalidSelection(String),
+    InvalidPercentEncoding(String),
+}
+
+impl fmt::Display for MagnetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MagnetError::UnexpectedEnd => write!(f, "Unexpected end of input"),
+            MagnetError::InvalidFormat(s) => write!(f, "Invalid format: {}", s),
+            MagnetError::Utf8Error(e) => write!(f, "UTF-8 error: {}", e),
+            MagnetError::MissingInfoHash => write!(f, "Missing BitTorrent info hash (xt=urn:btih: or urn:btmh:)"),
+            MagnetError::InvalidInfoHash(s) => write!(f, "Invalid info hash: {}", s),
+            MagnetError::InvalidLength(s) => write!(f, "Invalid exact length (xl): {}", s),
+            MagnetError::InvalidPeer(s) => write!(f, "Invalid peer address (x.pe): {}", s),
+            MagnetError::InvalidSelection(s) => write!(f, "Invalid file selection (so): {}", s),
+            MagnetError::InvalidPercentEncoding(s) => write!(f, "Invalid percent-encoding: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for MagnetError {}
+
+impl From<std::str::Utf8Error> for MagnetError {
+    fn from(err: std::str::Utf8Error) -> Self {
+        MagnetError::Utf8Error(err)
+    }
+}
\ No newline at end of file
@@ -0,0 +1,5 @@
+pub mod decode_magnet;
+pub mod error;
+
+pub use decode_magnet::{decode_magnet, MagnetLink};   // re-export
+pub use error::MagnetError;
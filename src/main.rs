@@ -1,22 +1,27 @@
 
-mod bencode;
-mod torrent;
-mod tracker;
 mod peer;
-mod utils;
 mod engine;
 mod message;
 mod piece_manager;
 mod piece_queue;
 mod magnet;
 mod file_io;
+mod pipeline;
+mod bitfield;
+mod listener;
+mod choker;
+mod resume;
+mod verify;
+mod pex;
 
 use clap::{Parser, Subcommand};
 use log::{error, info};
-use env_logger;
+use std::net::SocketAddr;
 use std::path::Path;
 
-use crate::engine::{decode_command, info_command, peers_command, handshake_command, download_piece_command, download_command, magnet_parse_command, magnet_handshake_command, magnet_info_command, magnet_download_piece_command, magnet_download_command};
+use rusbit_cli::{bencode, torrent, tracker, utils};
+use rusbit_cli::torrent::CreateOptions;
+use crate::engine::{decode_command, info_command, peers_command, handshake_command, download_piece_command, download_command, seed_command, verify_command, create_command, magnet_command, magnet_parse_command, magnet_handshake_command, magnet_info_command, magnet_download_piece_command, magnet_download_command, magnet_to_torrent_command};
 
 #[derive(Parser)]
 #[command(name = "rusbit-cli")]
@@ -71,9 +76,71 @@ enum Commands {
     },
     /// Download complete torrent
     Download {
-        /// Output file path
+        /// Output file path (parent directory for multi-file torrents)
+        #[arg(short, long)]
+        output: String,
+        /// Path to the torrent file
+        torrent_file: String,
+        /// Peer to connect to directly, as ip:port (repeatable)
+        #[arg(long = "peer", value_parser = parse_peer_addr)]
+        peers: Vec<(String, u16)>,
+    },
+    /// Seed a torrent from data already on disk (until interrupted)
+    Seed {
+        /// Path of the downloaded data (parent directory for multi-file torrents)
+        #[arg(short, long)]
+        output: String,
+        /// Path to the torrent file
+        torrent_file: String,
+    },
+    /// Check data on disk against the torrent's piece hashes
+    Verify {
+        /// Path of the downloaded data (parent directory for multi-file torrents)
+        #[arg(short, long)]
+        output: String,
+        /// Path to the torrent file
+        torrent_file: String,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Create a .torrent file from a file or directory
+    Create {
+        /// The file or directory to share
+        path: String,
+        /// Where to write the .torrent file
         #[arg(short, long)]
         output: String,
+        /// Tracker tier: one or more comma-separated announce URLs (repeat for more tiers)
+        #[arg(short, long = "tracker")]
+        trackers: Vec<String>,
+        /// Piece length in bytes, a power of two (chosen from the content size by default)
+        #[arg(long)]
+        piece_length: Option<usize>,
+        /// Free-form comment
+        #[arg(long)]
+        comment: Option<String>,
+        /// Name of the program that created the torrent
+        #[arg(long, default_value = concat!("rusbit-cli/", env!("CARGO_PKG_VERSION")))]
+        created_by: String,
+        /// Creation date in seconds since the UNIX epoch (now by default)
+        #[arg(long, conflicts_with = "no_creation_date")]
+        creation_date: Option<i64>,
+        /// Leave the creation date out
+        #[arg(long)]
+        no_creation_date: bool,
+        /// Mark the torrent private: peers only from its trackers
+        #[arg(long)]
+        private: bool,
+        /// Source tag, making the info hash unique to one tracker
+        #[arg(long)]
+        source: Option<String>,
+        /// Web seed URL (repeatable)
+        #[arg(short, long = "web-seed")]
+        web_seeds: Vec<String>,
+    },
+    /// Print a magnet link for a torrent file
+    Magnet {
         /// Path to the torrent file
         torrent_file: String,
     },
@@ -91,6 +158,9 @@ enum Commands {
     MagnetInfo {
         /// The magnet link
         magnet_link: String,
+        /// Peer to connect to directly, as ip:port (repeatable)
+        #[arg(long = "peer", value_parser = parse_peer_addr)]
+        peers: Vec<(String, u16)>,
     },
     /// Download piece via magnet link
     MagnetDownloadPiece {
@@ -101,10 +171,27 @@ enum Commands {
         magnet_link: String,
         /// Piece index to download
         piece_index: u32,
+        /// Also save the fetched metadata as a .torrent file
+        #[arg(long)]
+        save_torrent: Option<String>,
     },
     /// Download complete torrent via magnet link
     MagnetDownload {
-        /// Output file path
+        /// Output file path (parent directory for multi-file torrents)
+        #[arg(short, long)]
+        output: String,
+        /// The magnet link
+        magnet_link: String,
+        /// Also save the fetched metadata as a .torrent file
+        #[arg(long)]
+        save_torrent: Option<String>,
+        /// Peer to connect to directly, as ip:port (repeatable)
+        #[arg(long = "peer", value_parser = parse_peer_addr)]
+        peers: Vec<(String, u16)>,
+    },
+    /// Fetch the metadata of a magnet link and save it as a .torrent file
+    MagnetToTorrent {
+        /// Where to write the .torrent file
         #[arg(short, long)]
         output: String,
         /// The magnet link
@@ -173,14 +260,78 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .unwrap()
                 .block_on(download_piece_command(output, torrent_file, piece_index, cli.progress))
         }
-        Commands::Download { output, torrent_file } => {
+        Commands::Download { output, torrent_file, peers } => {
+            validate_file_path(&torrent_file)?;
+            validate_output_path(&output)?;
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(download_command(output, torrent_file, peers, cli.progress))
+        }
+        Commands::Seed { output, torrent_file } => {
             validate_file_path(&torrent_file)?;
             validate_output_path(&output)?;
             tokio::runtime::Builder::new_current_thread()
                 .enable_all()
                 .build()
                 .unwrap()
-                .block_on(download_command(output, torrent_file, cli.progress))
+                .block_on(seed_command(output, torrent_file))
+        }
+        Commands::Verify { output, torrent_file, json } => {
+            validate_file_path(&torrent_file)?;
+            validate_output_path(&output)?;
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(verify_command(output, torrent_file, json))
+        }
+        Commands::Create {
+            path,
+            output,
+            trackers,
+            piece_length,
+            comment,
+            created_by,
+            creation_date,
+            no_creation_date,
+            private,
+            source,
+            web_seeds,
+        } => {
+            validate_output_path(&output)?;
+            let creation_date = match creation_date {
+                Some(date) => Some(date),
+                None if no_creation_date => None,
+                None => Some(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs() as i64),
+            };
+            let options = CreateOptions {
+                piece_length,
+                trackers: trackers
+                    .iter()
+                    .map(|tier| tier.split(',').map(|url| url.trim().to_string()).filter(|url| !url.is_empty()).collect())
+                    .collect(),
+                comment,
+                created_by: Some(created_by),
+                creation_date,
+                private,
+                source,
+                web_seeds,
+            };
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(create_command(path, output, options))
+        }
+        Commands::Magnet { torrent_file } => {
+            validate_file_path(&torrent_file)?;
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(magnet_command(torrent_file))
         }
         Commands::MagnetParse { magnet_link } => {
             validate_magnet_link(&magnet_link)?;
@@ -198,31 +349,46 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .unwrap()
                 .block_on(magnet_handshake_command(magnet_link))
         }
-        Commands::MagnetInfo { magnet_link } => {
+        Commands::MagnetInfo { magnet_link, peers } => {
+            validate_magnet_link(&magnet_link)?;
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(magnet_info_command(magnet_link, peers))
+        }
+        Commands::MagnetDownloadPiece { output, magnet_link, piece_index, save_torrent } => {
             validate_magnet_link(&magnet_link)?;
+            validate_output_path(&output)?;
+            if let Some(path) = &save_torrent {
+                validate_output_path(path)?;
+            }
             tokio::runtime::Builder::new_current_thread()
                 .enable_all()
                 .build()
                 .unwrap()
-                .block_on(magnet_info_command(magnet_link))
+                .block_on(magnet_download_piece_command(output, magnet_link, piece_index, save_torrent, cli.progress))
         }
-        Commands::MagnetDownloadPiece { output, magnet_link, piece_index } => {
+        Commands::MagnetDownload { output, magnet_link, save_torrent, peers } => {
             validate_magnet_link(&magnet_link)?;
             validate_output_path(&output)?;
+            if let Some(path) = &save_torrent {
+                validate_output_path(path)?;
+            }
             tokio::runtime::Builder::new_current_thread()
                 .enable_all()
                 .build()
                 .unwrap()
-                .block_on(magnet_download_piece_command(output, magnet_link, piece_index, cli.progress))
+                .block_on(magnet_download_command(output, magnet_link, save_torrent, peers, cli.progress))
         }
-        Commands::MagnetDownload { output, magnet_link } => {
+        Commands::MagnetToTorrent { output, magnet_link } => {
             validate_magnet_link(&magnet_link)?;
             validate_output_path(&output)?;
             tokio::runtime::Builder::new_current_thread()
                 .enable_all()
                 .build()
                 .unwrap()
-                .block_on(magnet_download_command(output, magnet_link, cli.progress))
+                .block_on(magnet_to_torrent_command(output, magnet_link))
         }
     };
 
@@ -266,6 +432,16 @@ fn validate_peer_addr(addr: &str) -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// Parses a `--peer` address. IPv6 hosts keep their brackets, so the peer
+/// can be dialled as `host:port`.
+fn parse_peer_addr(addr: &str) -> Result<(String, u16), String> {
+    match addr.parse::<SocketAddr>() {
+        Ok(SocketAddr::V4(addr)) => Ok((addr.ip().to_string(), addr.port())),
+        Ok(SocketAddr::V6(addr)) => Ok((format!("[{}]", addr.ip()), addr.port())),
+        Err(_) => Err(format!("expected ip:port, got '{}'", addr)),
+    }
+}
+
 fn validate_magnet_link(link: &str) -> Result<(), Box<dyn std::error::Error>> {
     if !link.starts_with("magnet:?") {
         return Err("Invalid magnet link format".into());
@@ -10,19 +10,86 @@ use crate::bencode::{decode_bencode, BValue, encode_bvalue};
 pub const BT_PROTOCOL_STR: &str = "BitTorrent protocol";
 pub const BT_PROTOCOL_LEN: u8 = 19;
 
+/// The id we ask peers to use for ut_metadata messages sent to us.
+pub const UT_METADATA_ID: u8 = 20;
+
+/// The id we ask peers to use for ut_pex messages sent to us.
+pub const UT_PEX_ID: u8 = 21;
+
+/// Longest message we accept from a peer. Blocks are 16 KiB; this leaves
+/// room for the bitfield of a torrent with two million pieces and for large
+/// extension messages.
+pub const MAX_MESSAGE_LENGTH: usize = 1 << 18;
+
 /// The wire message types we support.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Message {
-    /// Standard messages
-    Interested,
+    /// Standard messages (BEP 3)
+    KeepAlive,
+    Choke,
     Unchoke,
-    Bitfield,
+    Interested,
+    NotInterested,
+    Have { index: u32 },
+    Bitfield(Vec<u8>),
     Request { index: u32, begin: u32, length: u32 },
-    Piece { payload: Vec<u8> },
+    Piece { payload: Vec<u8> }, // index, begin and the block
+    Cancel { index: u32, begin: u32, length: u32 },
+    Port(u16),
     /// Extended messages
     ExtendedHandshake(BValue),
 	ReceiveMetaData { ext_msg_id: u8, dict: BValue, payload: Vec<u8> },
-	RequestMetaData { ext_msg_id: u8, payload: Vec<u8> },
+}
+
+/// Serializes a message with its length prefix.
+pub fn encode_message(message: &Message) -> Vec<u8> {
+    // Message id followed by the payload.
+    let mut body: Vec<u8> = Vec::new();
+    match message {
+        Message::KeepAlive => {}
+        Message::Choke => body.push(0),
+        Message::Unchoke => body.push(1),
+        Message::Interested => body.push(2),
+        Message::NotInterested => body.push(3),
+        Message::Have { index } => {
+            body.push(4);
+            body.extend_from_slice(&index.to_be_bytes());
+        }
+        Message::Bitfield(bits) => {
+            body.push(5);
+            body.extend_from_slice(bits);
+        }
+        Message::Request { index, begin, length } | Message::Cancel { index, begin, length } => {
+            body.push(if matches!(message, Message::Request { .. }) { 6 } else { 8 });
+            body.extend_from_slice(&index.to_be_bytes());
+            body.extend_from_slice(&begin.to_be_bytes());
+            body.extend_from_slice(&length.to_be_bytes());
+        }
+        Message::Piece { payload } => {
+            body.push(7);
+            body.extend_from_slice(payload);
+        }
+        Message::Port(port) => {
+            body.push(9);
+            body.extend_from_slice(&port.to_be_bytes());
+        }
+        Message::ExtendedHandshake(dict) => {
+            body.push(20);
+            body.push(0); // extended handshake id
+            body.extend_from_slice(&encode_bvalue(dict));
+        }
+        Message::ReceiveMetaData { ext_msg_id, dict, payload } => {
+            body.push(20);
+            body.push(*ext_msg_id);
+            body.extend_from_slice(&encode_bvalue(dict));
+            body.extend_from_slice(payload);
+        }
+    }
+
+    let mut msg = Vec::with_capacity(4 + body.len());
+    msg.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    msg.extend_from_slice(&body);
+    msg
 }
 
 /// Sends a non-handshake message.
@@ -30,38 +97,41 @@ pub async fn send_message<S>(stream: &mut S, message: Message) -> Result<(), Err
 where
     S: AsyncWrite + Unpin,
 {
-    match message {
-        Message::Interested => {
-            // Interested message: length = 1 (id) + 0 payload
-            let msg = [0, 0, 0, 1, 2]; // “2” is the Interested message id
-            stream.write_all(&msg).await?;
-        }
-        Message::Request { index, begin, length } => {
-            // Request message: length (4 bytes) + id (1 byte = 6) + 12 bytes payload
-            let mut msg = Vec::with_capacity(17);
-            msg.extend_from_slice(&13_u32.to_be_bytes());
-            msg.push(6); // Request message id is 6.
-            msg.extend_from_slice(&index.to_be_bytes());
-            msg.extend_from_slice(&begin.to_be_bytes());
-            msg.extend_from_slice(&length.to_be_bytes());
-            stream.write_all(&msg).await?;
-        }
-		Message::RequestMetaData { ext_msg_id, payload }  => {
-            let mut msg: Vec<u8> = Vec::new();
-
-			let length = ((payload.len() + 2) as u32).to_be_bytes();
-			msg.extend_from_slice(&length);
-			msg.push(20);
-			msg.extend_from_slice(&[ext_msg_id]);
-			msg.extend_from_slice(&payload);
-            stream.write_all(&msg).await?;
-		}
-        _ => unimplemented!("send_message not implemented for {:?}", message),
-    }
+    stream.write_all(&encode_message(&message)).await?;
     stream.flush().await?;
     Ok(())
 }
 
+/// Reads `N` big-endian bytes from a fixed-size payload.
+fn fixed_payload<const N: usize>(payload: &[u8], name: &str) -> Result<[u8; N], Error> {
+    payload
+        .try_into()
+        .map_err(|_| Error::new(ErrorKind::InvalidData, format!("{} message must carry {} bytes", name, N)))
+}
+
+/// Parses the index, begin and length fields shared by Request and Cancel.
+fn block_fields(payload: &[u8], name: &str) -> Result<(u32, u32, u32), Error> {
+    let fields: [u8; 12] = fixed_payload(payload, name)?;
+    Ok((
+        u32::from_be_bytes(fields[0..4].try_into().unwrap()),
+        u32::from_be_bytes(fields[4..8].try_into().unwrap()),
+        u32::from_be_bytes(fields[8..12].try_into().unwrap()),
+    ))
+}
+
+/// Decodes a length prefix, refusing lengths above `MAX_MESSAGE_LENGTH` before
+/// anything is allocated for them.
+fn checked_length(len_buf: [u8; 4]) -> Result<usize, Error> {
+    let length = u32::from_be_bytes(len_buf) as usize;
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Message of {} bytes exceeds the limit of {}", length, MAX_MESSAGE_LENGTH),
+        ));
+    }
+    Ok(length)
+}
+
 /// Reads a message from the stream and converts it into our `Message` enum.
 pub async fn read_message<S>(stream: &mut S) -> Result<Message, Error>
 where
@@ -69,7 +139,10 @@ where
 {
     let mut len_buf = [0u8; 4];
     stream.read_exact(&mut len_buf).await?;
-    let length = u32::from_be_bytes(len_buf) as usize;
+    let length = checked_length(len_buf)?;
+    if length == 0 {
+        return Ok(Message::KeepAlive);
+    }
     let mut msg_buf = vec![0u8; length];
     stream.read_exact(&mut msg_buf).await?;
     
@@ -77,10 +150,33 @@ where
     let msg_id = msg_buf[0];
     let payload: Vec<u8> = msg_buf[1..].to_vec();
     match msg_id {
+        0 => Ok(Message::Choke),
         1 => Ok(Message::Unchoke),
         2 => Ok(Message::Interested),
-        5 => Ok(Message::Bitfield),
-        7 => Ok(Message::Piece { payload }),
+        3 => Ok(Message::NotInterested),
+        4 => {
+            let index = fixed_payload(&payload, "Have")?;
+            Ok(Message::Have { index: u32::from_be_bytes(index) })
+        }
+        5 => Ok(Message::Bitfield(payload)),
+        6 => {
+            let (index, begin, length) = block_fields(&payload, "Request")?;
+            Ok(Message::Request { index, begin, length })
+        }
+        7 => {
+            if payload.len() < 8 {
+                return Err(Error::new(ErrorKind::InvalidData, "Piece message too short"));
+            }
+            Ok(Message::Piece { payload })
+        }
+        8 => {
+            let (index, begin, length) = block_fields(&payload, "Cancel")?;
+            Ok(Message::Cancel { index, begin, length })
+        }
+        9 => {
+            let port = fixed_payload(&payload, "Port")?;
+            Ok(Message::Port(u16::from_be_bytes(port)))
+        }
         20 => {
             // For extended messages, the payload must start with an extension message id.
             if payload.is_empty() {
@@ -104,7 +200,43 @@ where
 				Ok(Message::ReceiveMetaData { ext_msg_id, dict, payload})
 			}
         }
-        _ => Err(Error::new(ErrorKind::Other, "Unknown message id")),
+        _ => Err(Error::other("Unknown message id")),
+    }
+}
+
+/// Reads messages like `read_message`, but buffers partial input so that a
+/// read abandoned in a `select!` loses nothing; the next read picks up where
+/// it stopped.
+#[derive(Debug, Default)]
+pub struct MessageReader {
+    buffer: Vec<u8>,
+}
+
+impl MessageReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn read<S>(&mut self, stream: &mut S) -> Result<Message, Error>
+    where
+        S: AsyncRead + Unpin,
+    {
+        loop {
+            if self.buffer.len() >= 4 {
+                let length = checked_length(self.buffer[0..4].try_into().unwrap())?;
+                if self.buffer.len() >= 4 + length {
+                    let frame: Vec<u8> = self.buffer.drain(..4 + length).collect();
+                    return read_message(&mut frame.as_slice()).await;
+                }
+            }
+            // `read` is cancel safe: when it does not complete, nothing was consumed.
+            let mut chunk = [0u8; 1 << 14];
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Peer closed the connection"));
+            }
+            self.buffer.extend_from_slice(&chunk[..n]);
+        }
     }
 }
 
@@ -137,8 +269,10 @@ where
 
 // Send an extended handshake message.
 /// This message has a header byte (0x14 for extended messages) and an extended id of 0.
-/// The payload is a bencoded dictionary.
-pub async fn send_extended_handshake<S> (stream: &mut S) -> std::io::Result<()> 
+/// The payload is a bencoded dictionary. `metadata_size` is the size of the
+/// info dict we can serve over ut_metadata, if any; `pex` advertises ut_pex;
+/// `max_requests` is how many block requests we queue for the peer (`reqq`).
+pub async fn send_extended_handshake<S> (stream: &mut S, metadata_size: Option<usize>, pex: bool, max_requests: Option<usize>) -> std::io::Result<()> 
 where 
 	S: AsyncWrite + Unpin
 {
@@ -150,8 +284,17 @@ where
     // We do not choose our own id here; we advertise support for "ut_metadata"
     // and the remote peer will assign an id for it.
 	let mut m: HashMap<String, BValue>  = HashMap::new();
-	m.insert("ut_metadata".into(), BValue::Integer(20));
+	m.insert("ut_metadata".into(), BValue::Integer(UT_METADATA_ID as i64));
+	if pex {
+		m.insert("ut_pex".into(), BValue::Integer(UT_PEX_ID as i64));
+	}
 	dict.insert("m".into(), BValue::Dict(m));
+	if let Some(size) = metadata_size {
+		dict.insert("metadata_size".into(), BValue::Integer(size as i64));
+	}
+	if let Some(max_requests) = max_requests {
+		dict.insert("reqq".into(), BValue::Integer(max_requests as i64));
+	}
 
     // Bencode
 	let payload = encode_bvalue(&BValue::Dict(dict));
@@ -172,22 +315,27 @@ where
 	Ok(())
 }
 
-/// Receives and validates the BitTorrent handshake. On success, returns the remote peer id.
-pub async fn receive_handshake<S>(
-    stream: &mut S,
-    expected_info_hash: &[u8; 20],
-) -> Result<([u8; 20], bool), Error>
+/// A handshake received from a peer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handshake {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub supports_extensions: bool,
+}
+
+/// Reads a BitTorrent handshake for any torrent, as an incoming connection does.
+pub async fn read_handshake<S>(stream: &mut S) -> Result<Handshake, Error>
 where
     S: AsyncRead + Unpin,
 {
     let mut buf = [0u8; 68];
     stream.read_exact(&mut buf).await?;
     if buf[0] != BT_PROTOCOL_LEN {
-        return Err(Error::new(ErrorKind::Other, "Invalid handshake pstrlen"));
+        return Err(Error::other("Invalid handshake pstrlen"));
     }
     let pstr_end = 1 + BT_PROTOCOL_LEN as usize;
     if &buf[1..pstr_end] != BT_PROTOCOL_STR.as_bytes() {
-        return Err(Error::new(ErrorKind::Other, "Invalid handshake pstr"));
+        return Err(Error::other("Invalid handshake pstr"));
     }
 
     // Extract reserved bytes.
@@ -198,10 +346,8 @@ where
     // Extract infohash.
     let infohash_start = pstr_end + 8;
     let infohash_end = infohash_start + 20;
-    let infohash = &buf[infohash_start..infohash_end];
-    if infohash != expected_info_hash {
-        return Err(Error::new(ErrorKind::Other, "Infohash mismatch"));
-    }
+    let mut info_hash = [0u8; 20];
+    info_hash.copy_from_slice(&buf[infohash_start..infohash_end]);
 
     // Extract peer id.
     let peer_id_start = infohash_end;
@@ -209,7 +355,22 @@ where
     let mut peer_id = [0u8; 20];
     peer_id.copy_from_slice(&buf[peer_id_start..peer_id_end]);
     
-    Ok((peer_id, supports_extensions))
+    Ok(Handshake { info_hash, peer_id, supports_extensions })
+}
+
+/// Receives and validates the BitTorrent handshake. On success, returns the remote peer id.
+pub async fn receive_handshake<S>(
+    stream: &mut S,
+    expected_info_hash: &[u8; 20],
+) -> Result<([u8; 20], bool), Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let handshake = read_handshake(stream).await?;
+    if &handshake.info_hash != expected_info_hash {
+        return Err(Error::other("Infohash mismatch"));
+    }
+    Ok((handshake.peer_id, handshake.supports_extensions))
 }
 
 mod tests {
@@ -275,12 +436,12 @@ mod tests {
 
 			// pstr ("BitTorrent protocol")
 			start += 1;
-			let mut end = start + BT_PROTOCOL_STR.as_bytes().len();
+			let mut end = start + BT_PROTOCOL_STR.len();
 			assert_eq!(&buf[start..end], BT_PROTOCOL_STR.as_bytes());
 
 			// 8-byte: reserved Exension
 			let reserved = [0u8; 8];
-			start = start + BT_PROTOCOL_LEN as usize;
+			start += BT_PROTOCOL_LEN as usize;
 			end = start + 8; 
 			assert_eq!(&buf[start..end], &reserved);
 			
@@ -323,13 +484,13 @@ mod tests {
 
 			// pstr ("BitTorrent protocol")
 			start += 1;
-			let mut end = start + BT_PROTOCOL_STR.as_bytes().len();
+			let mut end = start + BT_PROTOCOL_STR.len();
 			assert_eq!(&buf[start..end], BT_PROTOCOL_STR.as_bytes());
 
 			// 8-byte: reserved Exension
 			let mut reserved = [0u8; 8];
 			reserved[5] = 0x10;
-			start = start + BT_PROTOCOL_LEN as usize;
+			start += BT_PROTOCOL_LEN as usize;
 			end = start + 8; 
 			assert_eq!(&buf[start..end], &reserved);
 			
@@ -352,5 +513,99 @@ mod tests {
 		server_task.await.unwrap();
 	}
 
+	#[tokio::test]
+	async fn test_message_round_trip() {
+		let mut handshake = HashMap::new();
+		handshake.insert("reqq".to_string(), BValue::Integer(250));
+		let mut metadata = HashMap::new();
+		metadata.insert("msg_type".to_string(), BValue::Integer(1));
+
+		let messages = vec![
+			Message::KeepAlive,
+			Message::Choke,
+			Message::Unchoke,
+			Message::Interested,
+			Message::NotInterested,
+			Message::Have { index: 0x0102_0304 },
+			Message::Bitfield(vec![0b1010_0000, 0xff]),
+			Message::Bitfield(Vec::new()),
+			Message::Request { index: 1, begin: 16384, length: 16384 },
+			Message::Piece { payload: vec![0, 0, 0, 1, 0, 0, 0, 0, 42, 43] },
+			Message::Cancel { index: 1, begin: 32768, length: 100 },
+			Message::Port(6881),
+			Message::ExtendedHandshake(BValue::Dict(handshake)),
+			Message::ReceiveMetaData { ext_msg_id: 3, dict: BValue::Dict(metadata), payload: b"raw".to_vec() },
+		];
+
+		for message in messages {
+			let encoded = encode_message(&message);
+			let mut reader = &encoded[..];
+			let decoded = read_message(&mut reader).await.expect("decode failed");
+			assert_eq!(decoded, message);
+			assert!(reader.is_empty(), "{:?} left trailing bytes", message);
+		}
+	}
+
+	#[tokio::test]
+	async fn test_keep_alive_and_malformed_messages() {
+		let mut reader: &[u8] = &[0, 0, 0, 0];
+		assert_eq!(read_message(&mut reader).await.unwrap(), Message::KeepAlive);
 
-}
\ No newline at end of file
+		// Have with a truncated index.
+		let mut reader: &[u8] = &[0, 0, 0, 3, 4, 0, 1];
+		assert_eq!(read_message(&mut reader).await.unwrap_err().kind(), ErrorKind::InvalidData);
+
+		// Request missing its length field.
+		let mut reader: &[u8] = &[0, 0, 0, 9, 6, 0, 0, 0, 1, 0, 0, 0, 0];
+		assert!(read_message(&mut reader).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn test_oversized_length_is_refused() {
+		// A 4 GiB length prefix with no body behind it.
+		let mut reader: &[u8] = &[0xff, 0xff, 0xff, 0xff, 5];
+		assert_eq!(read_message(&mut reader).await.unwrap_err().kind(), ErrorKind::InvalidData);
+
+		let (mut client, mut server) = tokio::io::duplex(64);
+		client.write_all(&((MAX_MESSAGE_LENGTH + 1) as u32).to_be_bytes()).await.unwrap();
+		let mut message_reader = MessageReader::new();
+		assert_eq!(message_reader.read(&mut server).await.unwrap_err().kind(), ErrorKind::InvalidData);
+
+		// The limit itself is still fine.
+		let bitfield = encode_message(&Message::Bitfield(vec![0xff; MAX_MESSAGE_LENGTH - 1]));
+		let mut reader: &[u8] = &bitfield;
+		assert!(matches!(read_message(&mut reader).await.unwrap(), Message::Bitfield(_)));
+	}
+
+	#[tokio::test]
+	async fn test_send_message_writes_encoded_bytes() {
+		let (mut client, mut server) = tokio::io::duplex(64);
+		send_message(&mut client, Message::Cancel { index: 2, begin: 0, length: 16384 }).await.unwrap();
+		send_message(&mut client, Message::Port(51413)).await.unwrap();
+
+		assert_eq!(read_message(&mut server).await.unwrap(), Message::Cancel { index: 2, begin: 0, length: 16384 });
+		let mut port = [0u8; 7];
+		server.read_exact(&mut port).await.unwrap();
+		assert_eq!(port, [0, 0, 0, 3, 9, 0xc8, 0xd5]);
+	}
+
+	#[tokio::test]
+	async fn test_message_reader_survives_abandoned_reads() {
+		let (mut client, mut server) = tokio::io::duplex(64);
+		let mut reader = MessageReader::new();
+		let have = encode_message(&Message::Have { index: 7 });
+
+		// Half a message arrives, then the read is abandoned.
+		client.write_all(&have[..5]).await.unwrap();
+		let abandoned = tokio::time::timeout(std::time::Duration::from_millis(20), reader.read(&mut server)).await;
+		assert!(abandoned.is_err());
+
+		client.write_all(&have[5..]).await.unwrap();
+		client.write_all(&encode_message(&Message::Unchoke)).await.unwrap();
+		assert_eq!(reader.read(&mut server).await.unwrap(), Message::Have { index: 7 });
+		assert_eq!(reader.read(&mut server).await.unwrap(), Message::Unchoke);
+
+		drop(client);
+		assert_eq!(reader.read(&mut server).await.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+	}
+}
@@ -1,19 +1,29 @@
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
-use std::collections::HashMap;
-use std::time::Duration;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 use std::sync::Arc;
 use std::error::Error;
 use std::io::{Error as IoError, ErrorKind};
 
 use crate::torrent::TorrentInfo;
 use crate::message::{
-    Message, send_handshake, receive_handshake, send_message, read_message, send_extended_handshake,
+    Message, MessageReader, send_handshake, receive_handshake, send_message, read_message, send_extended_handshake,
+    UT_PEX_ID,
 };
 use crate::piece_manager::PieceManager;
 use crate::piece_queue::PieceQueue;
-use crate::bencode::{bvalue_to_json, encode_bvalue, decode_bencode, BValue};
-use crate::torrent::{get_integer, calculate_info_hash_from_struct};
+use crate::pipeline::RequestPipeline;
+use crate::listener::ActiveTorrent;
+use crate::bitfield::Bitfield;
+use crate::bencode::BValue;
+use crate::magnet::metadata::ut_metadata_id;
+use crate::pex::{ut_pex_id, PexMessage, PexState, Swarm, PEX_INTERVAL};
 use rusbit_cli::progress::ProgressTracker;
+use log::debug;
+
+/// A peer that sends nothing, not even a keep-alive, for this long is dropped.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
 
 /// The Peer structure now only holds connection and protocol state,
 /// and it delegates piece-related work to the PieceManager.
@@ -23,17 +33,29 @@ pub struct Peer {
     pub info_hash: [u8; 20],
     pub piece_manager: Option<PieceManager>,
     pub remote_supports_extensions: bool,
+    /// Where completed pieces are recorded so the listener can serve them.
+    pub active_torrent: Option<Arc<ActiveTorrent>>,
+    /// The pieces the remote peer has told us about.
+    pub remote_pieces: Bitfield,
+    pub remote_addr: Option<SocketAddr>,
+    /// Set when peers are exchanged (PEX) with the other download sessions.
+    pub swarm: Option<Arc<Swarm>>,
 }
 
 impl Peer {
     pub fn new(info_hash: [u8; 20], peer_id: [u8; 20], torrent_info: Option<TorrentInfo>) -> Self {
-        let piece_manager = torrent_info.map(|info| PieceManager::new(info));
+        let num_pieces = torrent_info.as_ref().map_or(0, |info| info.pieces.len());
+        let piece_manager = torrent_info.map(PieceManager::new);
         Self {
             peer_id,
             remote_peer_id: None,
             info_hash,
             piece_manager,
             remote_supports_extensions: false, // will update after handshake.
+            active_torrent: None,
+            remote_pieces: Bitfield::new(num_pieces),
+            remote_addr: None,
+            swarm: None,
         }
     }
 
@@ -49,13 +71,12 @@ impl Peer {
             .map_err(|_| IoError::new(ErrorKind::TimedOut, "Connection timed out"))??
             ;
         send_handshake(&mut stream, &self.info_hash, &self.peer_id, extension)
-            .await
-            .map_err(|e| e)?;
+            .await?;
         let (remote_id, remote_supports_extensions) =
             receive_handshake(&mut stream, &self.info_hash)
-                .await
-                .map_err(|e| e)?;
+                .await?;
         self.remote_peer_id = Some(remote_id);
+        self.remote_addr = stream.peer_addr().ok();
 
         // If we send an extension indicator in the reserved bits and the peer handshakes,
         // we can assume they support extensions.
@@ -64,184 +85,473 @@ impl Peer {
         Ok(stream)
     }
 
+    /// Downloads pieces from `piece_queue` over one connection until the queue
+    /// is empty or the peer fails.
+    ///
+    /// Pieces are picked rarest first among those the peer has, and requests
+    /// are pipelined through `pipeline`, which keeps a window of blocks
+    /// outstanding across piece boundaries. Once every piece is handed out
+    /// (endgame), the session also requests the missing blocks of pieces
+    /// other peers are downloading, and cancels the requests for blocks that
+    /// another peer delivers first. The pieces in flight when the peer fails
+    /// (or sends a piece that does not verify) go back into the queue for
+    /// another peer to pick up.
+    pub async fn run_download_session<S>(
+        &mut self,
+        mut stream: S,
+        output_path: &str,
+        piece_queue: Arc<PieceQueue>,
+        mut pipeline: RequestPipeline,
+        full_file: bool,
+        progress_tracker: Option<Arc<ProgressTracker>>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let result = self
+            .download_pieces(&mut stream, &mut pipeline, output_path, &piece_queue, full_file, progress_tracker)
+            .await;
+        for piece in pipeline.pieces() {
+            piece_queue.requeue_piece(*piece).await;
+        }
+        piece_queue.remove_peer_pieces(&self.remote_pieces);
+        if let (Some(swarm), Some(addr)) = (&self.swarm, self.remote_addr) {
+            swarm.disconnected(addr);
+        }
+        result
+    }
 
-	pub fn get_torrent_info(&self) ->  Result<TorrentInfo, Box<dyn Error + Send + Sync>>  {
-		if let Some(ref manager) = self.piece_manager {
-			Ok(manager.torrent_info.clone())
-		} else {
-			Err(IoError::new(
-				ErrorKind::Other,
-				"PieceManager not available for handling piece",
-			)
-			.into())
-		}
-	}
-
-
-
-    /// Runs the main loop to read and process messages.
-    /// When the peer sends a Bitfield, we reply with Interested;
-    /// when we receive an Unchoke, we ask for blocks;
-    /// when we receive a Piece message, we delegate to the PieceManager.
-    pub async fn run_message_loop(
+    async fn download_pieces<S>(
         &mut self,
-        mut stream: TcpStream,
-        piece_index: u32,
+        stream: &mut S,
+        pipeline: &mut RequestPipeline,
         output_path: &str,
-        in_progress: Arc<PieceQueue>,
+        piece_queue: &Arc<PieceQueue>,
         full_file: bool,
-		send_extension: bool,
         progress_tracker: Option<Arc<ProgressTracker>>,
-	    ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        loop {
-            let message = read_message(&mut stream).await?;
+    ) -> Result<(), Box<dyn Error + Send + Sync>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let manager = self.piece_manager.as_mut().ok_or_else(|| {
+            IoError::other("PieceManager not available for downloading pieces")
+        })?;
+        let num_pieces = manager.torrent_info.pieces.len();
+        self.remote_pieces = Bitfield::new(num_pieces);
+        let mut interested = false;
+        let mut choked = true;
 
-            match message {
-                Message::Bitfield => {
-                    // If the remote supports extensions, perform an extended handshake.
-                    if self.remote_supports_extensions {
-                        send_extended_handshake(&mut stream).await?;
-                        continue;
-                    }
+        // The peer's extended handshake tells us how many requests it queues
+        // (`reqq`) and whether it exchanges peers.
+        if self.remote_supports_extensions {
+            send_extended_handshake(stream, None, self.swarm.is_some(), None).await?;
+        }
+        if let (Some(swarm), Some(addr)) = (&self.swarm, self.remote_addr) {
+            swarm.connected(addr);
+        }
+        let mut pex = PexState::new();
+        let mut peer_ut_pex = None;
+        // The first tick is immediate, so the peer hears about the swarm as
+        // soon as its extended handshake arrives.
+        let mut pex_timer = tokio::time::interval(PEX_INTERVAL);
+        // Blocks count towards the peer's download rate, which earns it upload slots.
+        let choker_peer = self.remote_peer_id.zip(self.active_torrent.clone());
+        if let Some((remote_id, torrent)) = &choker_peer {
+            torrent.choker.start_download(*remote_id);
+        }
 
-                    // After receiving bitfield, signal our interest.
-                    send_message(&mut stream, Message::Interested)
-                        .await
-                        .map_err(|e| e)?;
+        let mut reader = MessageReader::new();
+        let mut delivered = piece_queue.subscribe();
+        loop {
+            let message = tokio::select! {
+                message = tokio::time::timeout(IDLE_TIMEOUT, reader.read(stream)) => {
+                    Some(message.map_err(|_| IoError::new(ErrorKind::TimedOut, "Peer went quiet"))??)
                 }
-                Message::Unchoke => {
-                    if let Some(ref mut manager) = self.piece_manager {
-                        manager.request_blocks(&mut stream, piece_index)
-                            .await
-                            .map_err(|e| e)?;
-                    } else {
-                        return Err(IoError::new(
-                            ErrorKind::Other,
-                            "PieceManager not available for requesting blocks",
+                Ok(block) = delivered.recv() => {
+                    if pipeline.cancel_block(block) {
+                        send_message(
+                            stream,
+                            Message::Cancel {
+                                index: block.index,
+                                begin: block.begin,
+                                length: block.length,
+                            },
                         )
-                        .into());
+                        .await?;
+                    }
+                    for piece in pipeline.drop_empty_pieces() {
+                        piece_queue.requeue_piece(piece).await;
                     }
+                    None
                 }
-                Message::Piece { payload } => {
-                    if let Some(ref mut manager) = self.piece_manager {
+                _ = pex_timer.tick(), if peer_ut_pex.is_some() => {
+                    if let (Some(swarm), Some(ut_pex)) = (&self.swarm, peer_ut_pex) {
+                        if let Some(message) = pex.next_message(swarm, self.remote_addr) {
+                            debug!("Sending PEX: {} added, {} dropped", message.added.len(), message.dropped.len());
+                            send_message(stream, message.to_message(ut_pex)).await?;
+                        }
+                    }
+                    None
+                }
+            };
+
+            // `None` when we only cancelled blocks another peer delivered.
+            if let Some(message) = message {
+                match message {
+                    Message::KeepAlive => {}
+                    Message::Bitfield(bytes) => {
+                        let remote_pieces = Bitfield::from_bytes(&bytes, num_pieces).ok_or_else(|| {
+                            IoError::new(ErrorKind::InvalidData, "Bitfield does not match the torrent")
+                        })?;
+                        piece_queue.remove_peer_pieces(&self.remote_pieces);
+                        piece_queue.add_peer_pieces(&remote_pieces);
+                        self.remote_pieces = remote_pieces;
+                    }
+                    Message::Have { index } => {
+                        debug!("Peer now has piece {}", index);
+                        if (index as usize) < num_pieces && !self.remote_pieces.has(index) {
+                            self.remote_pieces.set(index);
+                            piece_queue.add_peer_piece(index);
+                        }
+                    }
+                    Message::ExtendedHandshake(handshake) => {
+                        if let BValue::Dict(dict) = &handshake {
+                            if let Some(BValue::Integer(reqq)) = dict.get("reqq") {
+                                if *reqq > 0 {
+                                    pipeline.set_peer_limit(*reqq as usize);
+                                    debug!("Peer queues up to {} requests", reqq);
+                                }
+                            }
+                        }
+                        if self.swarm.is_some() {
+                            peer_ut_pex = ut_pex_id(&handshake);
+                        }
+                    }
+                    Message::ReceiveMetaData { ext_msg_id: UT_PEX_ID, dict, .. } => {
+                        if let Some(swarm) = &self.swarm {
+                            if pex.accept_incoming(Instant::now()) {
+                                swarm.discover(&PexMessage::parse(&dict));
+                            } else {
+                                debug!("Ignoring PEX message sent too soon");
+                            }
+                        }
+                    }
+                    Message::Choke => {
+                        // The peer drops our outstanding requests; ask again after the next unchoke.
+                        choked = true;
+                        pipeline.on_choke();
+                    }
+                    Message::Unchoke => {
+                        choked = false;
+                    }
+                    Message::Piece { payload } => {
+                        if payload.len() < 8 {
+                            return Err(IoError::new(ErrorKind::InvalidData, "Payload too short").into());
+                        }
+                        let index = u32::from_be_bytes(payload[0..4].try_into().unwrap());
+                        let begin = u32::from_be_bytes(payload[4..8].try_into().unwrap());
+                        if !pipeline.on_block(index, begin, (payload.len() - 8) as u32) {
+                            debug!("Ignoring unrequested block {}:{}", index, begin);
+                            continue;
+                        }
+                        if let Some((remote_id, torrent)) = &choker_peer {
+                            torrent.choker.record_download(*remote_id, (payload.len() - 8) as u64);
+                        }
                         let piece_complete = manager
-                            .handle_piece(payload, output_path, &in_progress, full_file)
-                            .await
-                            .map_err(|e| e)?;
+                            .handle_piece(payload, output_path, piece_queue, full_file)
+                            .await?;
                         if piece_complete {
-                            println!(
-                                "Piece {} completely downloaded and written.",
-                                piece_index
-                            );
+                            pipeline.finish_piece(index);
+                            if let Some(torrent) = &self.active_torrent {
+                                torrent.mark_have(index);
+                            }
+                            println!("Piece {} completely downloaded and written.", index);
                             if let Some(tracker) = &progress_tracker {
                                 tracker.increment();
                             }
-                            break;
                         }
-                    } else {
-                        return Err(IoError::new(
-                            ErrorKind::Other,
-                            "PieceManager not available for handling piece",
-                        )
-                        .into());
+                    }
+                    _ => {
+                        debug!("Unhandled message: {:?}", message);
+                        continue;
                     }
                 }
-                Message::ExtendedHandshake(payload) => {
-
-
-					
-                    let json_payload = bvalue_to_json(&payload);
-                    let m = json_payload.get("m").ok_or_else(|| {
-                        IoError::new(ErrorKind::InvalidData, "Missing key 'm' in extended handshake")
-                    })?;
+            }
 
-                    let ut_metadata = m.get("ut_metadata").ok_or_else(|| {
-                        IoError::new(ErrorKind::InvalidData, "Missing key 'ut_metadata' in extended handshake")
-                    })?;
+            if !interested && self.remote_pieces.count() > 0 {
+                send_message(stream, Message::Interested).await?;
+                interested = true;
+            }
+            if choked {
+                continue;
+            }
+            // Top the window up, pulling in further pieces the peer has, and in
+            // endgame the missing blocks of pieces others are downloading.
+            while pipeline.wants_piece() {
+                if let Some(piece) = piece_queue.pick_piece(&self.remote_pieces).await {
+                    pipeline.add_piece(piece, manager.blocks(piece));
+                } else if let Some(piece) =
+                    piece_queue.pick_endgame_piece(&self.remote_pieces, pipeline.pieces()).await
+                {
+                    if let Some(tracker) = &progress_tracker {
+                        tracker.start_endgame();
+                    }
+                    pipeline.add_piece(piece, piece_queue.missing_blocks(piece, manager.blocks(piece)));
+                } else {
+                    break;
+                }
+            }
+            // With pieces left that the peer lacks, wait for it to announce them.
+            if pipeline.is_idle() && piece_queue.is_empty().await {
+                return Ok(());
+            }
+            for block in pipeline.next_requests() {
+                send_message(
+                    stream,
+                    Message::Request {
+                        index: block.index,
+                        begin: block.begin,
+                        length: block.length,
+                    },
+                )
+                .await?;
+            }
+        }
+    }
 
+    /// Waits for the peer's extended handshake and prints the id it assigns
+    /// to ut_metadata. When the peer sends a Bitfield, we reply with our own
+    /// extended handshake. Fetching the metadata itself is up to
+    /// `magnet::metadata::fetch_metadata`.
+    pub async fn run_message_loop(&mut self, mut stream: TcpStream) -> Result<(), Box<dyn Error + Send + Sync>> {
+        loop {
+            let message = read_message(&mut stream).await?;
 
-                    // Try to extract the value as an integer.
-                    let ut_metadata_int = ut_metadata.as_i64().ok_or_else(|| {
-                        IoError::new(ErrorKind::InvalidData, "'ut_metadata' is not a number")
-                    })?;
-                    // Check that the number is within the valid range for u8.
-                    if ut_metadata_int < 1 || ut_metadata_int > 255 {
-                        return Err(IoError::new(
-                            ErrorKind::InvalidData,
-                            "'ut_metadata' value is out of range for u8",
-                        )
-                        .into());
+            match message {
+                Message::Bitfield(_) => {
+                    if !self.remote_supports_extensions {
+                        return Err(IoError::other("Peer does not support the extension protocol").into());
                     }
-
-                    println!("Peer Metadata Extension ID: {}", ut_metadata_int);
-					if !send_extension {
-						break
-					}
-
-                    let mut msg_map: HashMap<String, BValue> = HashMap::new();
-                    msg_map.insert("msg_type".into(), BValue::Integer(0));
-                    msg_map.insert("piece".into(), BValue::Integer(0));
-                    let payload = encode_bvalue(&BValue::Dict(msg_map));
-
-                    send_message(
-                        &mut stream,
-                        Message::RequestMetaData {
-                            ext_msg_id: ut_metadata_int as u8,
-                            payload,
-                        },
-                    )
-                    .await
-                    .map_err(|e| e)?;
+                    send_extended_handshake(&mut stream, None, false, None).await?;
+                }
+                Message::ExtendedHandshake(payload) => {
+                    let ut_metadata = ut_metadata_id(&payload).ok_or_else(|| {
+                        IoError::new(ErrorKind::InvalidData, "Missing or invalid 'ut_metadata' in extended handshake")
+                    })?;
+                    println!("Peer Metadata Extension ID: {}", ut_metadata);
+                    return Ok(());
                 }
-                Message::ReceiveMetaData { ext_msg_id: _u8, dict, payload } => {
-					let root_dict = match dict {
-						BValue::Dict(m) => m,
-						_ => {
-							return Err(
-								IoError::new(ErrorKind::InvalidData, "Root of .torrent must be a dictionary")
-									.into(),
-							);
-						}
-					};
-
-
-					let msg_type = get_integer(&root_dict, "msg_type")?;
-                    let piece= get_integer(&root_dict, "piece")?;
-
-					println!("msg_type: {}", msg_type);
-					println!("piece: {}", piece);
-
-					 let (_consumed, bvalue) = decode_bencode(&payload)
-					 	.map_err(|e| IoError::new(ErrorKind::InvalidData, e))?;
-
-
-					// Ensure the torrent_info is a dictionary.
-					let torrent_dict = match bvalue {
-						BValue::Dict(m) => m,
-						_ => {
-							return Err(
-								IoError::new(ErrorKind::InvalidData, "Root of .torrent must be a dictionary")
-									.into(),
-							);
-						}
-					};
-					let info: TorrentInfo =  TorrentInfo::from_bvalue(&torrent_dict)?;
-					let info_hash = calculate_info_hash_from_struct(&info);
-
-					if &info_hash != &self.info_hash {
-						return Err(
-							IoError::new(ErrorKind::InvalidData, "InfoHashes dont match")
-								.into(),
-						);
-					}
-					self.piece_manager = Some(PieceManager::new(info));
-					break
-				}
                 _ => {
                     println!("Unhandled message: {:?}", message);
                 }
             }
         }
-        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{HashMap, VecDeque};
+    use sha1::{Digest, Sha1};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    fn test_info(content: &[u8], piece_length: usize) -> TorrentInfo {
+        TorrentInfo {
+            length: content.len(),
+            name: "content.bin".to_string(),
+            piece_length,
+            pieces: content
+                .chunks(piece_length)
+                .map(|piece| Sha1::digest(piece).into())
+                .collect(),
+            files: None,
+            extra: HashMap::new(),
+        }
+    }
+
+    /// Acts as a seeder on one connection: announces a bitfield, unchokes and
+    /// answers every request from `content`. Returns the number of requests served.
+    async fn serve(mut stream: DuplexStream, content: Vec<u8>, piece_length: usize) -> usize {
+        stream.write_all(&[0, 0, 0, 2, 5, 0xff]).await.unwrap();
+        stream.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+
+        let mut served = 0;
+        loop {
+            let mut len = [0u8; 4];
+            if stream.read_exact(&mut len).await.is_err() {
+                return served;
+            }
+            let mut body = vec![0u8; u32::from_be_bytes(len) as usize];
+            stream.read_exact(&mut body).await.unwrap();
+            if body[0] != 6 {
+                continue;
+            }
+            let index = u32::from_be_bytes(body[1..5].try_into().unwrap()) as usize;
+            let begin = u32::from_be_bytes(body[5..9].try_into().unwrap()) as usize;
+            let length = u32::from_be_bytes(body[9..13].try_into().unwrap()) as usize;
+            let start = index * piece_length + begin;
+
+            let mut msg = ((9 + length) as u32).to_be_bytes().to_vec();
+            msg.push(7);
+            msg.extend_from_slice(&body[1..9]);
+            msg.extend_from_slice(&content[start..start + length]);
+            stream.write_all(&msg).await.unwrap();
+            served += 1;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_downloads_every_piece_over_one_connection() {
+        let content: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+        let piece_length = 20_000;
+        let info = test_info(&content, piece_length);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("content.bin");
+
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let seeder = tokio::spawn(serve(server, content.clone(), piece_length));
+
+        let queue = Arc::new(PieceQueue::new(VecDeque::from(vec![0, 1, 2])));
+        let tracker = Arc::new(ProgressTracker::new(3));
+        let mut peer = Peer::new([1u8; 20], [2u8; 20], Some(info));
+        peer.run_download_session(client, output.to_str().unwrap(), Arc::clone(&queue), RequestPipeline::new(4, 16), true, Some(Arc::clone(&tracker)))
+            .await
+            .unwrap();
+
+        // Two blocks for each full piece and one for the short last piece.
+        assert_eq!(seeder.await.unwrap(), 5);
+        assert!(tracker.is_complete());
+        assert!(queue.is_empty().await);
+        assert_eq!(std::fs::read(&output).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn test_session_requeues_piece_when_peer_fails() {
+        let content = vec![7u8; 1000];
+        let info = test_info(&content, 500);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("content.bin");
+
+        // The peer has both pieces, unchokes us and then hangs up before
+        // sending any block.
+        let (client, mut server) = tokio::io::duplex(1 << 16);
+        server.write_all(&[0, 0, 0, 2, 5, 0b1100_0000]).await.unwrap();
+        server.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+        drop(server);
+
+        let queue = Arc::new(PieceQueue::new(VecDeque::from(vec![0, 1])));
+        let mut peer = Peer::new([1u8; 20], [2u8; 20], Some(info));
+        let result = peer
+            .run_download_session(client, output.to_str().unwrap(), Arc::clone(&queue), RequestPipeline::new(4, 16), true, None)
+            .await;
+
+        assert!(result.is_err());
+        let has_both = Bitfield::from_bytes(&[0b1100_0000], 2).unwrap();
+        let mut remaining = vec![queue.pick_piece(&has_both).await, queue.pick_piece(&has_both).await];
+        remaining.sort();
+        assert_eq!(remaining, vec![Some(0), Some(1)]);
+    }
+
+    /// A peer that has everything and unchokes us, but never sends a block.
+    /// Reports the first request it sees, then counts the cancels until the
+    /// connection closes.
+    async fn stall(mut stream: DuplexStream, requested: tokio::sync::oneshot::Sender<()>) -> usize {
+        stream.write_all(&[0, 0, 0, 2, 5, 0xff]).await.unwrap();
+        stream.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+
+        let mut requested = Some(requested);
+        let mut cancels = 0;
+        while let Ok(message) = read_message(&mut stream).await {
+            match message {
+                Message::Request { .. } => {
+                    if let Some(requested) = requested.take() {
+                        requested.send(()).unwrap();
+                    }
+                }
+                Message::Cancel { .. } => cancels += 1,
+                _ => {}
+            }
+        }
+        cancels
+    }
+
+    #[tokio::test]
+    async fn test_endgame_fetches_stalled_piece_from_another_peer() {
+        let content: Vec<u8> = (0..40_000u32).map(|i| (i % 249) as u8).collect();
+        let info = test_info(&content, content.len());
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("content.bin");
+        let output = output.to_str().unwrap().to_string();
+        let queue = Arc::new(PieceQueue::new(VecDeque::from(vec![0])));
+        let tracker = Arc::new(ProgressTracker::new(1));
+
+        // The only piece goes to a peer that stalls.
+        let (slow_client, slow_server) = tokio::io::duplex(1 << 16);
+        let (requested_tx, requested_rx) = tokio::sync::oneshot::channel();
+        let slow_peer = tokio::spawn(stall(slow_server, requested_tx));
+        let mut slow = Peer::new([1u8; 20], [2u8; 20], Some(info.clone()));
+        let slow_session = {
+            let (queue, output) = (Arc::clone(&queue), output.clone());
+            tokio::spawn(async move {
+                slow.run_download_session(slow_client, &output, queue, RequestPipeline::new(4, 16), true, None).await
+            })
+        };
+        requested_rx.await.unwrap();
+
+        // With nothing left in the queue, a second peer requests the same blocks.
+        let (fast_client, fast_server) = tokio::io::duplex(1 << 16);
+        let fast_peer = tokio::spawn(serve(fast_server, content.clone(), content.len()));
+        let mut fast = Peer::new([1u8; 20], [3u8; 20], Some(info));
+        fast.run_download_session(fast_client, &output, Arc::clone(&queue), RequestPipeline::new(4, 16), true, Some(Arc::clone(&tracker)))
+            .await
+            .unwrap();
+
+        assert!(tracker.is_endgame() && tracker.is_complete());
+        assert_eq!(fast_peer.await.unwrap(), 3);
+        // The stalled requests are cancelled and the slow session ends too.
+        slow_session.await.unwrap().unwrap();
+        assert_eq!(slow_peer.await.unwrap(), 3);
+        assert_eq!(std::fs::read(&output).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn test_session_exchanges_peers() {
+        let info = test_info(&[1u8; 100], 100);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("content.bin");
+        let swarm = Arc::new(Swarm::new());
+        let other: SocketAddr = "10.0.0.5:6881".parse().unwrap();
+        swarm.connected(other);
+
+        // The remote peer supports ut_pex as id 7, tells us about a peer and
+        // hangs up once it has heard about ours.
+        let (client, mut server) = tokio::io::duplex(1 << 16);
+        let remote = tokio::spawn(async move {
+            let mut handshake = HashMap::new();
+            let mut m = HashMap::new();
+            m.insert("ut_pex".to_string(), BValue::Integer(7));
+            handshake.insert("m".to_string(), BValue::Dict(m));
+            send_message(&mut server, Message::ExtendedHandshake(BValue::Dict(handshake))).await.unwrap();
+            let learned = PexMessage { added: vec![("10.0.0.7:6881".parse().unwrap(), 0)], dropped: Vec::new() };
+            send_message(&mut server, learned.to_message(UT_PEX_ID)).await.unwrap();
+            loop {
+                if let Message::ReceiveMetaData { ext_msg_id: 7, dict, .. } = read_message(&mut server).await.unwrap() {
+                    return PexMessage::parse(&dict);
+                }
+            }
+        });
+
+        let queue = Arc::new(PieceQueue::new(VecDeque::from(vec![0])));
+        let mut peer = Peer::new([1u8; 20], [2u8; 20], Some(info));
+        peer.remote_supports_extensions = true;
+        peer.swarm = Some(Arc::clone(&swarm));
+        let result = peer
+            .run_download_session(client, output.to_str().unwrap(), queue, RequestPipeline::new(4, 16), true, None)
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(remote.await.unwrap().added, vec![(other, crate::pex::FLAG_REACHABLE)]);
+        assert_eq!(swarm.next_discovered().await, vec!["10.0.0.7:6881".parse().unwrap()]);
     }
 }
@@ -0,0 +1,329 @@
+// pex.rs
+//! Peer exchange (ut_pex, BEP 11): connected peers tell each other about
+//! the other peers they are connected to, so the swarm grows without asking
+//! the tracker again.
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use tokio::sync::Notify;
+
+use crate::bencode::BValue;
+use crate::message::Message;
+
+/// How often we send PEX to a peer; BEP 11 allows at most one message a minute.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Most peers a message may add, and separately drop.
+pub const MAX_PEX_PEERS: usize = 50;
+
+/// Messages from a peer that arrive sooner than this after its previous one
+/// are ignored. A little under `PEX_INTERVAL`, to allow for timer jitter.
+const MIN_INCOMING_INTERVAL: Duration = Duration::from_secs(45);
+
+/// `added.f` flag: the peer accepts incoming connections, as it does when
+/// we connected to it.
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+/// A ut_pex message: peers that joined and left the sender's swarm since its
+/// previous message.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PexMessage {
+    pub added: Vec<(SocketAddr, u8)>, // With their `added.f` flags
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    /// Parses the bencoded dict of a ut_pex message. Entries beyond
+    /// `MAX_PEX_PEERS` are ignored, as is a trailing partial entry.
+    pub fn parse(dict: &BValue) -> Self {
+        let BValue::Dict(dict) = dict else { return Self::default() };
+        let bytes = |key: &str| match dict.get(key) {
+            Some(BValue::ByteString(bytes)) => bytes.as_slice(),
+            _ => &[],
+        };
+
+        let mut added = Vec::new();
+        for (peers, flags, size) in [("added", "added.f", 6), ("added6", "added6.f", 18)] {
+            let flags = bytes(flags);
+            for (i, peer) in bytes(peers).chunks_exact(size).enumerate() {
+                added.push((decode_peer(peer), flags.get(i).copied().unwrap_or(0)));
+            }
+        }
+        let mut dropped: Vec<SocketAddr> = bytes("dropped").chunks_exact(6).map(decode_peer).collect();
+        dropped.extend(bytes("dropped6").chunks_exact(18).map(decode_peer));
+
+        added.truncate(MAX_PEX_PEERS);
+        dropped.truncate(MAX_PEX_PEERS);
+        Self { added, dropped }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+
+    /// The wire message, for a peer that assigned ut_pex the id `ext_msg_id`.
+    pub fn to_message(&self, ext_msg_id: u8) -> Message {
+        let (mut added, mut added_f, mut added6, mut added6_f) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        for (addr, flags) in &self.added {
+            match addr {
+                SocketAddr::V4(_) => {
+                    added.extend(encode_peer(addr));
+                    added_f.push(*flags);
+                }
+                SocketAddr::V6(_) => {
+                    added6.extend(encode_peer(addr));
+                    added6_f.push(*flags);
+                }
+            }
+        }
+        let (mut dropped, mut dropped6) = (Vec::new(), Vec::new());
+        for addr in &self.dropped {
+            match addr {
+                SocketAddr::V4(_) => dropped.extend(encode_peer(addr)),
+                SocketAddr::V6(_) => dropped6.extend(encode_peer(addr)),
+            }
+        }
+
+        let mut dict = HashMap::new();
+        for (key, value) in [
+            ("added", added),
+            ("added.f", added_f),
+            ("added6", added6),
+            ("added6.f", added6_f),
+            ("dropped", dropped),
+            ("dropped6", dropped6),
+        ] {
+            dict.insert(key.to_string(), BValue::ByteString(value));
+        }
+        Message::ReceiveMetaData { ext_msg_id, dict: BValue::Dict(dict), payload: Vec::new() }
+    }
+}
+
+/// The id a peer's extended handshake assigns to ut_pex, if it supports the
+/// extension.
+pub fn ut_pex_id(handshake: &BValue) -> Option<u8> {
+    let BValue::Dict(handshake) = handshake else { return None };
+    match handshake.get("m") {
+        Some(BValue::Dict(m)) => match m.get("ut_pex") {
+            Some(BValue::Integer(id)) => u8::try_from(*id).ok().filter(|id| *id != 0),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The port a peer's extended handshake says it accepts connections on
+/// (`p`), if given.
+pub fn listen_port(handshake: &BValue) -> Option<u16> {
+    let BValue::Dict(handshake) = handshake else { return None };
+    match handshake.get("p") {
+        Some(BValue::Integer(port)) => u16::try_from(*port).ok().filter(|port| *port != 0),
+        _ => None,
+    }
+}
+
+/// A compact peer: 4 or 16 bytes of address, then the port.
+fn decode_peer(bytes: &[u8]) -> SocketAddr {
+    let (ip, port) = bytes.split_at(bytes.len() - 2);
+    let ip = match <[u8; 4]>::try_from(ip) {
+        Ok(ip) => IpAddr::V4(Ipv4Addr::from(ip)),
+        Err(_) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap())),
+    };
+    SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]]))
+}
+
+fn encode_peer(addr: &SocketAddr) -> Vec<u8> {
+    let mut bytes = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    bytes.extend_from_slice(&addr.port().to_be_bytes());
+    bytes
+}
+
+/// The peers of one torrent, shared by its download sessions: who we are
+/// connected to (what we tell others about), and the peers others told us
+/// about or announced on the local network that we have not tried yet.
+#[derive(Debug, Default)]
+pub struct Swarm {
+    connected: Mutex<HashSet<SocketAddr>>,
+    known: Mutex<HashSet<SocketAddr>>, // Every peer we tried or queued
+    discovered: Mutex<VecDeque<SocketAddr>>,
+    notify: Notify,
+}
+
+impl Swarm {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remembers a peer we are about to try, so PEX does not queue it again.
+    pub fn add_known(&self, addr: SocketAddr) {
+        self.known.lock().unwrap().insert(addr);
+    }
+
+    pub fn connected(&self, addr: SocketAddr) {
+        self.connected.lock().unwrap().insert(addr);
+    }
+
+    pub fn disconnected(&self, addr: SocketAddr) {
+        self.connected.lock().unwrap().remove(&addr);
+    }
+
+    /// Queues the peers of a PEX message we have not seen before.
+    pub fn discover(&self, message: &PexMessage) {
+        self.add_discovered(message.added.iter().map(|(addr, _)| *addr));
+    }
+
+    /// Queues the peers we have not seen before, however they were learned.
+    pub fn add_discovered(&self, peers: impl IntoIterator<Item = SocketAddr>) {
+        let mut known = self.known.lock().unwrap();
+        let mut discovered = self.discovered.lock().unwrap();
+        for addr in peers {
+            if addr.port() != 0 && known.insert(addr) {
+                debug!("Learned peer {}", addr);
+                discovered.push_back(addr);
+            }
+        }
+        if !discovered.is_empty() {
+            self.notify.notify_one();
+        }
+    }
+
+    /// Waits for peers learned through PEX or Local Service Discovery and
+    /// takes them.
+    pub async fn next_discovered(&self) -> Vec<SocketAddr> {
+        loop {
+            let peers: Vec<SocketAddr> = self.discovered.lock().unwrap().drain(..).collect();
+            if !peers.is_empty() {
+                return peers;
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+/// What we have told one peer so far, and when.
+#[derive(Debug, Default)]
+pub struct PexState {
+    advertised: HashSet<SocketAddr>,
+    last_received: Option<Instant>,
+}
+
+impl PexState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The next message for `remote`: the connected peers it has not heard
+    /// about, and the ones that left since, up to `MAX_PEX_PEERS` of each.
+    /// `None` if there is no news.
+    pub fn next_message(&mut self, swarm: &Swarm, remote: Option<SocketAddr>) -> Option<PexMessage> {
+        let connected = swarm.connected.lock().unwrap();
+        let added: Vec<(SocketAddr, u8)> = connected
+            .iter()
+            .filter(|addr| Some(**addr) != remote && !self.advertised.contains(addr))
+            .take(MAX_PEX_PEERS)
+            .map(|addr| (*addr, FLAG_REACHABLE))
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .advertised
+            .iter()
+            .filter(|addr| !connected.contains(addr))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        drop(connected);
+
+        for (addr, _) in &added {
+            self.advertised.insert(*addr);
+        }
+        for addr in &dropped {
+            self.advertised.remove(addr);
+        }
+        let message = PexMessage { added, dropped };
+        (!message.is_empty()).then_some(message)
+    }
+
+    /// Whether to act on a message that arrived `now`; peers that send more
+    /// often than once a minute are ignored until they slow down.
+    pub fn accept_incoming(&mut self, now: Instant) -> bool {
+        if let Some(last) = self.last_received {
+            if now.duration_since(last) < MIN_INCOMING_INTERVAL {
+                return false;
+            }
+        }
+        self.last_received = Some(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_messages_round_trip_and_are_capped() {
+        let message = PexMessage {
+            added: vec![(addr("10.0.0.1:6881"), FLAG_REACHABLE), (addr("[::1]:51413"), 0x02)],
+            dropped: vec![addr("10.0.0.2:80"), addr("[2001:db8::7]:1")],
+        };
+        let Message::ReceiveMetaData { ext_msg_id: 9, dict, payload } = message.to_message(9) else { panic!() };
+        assert!(payload.is_empty());
+        assert_eq!(PexMessage::parse(&dict), message);
+
+        // A peer flooding us with 60 entries only gets 50 of them heard.
+        let mut flood = HashMap::new();
+        flood.insert("added".to_string(), BValue::ByteString(vec![1; 6 * 60 + 3]));
+        let parsed = PexMessage::parse(&BValue::Dict(flood));
+        assert_eq!(parsed.added.len(), MAX_PEX_PEERS);
+        assert_eq!(parsed.added[0], (addr("1.1.1.1:257"), 0));
+    }
+
+    #[test]
+    fn test_state_sends_only_news() {
+        let swarm = Swarm::new();
+        let remote = addr("10.0.0.9:1");
+        for i in 0..60 {
+            swarm.connected(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 1, 0, i)), 6881));
+        }
+        swarm.connected(remote);
+
+        let mut state = PexState::new();
+        let first = state.next_message(&swarm, Some(remote)).unwrap();
+        assert_eq!(first.added.len(), MAX_PEX_PEERS);
+        assert!(first.added.iter().all(|(peer, _)| *peer != remote));
+        let second = state.next_message(&swarm, Some(remote)).unwrap();
+        assert_eq!(second.added.len(), 10);
+        assert!(state.next_message(&swarm, Some(remote)).is_none());
+
+        let (gone, _) = first.added[0];
+        swarm.disconnected(gone);
+        assert_eq!(state.next_message(&swarm, Some(remote)).unwrap().dropped, vec![gone]);
+
+        let now = Instant::now();
+        assert!(state.accept_incoming(now));
+        assert!(!state.accept_incoming(now + Duration::from_secs(10)));
+        assert!(state.accept_incoming(now + PEX_INTERVAL));
+    }
+
+    #[tokio::test]
+    async fn test_swarm_queues_unknown_peers_once() {
+        let swarm = Swarm::new();
+        swarm.add_known(addr("10.0.0.1:1"));
+        let message = PexMessage {
+            added: vec![(addr("10.0.0.1:1"), 0), (addr("10.0.0.2:2"), 0), (addr("10.0.0.3:0"), 0)],
+            dropped: Vec::new(),
+        };
+        swarm.discover(&message);
+        swarm.discover(&message);
+        assert_eq!(swarm.next_discovered().await, vec![addr("10.0.0.2:2")]);
+    }
+}
@@ -1,71 +1,52 @@
 // piece_manager.rs
-use std::collections::HashMap;
 use sha1::{Sha1, Digest};
 use std::io::{Error, ErrorKind};
-use tokio::net::TcpStream;
 use std::sync::Arc;
 use log::debug;
 
 use crate::torrent::TorrentInfo;
-use crate::file_io::write_piece_to_file_at_offset;
-use crate::message::{send_message, Message};
-use crate::piece_queue::PieceQueue;
+use crate::file_io::{write_piece_to_file_at_offset, write_piece_to_files};
+use crate::pipeline::{BlockRequest, BLOCK_SIZE};
+use crate::piece_queue::{AddedBlock, PieceQueue};
 
 /// Handles block requests, assembling blocks into pieces, verifying pieces,
-/// and writing complete pieces to file.
+/// and writing complete pieces to file. Partially received pieces live in
+/// the shared `PieceQueue`, so blocks from different peers fill them alike.
 pub struct PieceManager {
     pub torrent_info: TorrentInfo,
-    received_blocks: HashMap<u32, Vec<u8>>,
 }
 
 impl PieceManager {
     pub fn new(torrent_info: TorrentInfo) -> Self {
-        Self {
-            torrent_info,
-            received_blocks: HashMap::new(),
-        }
+        Self { torrent_info }
     }
 
-    /// For a given piece index, send a series of block requests.
+    /// Splits a piece into the block requests that cover it.
     /// (Here we assume a 16 KiB block size.)
-    pub async fn request_blocks(&self, stream: &mut TcpStream, piece_index: u32) -> Result<(), Error> {
-
-		// Calculate the actual piece length, it if it's the last piece it may be smaller than 16 kb  
-        let piece_length = self.torrent_info.piece_length as u32;
-        let file_length = self.torrent_info.length as u32;
-
-        let total_length = if (piece_index + 1) * piece_length > file_length {
-            file_length - piece_index * piece_length
-        } else {
-            piece_length
-        };
+    pub fn blocks(&self, piece_index: u32) -> Vec<BlockRequest> {
+        let total_length = self.torrent_info.piece_size(piece_index);
 
-		
-        let block_size = 1 << 14; // 16 KiB
-        let mut offset = 0;
+        let mut blocks = Vec::new();
+        let mut offset = 0u64;
         while offset < total_length {
-            let block_length = std::cmp::min(block_size, total_length - offset);
-            send_message(
-                stream,
-                Message::Request {
-                    index: piece_index,
-                    begin: offset,
-                    length: block_length,
-                },
-            )
-            .await?;
-            offset += block_size;
+            // Offsets inside a piece always fit the u32 fields of the wire protocol.
+            blocks.push(BlockRequest {
+                index: piece_index,
+                begin: offset as u32,
+                length: std::cmp::min(BLOCK_SIZE as u64, total_length - offset) as u32,
+            });
+            offset += BLOCK_SIZE as u64;
         }
-        Ok(())
+        blocks
     }
 
     /// Handles an incoming piece message payload. If the full piece is received,
     /// verify its hash and write it to file.
     ///
     /// Returns `Ok(true)` if the piece is complete and written, or `Ok(false)` if not yet complete.
-	/// And re-queues the piece
+    /// A piece that fails verification is dropped; requeueing it is up to the caller.
     pub async fn handle_piece(
-        &mut self,
+        &self,
         payload: Vec<u8>,
         output_path: &str,
         piece_queue: &Arc<PieceQueue>,
@@ -81,54 +62,168 @@ impl PieceManager {
 
         let offset = u32::from_be_bytes(payload[4..8].try_into().map_err(|_| {
             Error::new(ErrorKind::InvalidData, "Failed to parse offset")
-        })?);
+        })?) as u64;
 		
         let block = &payload[8..];
-        
-        self.received_blocks
-            .entry(piece_index)
-            .or_default()
-            .extend_from_slice(block);
-
-        let piece_length = self.torrent_info.piece_length as u32;
-        let file_length = self.torrent_info.length as u32;
-        let total_piece_size = if (piece_index + 1) * piece_length > file_length {
-            file_length - piece_index * piece_length
-        } else {
-            piece_length
+        if piece_index as usize >= self.torrent_info.pieces.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "Block for a piece that does not exist"));
+        }
+
+        // Blocks may arrive in any order; each one must line up with a block we request.
+        let total_piece_size = self.torrent_info.piece_size(piece_index);
+        let expected_length = std::cmp::min(BLOCK_SIZE as u64, total_piece_size.saturating_sub(offset));
+        if !offset.is_multiple_of(BLOCK_SIZE as u64) || offset >= total_piece_size || block.len() as u64 != expected_length {
+            return Err(Error::new(ErrorKind::InvalidData, "Block does not match a requested block"));
+        }
+
+        let complete_piece = match piece_queue.add_block(piece_index, offset, block, total_piece_size) {
+            AddedBlock::Duplicate => return Ok(false),
+            AddedBlock::Partial { missing } => {
+                debug!(
+                    "Received block: piece={}, offset={}, block_length={}, missing_blocks={}",
+                    piece_index,
+                    offset,
+                    block.len(),
+                    missing
+                );
+                return Ok(false);
+            }
+            AddedBlock::Complete(data) => data,
         };
 
-        let current_size = self.received_blocks.get(&piece_index).unwrap().len() as u32;
-        debug!(
-            "Received block: piece={}, offset={}, block_length={}, current_size={}/{}",
-            piece_index,
-            offset,
-            block.len(),
-            current_size,
-            total_piece_size
-        );
-
-        if current_size >= total_piece_size {
-            let complete_piece = self.received_blocks.remove(&piece_index).unwrap();
-            let verified = self.verify_piece(piece_index, &complete_piece);
-            println!("Piece {} verified: {}", piece_index, verified);
-            if verified {
-                write_piece_to_file_at_offset(&complete_piece, piece_index, output_path, piece_length, full_file).await?;
-                piece_queue.mark_piece_complete(piece_index).await;
-                return Ok(true);
+        let verified = self.verify_piece(piece_index, &complete_piece);
+        println!("Piece {} verified: {}", piece_index, verified);
+        if verified {
+            if full_file {
+                write_piece_to_files(&complete_piece, piece_index, output_path, &self.torrent_info).await?;
             } else {
-                piece_queue.requeue_piece(piece_index).await;
-                return Err(Error::new(ErrorKind::Other, "Piece verification failed"));
+                write_piece_to_file_at_offset(&complete_piece, piece_index, output_path, self.torrent_info.piece_length as u64, full_file).await?;
             }
+            piece_queue.mark_piece_complete(piece_index).await;
+            Ok(true)
+        } else {
+            piece_queue.discard_blocks(piece_index);
+            Err(Error::other("Piece verification failed"))
         }
-        Ok(false)
     }
 
     /// Verifies the SHA-1 hash of the piece against the expected hash.
     fn verify_piece(&self, piece_index: u32, piece_data: &[u8]) -> bool {
-        let expected_hash = &self.torrent_info.pieces[piece_index as usize];
-        let mut hasher = Sha1::new();
-        hasher.update(piece_data);
-        hasher.finalize().as_slice() == expected_hash
+        verify_piece_data(&self.torrent_info, piece_index, piece_data)
+    }
+}
+
+/// Checks piece data against the SHA-1 hash the torrent lists for it.
+pub fn verify_piece_data(info: &TorrentInfo, piece_index: u32, piece_data: &[u8]) -> bool {
+    let Some(expected_hash) = info.pieces.get(piece_index as usize) else {
+        return false;
+    };
+    let mut hasher = Sha1::new();
+    hasher.update(piece_data);
+    hasher.finalize().as_slice() == expected_hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{HashMap, VecDeque};
+    use crate::bencode::BValue;
+
+    /// A single-piece torrent of three blocks, the last one short.
+    fn test_manager(content: &[u8]) -> PieceManager {
+        PieceManager::new(TorrentInfo {
+            length: content.len(),
+            name: "piece.bin".to_string(),
+            piece_length: content.len(),
+            pieces: vec![Sha1::digest(content).into()],
+            files: None,
+            extra: HashMap::new(),
+        })
+    }
+
+    fn block_payload(content: &[u8], begin: u32) -> Vec<u8> {
+        let end = std::cmp::min(begin + BLOCK_SIZE, content.len() as u32);
+        let mut payload = 0u32.to_be_bytes().to_vec();
+        payload.extend_from_slice(&begin.to_be_bytes());
+        payload.extend_from_slice(&content[begin as usize..end as usize]);
+        payload
+    }
+
+    #[tokio::test]
+    async fn test_shuffled_and_duplicate_blocks() {
+        let content: Vec<u8> = (0..40_000u32).map(|i| (i * 7 % 256) as u8).collect();
+        let manager = test_manager(&content);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("piece.bin");
+        let output = output.to_str().unwrap();
+        let queue = Arc::new(PieceQueue::new(VecDeque::new()));
+
+        // Last block first, the first block twice, the middle block last.
+        for begin in [2 * BLOCK_SIZE, 0, 0, 2 * BLOCK_SIZE] {
+            let complete = manager.handle_piece(block_payload(&content, begin), output, &queue, true).await.unwrap();
+            assert!(!complete);
+        }
+        let complete = manager.handle_piece(block_payload(&content, BLOCK_SIZE), output, &queue, true).await.unwrap();
+        assert!(complete);
+        assert_eq!(std::fs::read(output).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn test_misaligned_block_rejected() {
+        let content = vec![1u8; 40_000];
+        let manager = test_manager(&content);
+        let queue = Arc::new(PieceQueue::new(VecDeque::new()));
+
+        let mut payload = block_payload(&content, 0);
+        payload[4..8].copy_from_slice(&100u32.to_be_bytes());
+        let err = manager.handle_piece(payload, "unused.bin", &queue, true).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        // A block of the wrong size does not fit either.
+        let mut payload = block_payload(&content, BLOCK_SIZE);
+        payload.truncate(100);
+        assert!(manager.handle_piece(payload, "unused.bin", &queue, true).await.is_err());
+    }
+
+    /// A synthetic info dict declaring 4 GiB of 1 MiB pieces plus a short last piece.
+    fn large_info(last_piece: &[u8]) -> TorrentInfo {
+        let piece_length = 1u64 << 20;
+        let length = (4u64 << 30) + last_piece.len() as u64;
+        let count = length.div_ceil(piece_length) as usize;
+        let mut pieces = vec![0u8; count * 20];
+        pieces[(count - 1) * 20..].copy_from_slice(&Sha1::digest(last_piece));
+
+        let mut dict = HashMap::new();
+        dict.insert("name".to_string(), BValue::ByteString(b"big.bin".to_vec()));
+        dict.insert("length".to_string(), BValue::Integer(length as i64));
+        dict.insert("piece length".to_string(), BValue::Integer(piece_length as i64));
+        dict.insert("pieces".to_string(), BValue::ByteString(pieces));
+        TorrentInfo::from_bvalue(&dict).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_torrent_larger_than_4_gib() {
+        let last_piece = vec![9u8; 1000];
+        let info = large_info(&last_piece);
+        assert_eq!(info.pieces.len(), 4097);
+        let manager = PieceManager::new(info);
+
+        // A full piece past the 4 GiB mark, and the short last piece.
+        assert_eq!(manager.blocks(4095).len(), 64);
+        assert_eq!(manager.blocks(4096), vec![BlockRequest { index: 4096, begin: 0, length: 1000 }]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("big.bin");
+        let output = output.to_str().unwrap();
+        let queue = Arc::new(PieceQueue::new(VecDeque::new()));
+
+        let mut payload = 4096u32.to_be_bytes().to_vec();
+        payload.extend_from_slice(&0u32.to_be_bytes());
+        payload.extend_from_slice(&last_piece);
+        assert!(manager.handle_piece(payload, output, &queue, true).await.unwrap());
+
+        // The piece lands at its 64-bit offset (the file is sparse up to there).
+        let written = std::fs::metadata(output).unwrap().len();
+        assert_eq!(written, (4u64 << 30) + 1000);
     }
 }
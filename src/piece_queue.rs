@@ -1,12 +1,62 @@
 // piece_queue.rs
-use std::collections::{HashSet, VecDeque};
-use tokio::sync::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use log::debug;
+use rand::seq::SliceRandom;
+use tokio::sync::{broadcast, Mutex};
 
-/// Holds the available pieces as well as the pieces already in progress.
+use crate::bitfield::Bitfield;
+use crate::pipeline::{BlockRequest, BLOCK_SIZE};
+
+/// Until this many pieces are complete, pieces are picked at random rather
+/// than rarest first, so we quickly have something to trade.
+const RANDOM_FIRST_PIECES: usize = 4;
+
+/// A piece being assembled from blocks that may arrive in any order, from
+/// any of the peers downloading it.
+#[derive(Debug)]
+struct PieceBuffer {
+    data: Vec<u8>,
+    received: Vec<bool>, // One flag per block
+    missing: usize,
+}
+
+impl PieceBuffer {
+    fn new(piece_size: u64) -> Self {
+        let blocks = piece_size.div_ceil(BLOCK_SIZE as u64) as usize;
+        Self {
+            data: vec![0u8; piece_size as usize],
+            received: vec![false; blocks],
+            missing: blocks,
+        }
+    }
+}
+
+/// What became of a block handed to `PieceQueue::add_block`.
+#[derive(Debug, PartialEq)]
+pub enum AddedBlock {
+    /// Another peer already delivered it.
+    Duplicate,
+    Partial { missing: usize },
+    /// The last block of the piece; the assembled piece, not yet verified.
+    Complete(Vec<u8>),
+}
+
+/// Holds the available pieces as well as the pieces already in progress, and
+/// how many connected peers have each piece.
+///
+/// Once every piece has been handed out the queue enters endgame: sessions
+/// may then pick pieces that are already in progress, and every block that
+/// arrives is announced so the other sessions can cancel their requests.
 #[derive(Debug)]
 pub struct PieceQueue {
     available: Mutex<VecDeque<u32>>,
-    in_progress: Mutex<HashSet<u32>>,
+    in_progress: Mutex<HashMap<u32, usize>>, // Number of sessions downloading each piece
+    partial: std::sync::Mutex<HashMap<u32, PieceBuffer>>,
+    availability: std::sync::Mutex<Vec<u32>>,
+    completed: AtomicUsize,
+    endgame: AtomicBool,
+    received: broadcast::Sender<BlockRequest>,
 }
 
 impl PieceQueue {
@@ -14,42 +64,223 @@ impl PieceQueue {
     pub fn new(available: VecDeque<u32>) -> Self {
         Self {
             available: Mutex::new(available),
-            in_progress: Mutex::new(HashSet::new()),
+            in_progress: Mutex::new(HashMap::new()),
+            partial: std::sync::Mutex::new(HashMap::new()),
+            availability: std::sync::Mutex::new(Vec::new()),
+            completed: AtomicUsize::new(0),
+            endgame: AtomicBool::new(false),
+            received: broadcast::channel(1024).0,
+        }
+    }
+
+    /// Counts the pieces of a peer's bitfield towards their availability.
+    pub fn add_peer_pieces(&self, has: &Bitfield) {
+        let mut availability = self.availability.lock().unwrap();
+        if availability.len() < has.len() {
+            availability.resize(has.len(), 0);
+        }
+        for piece in has.pieces() {
+            availability[piece as usize] += 1;
+        }
+    }
+
+    /// Counts a piece a peer announced with Have.
+    pub fn add_peer_piece(&self, piece: u32) {
+        let mut availability = self.availability.lock().unwrap();
+        if availability.len() <= piece as usize {
+            availability.resize(piece as usize + 1, 0);
+        }
+        availability[piece as usize] += 1;
+    }
+
+    /// Forgets the pieces of a peer that disconnected.
+    pub fn remove_peer_pieces(&self, has: &Bitfield) {
+        let mut availability = self.availability.lock().unwrap();
+        for piece in has.pieces() {
+            if let Some(count) = availability.get_mut(piece as usize) {
+                *count = count.saturating_sub(1);
+            }
         }
     }
 
-    /// Returns the next piece that is not already in progress.
+    /// Picks the next piece to download from a peer that has `peer_has`,
+    /// and marks it as in progress.
     ///
-    /// This method locks both internal collections, pops pieces from the available
-    /// queue until it finds one that isn’t marked as in progress, marks it as in progress,
-    /// and returns it.
-    pub async fn get_next_piece(&self) -> Option<u32> {
+    /// Only pieces the peer has and nobody is downloading are considered.
+    /// The first few pieces are picked at random; after that the rarest
+    /// piece wins, ties broken at random.
+    pub async fn pick_piece(&self, peer_has: &Bitfield) -> Option<u32> {
         // Lock both collections. (Make sure that the locking order is consistent
         // elsewhere in your code to avoid deadlocks.)
         let mut available = self.available.lock().await;
         let mut in_progress = self.in_progress.lock().await;
 
-        while let Some(piece) = available.pop_front() {
-            if !in_progress.contains(&piece) {
-                in_progress.insert(piece);
-                return Some(piece);
+        let mut candidates: Vec<usize> = available
+            .iter()
+            .enumerate()
+            .filter(|(_, piece)| peer_has.has(**piece) && !in_progress.contains_key(*piece))
+            .map(|(pos, _)| pos)
+            .collect();
+        if self.completed.load(Ordering::Relaxed) >= RANDOM_FIRST_PIECES {
+            let availability = self.availability.lock().unwrap();
+            let count = |pos: &usize| availability.get(available[*pos] as usize).copied().unwrap_or(0);
+            if let Some(rarest) = candidates.iter().map(count).min() {
+                candidates.retain(|pos| count(pos) == rarest);
             }
         }
-        None
+
+        let pos = *candidates.choose(&mut rand::thread_rng())?;
+        let piece = available.remove(pos)?;
+        in_progress.insert(piece, 1);
+        Some(piece)
+    }
+
+    /// In endgame, picks a piece another session is already downloading
+    /// that the peer has and `exclude` (the session's own pieces) does not
+    /// list. Returns `None` while there are pieces nobody has started.
+    pub async fn pick_endgame_piece(&self, peer_has: &Bitfield, exclude: &[u32]) -> Option<u32> {
+        let available = self.available.lock().await;
+        let mut in_progress = self.in_progress.lock().await;
+        if !available.is_empty() {
+            return None;
+        }
+
+        // Pieces with every block in are being verified and written already.
+        let partial = self.partial.lock().unwrap();
+        let candidates: Vec<u32> = in_progress
+            .keys()
+            .filter(|piece| peer_has.has(**piece) && !exclude.contains(piece))
+            .filter(|piece| partial.get(piece).is_none_or(|buffer| buffer.missing > 0))
+            .copied()
+            .collect();
+        drop(partial);
+        let piece = *candidates.choose(&mut rand::thread_rng())?;
+        *in_progress.entry(piece).or_default() += 1;
+        if !self.endgame.swap(true, Ordering::Relaxed) {
+            debug!("Entering endgame with {} pieces in progress", in_progress.len());
+        }
+        Some(piece)
+    }
+
+    /// Receives the blocks that arrive while in endgame.
+    pub fn subscribe(&self) -> broadcast::Receiver<BlockRequest> {
+        self.received.subscribe()
+    }
+
+    /// Of the `blocks` of `piece`, the ones no peer has delivered yet.
+    pub fn missing_blocks(&self, piece: u32, blocks: Vec<BlockRequest>) -> Vec<BlockRequest> {
+        let partial = self.partial.lock().unwrap();
+        match partial.get(&piece) {
+            Some(buffer) => blocks
+                .into_iter()
+                .filter(|block| !buffer.received[(block.begin / BLOCK_SIZE) as usize])
+                .collect(),
+            None => blocks,
+        }
+    }
+
+    /// Stores a block at `offset` of a piece of `piece_size` bytes. The
+    /// block must be aligned to `BLOCK_SIZE` and have the matching length.
+    ///
+    /// A complete piece keeps its (emptied) buffer until it is marked
+    /// complete or discarded, so late copies of its blocks count as duplicates.
+    pub fn add_block(&self, piece: u32, offset: u64, block: &[u8], piece_size: u64) -> AddedBlock {
+        let mut partial = self.partial.lock().unwrap();
+        let buffer = partial.entry(piece).or_insert_with(|| PieceBuffer::new(piece_size));
+        let block_number = (offset / BLOCK_SIZE as u64) as usize;
+        if buffer.received[block_number] {
+            debug!("Dropping duplicate block: piece={}, offset={}", piece, offset);
+            return AddedBlock::Duplicate;
+        }
+        buffer.data[offset as usize..offset as usize + block.len()].copy_from_slice(block);
+        buffer.received[block_number] = true;
+        buffer.missing -= 1;
+
+        if self.endgame.load(Ordering::Relaxed) {
+            // Nobody is listening outside endgame, so a failed send is fine.
+            let _ = self.received.send(BlockRequest {
+                index: piece,
+                begin: offset as u32,
+                length: block.len() as u32,
+            });
+        }
+        if buffer.missing == 0 {
+            AddedBlock::Complete(std::mem::take(&mut buffer.data))
+        } else {
+            AddedBlock::Partial { missing: buffer.missing }
+        }
+    }
+
+    /// Whether every piece has been handed out.
+    pub async fn is_empty(&self) -> bool {
+        self.available.lock().await.is_empty()
     }
 
     pub async fn mark_piece_complete(&self, piece: u32) {
         let mut in_progress = self.in_progress.lock().await;
         in_progress.remove(&piece);
+        self.partial.lock().unwrap().remove(&piece);
+        self.completed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Throws away the blocks of a piece that failed verification.
+    pub fn discard_blocks(&self, piece: u32) {
+        self.partial.lock().unwrap().remove(&piece);
     }
 
-    /// If a piece fails or needs to be retried, we requeue it
+    /// If a piece fails or needs to be retried, we requeue it. In endgame the
+    /// piece only goes back once no other session is downloading it, and a
+    /// piece completed in the meantime is not requeued at all.
     pub async fn requeue_piece(&self, piece: u32) {
-        {
-            let mut in_progress = self.in_progress.lock().await;
+        let mut available = self.available.lock().await;
+        let mut in_progress = self.in_progress.lock().await;
+        let Some(sessions) = in_progress.get_mut(&piece) else { return };
+        *sessions -= 1;
+        if *sessions == 0 {
             in_progress.remove(&piece);
+            available.push_back(piece);
         }
-        let mut available = self.available.lock().await;
-        available.push_back(piece);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bitfield(len: usize, pieces: &[u32]) -> Bitfield {
+        let mut has = Bitfield::new(len);
+        for piece in pieces {
+            has.set(*piece);
+        }
+        has
+    }
+
+    #[tokio::test]
+    async fn test_picks_only_pieces_the_peer_has_rarest_first() {
+        let queue = PieceQueue::new((0..8).collect());
+        let everything = bitfield(8, &[0, 1, 2, 3, 4, 5, 6, 7]);
+        queue.add_peer_pieces(&everything);
+        queue.add_peer_pieces(&bitfield(8, &[0, 1, 2, 3, 4, 5]));
+        queue.add_peer_pieces(&bitfield(8, &[0, 1, 2, 3, 4, 5]));
+        queue.add_peer_piece(6);
+
+        // Random start: any piece the peer has, never one it lacks.
+        let only_two = bitfield(8, &[2]);
+        assert_eq!(queue.pick_piece(&only_two).await, Some(2));
+        assert_eq!(queue.pick_piece(&only_two).await, None);
+        queue.mark_piece_complete(2).await;
+        for _ in 0..3 {
+            let piece = queue.pick_piece(&bitfield(8, &[0, 1, 3, 4])).await.unwrap();
+            queue.mark_piece_complete(piece).await;
+        }
+
+        // Piece 7 is held by one peer only, 6 by two, the rest by three.
+        assert_eq!(queue.pick_piece(&everything).await, Some(7));
+        assert_eq!(queue.pick_piece(&everything).await, Some(6));
+
+        queue.remove_peer_pieces(&everything);
+        queue.requeue_piece(7).await;
+        // Nobody is left with piece 7, so it is rarer than anything.
+        assert_eq!(queue.pick_piece(&everything).await, Some(7));
     }
 }
@@ -0,0 +1,245 @@
+// pipeline.rs
+//! Per-peer request pipeline: keeps a window of block requests outstanding,
+//! across piece boundaries, so the connection never idles between blocks.
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Block size used for all requests (16 KiB).
+pub const BLOCK_SIZE: u32 = 1 << 14;
+
+/// Never keep fewer requests outstanding than this.
+const MIN_DEPTH: usize = 2;
+
+/// Aim to keep this much of the measured throughput in flight.
+const REQUEST_QUEUE_TIME: Duration = Duration::from_secs(3);
+
+/// How often the depth is re-evaluated from the measured throughput.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// A single block request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+pub struct RequestPipeline {
+    depth: usize,
+    max_depth: usize,
+    peer_limit: Option<usize>, // The peer's `reqq`, if it sent one
+    pieces: Vec<u32>,
+    pending: VecDeque<BlockRequest>,
+    outstanding: Vec<BlockRequest>,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl RequestPipeline {
+    /// Creates a pipeline starting at `depth` outstanding requests, which
+    /// may grow up to `max_depth` on fast peers.
+    pub fn new(depth: usize, max_depth: usize) -> Self {
+        let max_depth = max_depth.max(MIN_DEPTH);
+        Self {
+            depth: depth.clamp(MIN_DEPTH, max_depth),
+            max_depth,
+            peer_limit: None,
+            pieces: Vec::new(),
+            pending: VecDeque::new(),
+            outstanding: Vec::new(),
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    /// Current number of requests we try to keep outstanding.
+    pub fn depth(&self) -> usize {
+        match self.peer_limit {
+            Some(limit) => self.depth.min(limit.max(1)),
+            None => self.depth,
+        }
+    }
+
+    /// Caps the depth at the `reqq` the peer advertised in its extended handshake.
+    pub fn set_peer_limit(&mut self, reqq: usize) {
+        self.peer_limit = Some(reqq);
+    }
+
+    /// Whether the queued blocks no longer cover the window, i.e. the next
+    /// piece should be added.
+    pub fn wants_piece(&self) -> bool {
+        self.pending.len() + self.outstanding.len() < self.depth()
+    }
+
+    /// Queues all blocks of a piece for requesting.
+    pub fn add_piece(&mut self, index: u32, blocks: Vec<BlockRequest>) {
+        self.pieces.push(index);
+        self.pending.extend(blocks);
+    }
+
+    /// Pieces with blocks queued, in flight or partially received.
+    pub fn pieces(&self) -> &[u32] {
+        &self.pieces
+    }
+
+    /// Forgets a piece once it is complete.
+    pub fn finish_piece(&mut self, index: u32) {
+        self.pieces.retain(|piece| *piece != index);
+        self.pending.retain(|block| block.index != index);
+        self.outstanding.retain(|block| block.index != index);
+    }
+
+    /// Moves queued blocks into flight until the window is full and returns
+    /// the requests to send.
+    pub fn next_requests(&mut self) -> Vec<BlockRequest> {
+        let mut requests = Vec::new();
+        while self.outstanding.len() < self.depth() {
+            let Some(block) = self.pending.pop_front() else { break };
+            self.outstanding.push(block);
+            requests.push(block);
+        }
+        requests
+    }
+
+    /// Records an arriving block. Returns `false` for blocks we did not
+    /// request (or no longer wait for), which should be ignored.
+    pub fn on_block(&mut self, index: u32, begin: u32, length: u32) -> bool {
+        let Some(pos) = self
+            .outstanding
+            .iter()
+            .position(|block| block.index == index && block.begin == begin && block.length == length)
+        else {
+            return false;
+        };
+        self.outstanding.remove(pos);
+
+        self.window_bytes += length as u64;
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= RATE_WINDOW {
+            self.adjust(self.window_bytes as f64 / elapsed.as_secs_f64());
+            self.window_start = Instant::now();
+            self.window_bytes = 0;
+        }
+        true
+    }
+
+    /// A choke discards our outstanding requests; queue them again, ahead of
+    /// the blocks not requested yet.
+    pub fn on_choke(&mut self) {
+        self.outstanding.sort_by_key(|block| (block.index, block.begin));
+        for block in self.outstanding.drain(..).rev() {
+            self.pending.push_front(block);
+        }
+    }
+
+    /// Drops a block another peer delivered first (endgame). Returns `true`
+    /// if our request for it is in flight and should be cancelled.
+    pub fn cancel_block(&mut self, block: BlockRequest) -> bool {
+        self.pending.retain(|pending| *pending != block);
+        match self.outstanding.iter().position(|outstanding| *outstanding == block) {
+            Some(pos) => {
+                self.outstanding.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forgets the pieces with nothing left to request or wait for, which
+    /// other peers are completing, and returns them.
+    pub fn drop_empty_pieces(&mut self) -> Vec<u32> {
+        let (empty, kept): (Vec<u32>, Vec<u32>) = self.pieces.iter().partition(|piece| {
+            !self.pending.iter().chain(&self.outstanding).any(|block| block.index == **piece)
+        });
+        self.pieces = kept;
+        empty
+    }
+
+    /// Whether there is nothing left to request or wait for.
+    pub fn is_idle(&self) -> bool {
+        self.pending.is_empty() && self.outstanding.is_empty()
+    }
+
+    /// Sizes the window to cover `REQUEST_QUEUE_TIME` at `bytes_per_sec`.
+    fn adjust(&mut self, bytes_per_sec: f64) {
+        let wanted = (bytes_per_sec * REQUEST_QUEUE_TIME.as_secs_f64() / BLOCK_SIZE as f64).ceil() as usize;
+        self.depth = wanted.clamp(MIN_DEPTH, self.max_depth);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocks(index: u32, count: u32) -> Vec<BlockRequest> {
+        (0..count)
+            .map(|i| BlockRequest { index, begin: i * BLOCK_SIZE, length: BLOCK_SIZE })
+            .collect()
+    }
+
+    #[test]
+    fn test_window_spans_piece_boundaries() {
+        let mut pipeline = RequestPipeline::new(5, 64);
+        pipeline.add_piece(0, blocks(0, 2));
+        assert!(pipeline.wants_piece());
+        pipeline.add_piece(1, blocks(1, 2));
+        pipeline.add_piece(2, blocks(2, 2));
+        assert!(!pipeline.wants_piece());
+
+        let requests = pipeline.next_requests();
+        assert_eq!(requests.len(), 5);
+        assert_eq!(requests[4], BlockRequest { index: 2, begin: 0, length: BLOCK_SIZE });
+
+        // One block back frees one slot in the window.
+        assert!(pipeline.on_block(0, 0, BLOCK_SIZE));
+        assert!(!pipeline.on_block(0, 0, BLOCK_SIZE));
+        assert_eq!(pipeline.next_requests(), vec![BlockRequest { index: 2, begin: BLOCK_SIZE, length: BLOCK_SIZE }]);
+
+        pipeline.finish_piece(0);
+        assert_eq!(pipeline.pieces(), &[1, 2]);
+    }
+
+    #[test]
+    fn test_depth_limited_by_reqq_and_throughput() {
+        let mut pipeline = RequestPipeline::new(32, 128);
+        assert_eq!(pipeline.depth(), 32);
+        pipeline.set_peer_limit(10);
+        assert_eq!(pipeline.depth(), 10);
+
+        let mut pipeline = RequestPipeline::new(32, 128);
+        // 1 MiB/s over 3 s is 192 blocks, capped at the configured maximum.
+        pipeline.adjust(1024.0 * 1024.0);
+        assert_eq!(pipeline.depth(), 128);
+        // A trickle still keeps a couple of requests in flight.
+        pipeline.adjust(100.0);
+        assert_eq!(pipeline.depth(), MIN_DEPTH);
+    }
+
+    #[test]
+    fn test_choke_requeues_outstanding_blocks() {
+        let mut pipeline = RequestPipeline::new(3, 64);
+        pipeline.add_piece(0, blocks(0, 4));
+        pipeline.next_requests();
+        pipeline.on_choke();
+        assert!(!pipeline.on_block(0, 0, BLOCK_SIZE));
+
+        let requests = pipeline.next_requests();
+        assert_eq!(requests.iter().map(|block| block.begin / BLOCK_SIZE).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert!(!pipeline.is_idle());
+    }
+
+    #[test]
+    fn test_blocks_delivered_elsewhere_are_cancelled() {
+        let mut pipeline = RequestPipeline::new(2, 64);
+        pipeline.add_piece(0, blocks(0, 1));
+        pipeline.add_piece(1, blocks(1, 2));
+        pipeline.next_requests();
+
+        // In flight: needs a Cancel. Still queued: just dropped.
+        assert!(pipeline.cancel_block(BlockRequest { index: 0, begin: 0, length: BLOCK_SIZE }));
+        assert!(!pipeline.cancel_block(BlockRequest { index: 1, begin: BLOCK_SIZE, length: BLOCK_SIZE }));
+        assert_eq!(pipeline.drop_empty_pieces(), vec![0]);
+        assert_eq!(pipeline.pieces(), &[1]);
+        assert_eq!(pipeline.next_requests(), vec![]);
+    }
+}
@@ -1,4 +1,4 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
 use indicatif::{ProgressBar, ProgressStyle};
@@ -8,6 +8,8 @@ use log::info;
 pub struct ProgressTracker {
     total_pieces: usize,
     downloaded_pieces: Arc<AtomicUsize>,
+    resumed_pieces: Arc<AtomicUsize>, // Already on disk when the download started
+    endgame: Arc<AtomicBool>,
     start_time: Instant,
     progress_bar: Option<ProgressBar>,
 }
@@ -17,6 +19,8 @@ impl ProgressTracker {
         Self {
             total_pieces,
             downloaded_pieces: Arc::new(AtomicUsize::new(0)),
+            resumed_pieces: Arc::new(AtomicUsize::new(0)),
+            endgame: Arc::new(AtomicBool::new(false)),
             start_time: Instant::now(),
             progress_bar: None,
         }
@@ -40,6 +44,8 @@ impl ProgressTracker {
         Self {
             total_pieces,
             downloaded_pieces: Arc::new(AtomicUsize::new(0)),
+            resumed_pieces: Arc::new(AtomicUsize::new(0)),
+            endgame: Arc::new(AtomicBool::new(false)),
             start_time: Instant::now(),
             progress_bar,
         }
@@ -49,11 +55,13 @@ impl ProgressTracker {
         let downloaded = self.downloaded_pieces.fetch_add(1, Ordering::SeqCst) + 1;
         let percentage = (downloaded as f64 / self.total_pieces as f64) * 100.0;
         let elapsed = self.start_time.elapsed();
-        let rate = downloaded as f64 / elapsed.as_secs_f64();
+        let fetched = downloaded - self.resumed_pieces.load(Ordering::SeqCst);
+        let rate = fetched as f64 / elapsed.as_secs_f64();
 
         if let Some(pb) = &self.progress_bar {
             pb.set_position(downloaded as u64);
-            pb.set_message(format!("{:.1} pieces/sec", rate));
+            let phase = if self.is_endgame() { " (endgame)" } else { "" };
+            pb.set_message(format!("{:.1} pieces/sec{}", rate, phase));
         } else {
             info!(
                 "Progress: {}/{} pieces ({:.1}%) - {:.1} pieces/sec",
@@ -62,6 +70,32 @@ impl ProgressTracker {
         }
     }
 
+    /// Counts pieces found on disk from an earlier run as downloaded.
+    pub fn resume_from(&self, pieces: usize) {
+        self.resumed_pieces.store(pieces, Ordering::SeqCst);
+        self.downloaded_pieces.store(pieces, Ordering::SeqCst);
+        if let Some(pb) = &self.progress_bar {
+            pb.set_position(pieces as u64);
+        }
+    }
+
+    /// Reports, once, that the last pieces are now requested from every peer.
+    pub fn start_endgame(&self) {
+        if self.endgame.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let (downloaded, total) = self.get_progress();
+        if let Some(pb) = &self.progress_bar {
+            pb.set_message("Endgame: requesting the last blocks from every peer");
+        } else {
+            info!("Endgame started with {}/{} pieces downloaded", downloaded, total);
+        }
+    }
+
+    pub fn is_endgame(&self) -> bool {
+        self.endgame.load(Ordering::SeqCst)
+    }
+
     pub fn finish(&self) {
         if let Some(pb) = &self.progress_bar {
             pb.finish_with_message("Download complete!");
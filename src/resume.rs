@@ -0,0 +1,216 @@
+// resume.rs
+//! Fast resume: the pieces a download had verified when it last stopped,
+//! saved with the size and modification time of its files and the state of
+//! its trackers. The next run trusts the saved pieces only while the files
+//! are unchanged.
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::bencode::{decode_bencode, encode_bvalue, BValue};
+use crate::bitfield::Bitfield;
+use crate::file_io;
+use crate::torrent::metadata::lookup_bytestring;
+use crate::torrent::{get_integer, TorrentInfo};
+
+/// Size and modification time (seconds since the epoch) of one file of the
+/// torrent; both zero if the file does not exist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileState {
+    pub size: u64,
+    pub mtime: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResumeData {
+    pub info_hash: [u8; 20],
+    pub output: String,          // The output path the download was started with
+    pub pieces: Vec<u8>,         // Verified pieces, in the wire layout of a Bitfield
+    pub files: Vec<FileState>,   // In torrent order
+    pub trackers: Vec<Vec<String>>, // Tiers, the trackers that answered in front
+}
+
+impl ResumeData {
+    /// Where the resume data of a torrent lives inside `directory`.
+    pub fn path(directory: &Path, info_hash: &[u8; 20]) -> PathBuf {
+        directory.join(format!("{}.resume", hex::encode(info_hash)))
+    }
+
+    /// Records the current state of a download.
+    pub fn capture(
+        info_hash: [u8; 20],
+        info: &TorrentInfo,
+        output: &str,
+        have: &Bitfield,
+        trackers: &[Vec<String>],
+    ) -> Self {
+        Self {
+            info_hash,
+            output: output.to_string(),
+            pieces: have.as_bytes().to_vec(),
+            files: file_states(info, Path::new(output)),
+            trackers: trackers.to_vec(),
+        }
+    }
+
+    /// The saved pieces, if they still hold for `info` at `output`: the
+    /// download must go to the same place and no file may have changed.
+    pub fn verified_pieces(&self, info: &TorrentInfo, output: &str) -> Option<Bitfield> {
+        if self.output != output || self.files != file_states(info, Path::new(output)) {
+            return None;
+        }
+        Bitfield::from_bytes(&self.pieces, info.pieces.len())
+    }
+
+    /// Reads saved resume data; `None` if there is none.
+    pub fn load(path: &Path) -> Result<Option<Self>, Box<dyn Error + Send + Sync>> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let (_, value) = decode_bencode(&bytes)?;
+        let BValue::Dict(dict) = value else {
+            return Err("Resume data must be a dictionary".into());
+        };
+
+        let info_hash = lookup_bytestring(&dict, "info-hash")?
+            .try_into()
+            .map_err(|_| "'info-hash' must be 20 bytes")?;
+        let output = String::from_utf8(lookup_bytestring(&dict, "output")?.to_vec())?;
+        let pieces = lookup_bytestring(&dict, "pieces")?.to_vec();
+
+        let mut files = Vec::new();
+        for file in list(&dict, "files")? {
+            let BValue::Dict(file) = file else {
+                return Err("'files' entries must be dictionaries".into());
+            };
+            files.push(FileState {
+                size: get_integer(file, "size")? as u64,
+                mtime: get_integer(file, "mtime")? as u64,
+            });
+        }
+
+        let mut trackers = Vec::new();
+        for tier in list(&dict, "trackers")? {
+            let BValue::List(urls) = tier else {
+                return Err("'trackers' entries must be lists".into());
+            };
+            let mut tier = Vec::new();
+            for url in urls {
+                if let BValue::ByteString(url) = url {
+                    tier.push(String::from_utf8(url.clone())?);
+                }
+            }
+            trackers.push(tier);
+        }
+
+        Ok(Some(Self { info_hash, output, pieces, files, trackers }))
+    }
+
+    /// Writes the resume data, replacing the previous file only once the new
+    /// one is complete.
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let mut dict = HashMap::new();
+        dict.insert("info-hash".to_string(), BValue::ByteString(self.info_hash.to_vec()));
+        dict.insert("output".to_string(), BValue::ByteString(self.output.clone().into_bytes()));
+        dict.insert("pieces".to_string(), BValue::ByteString(self.pieces.clone()));
+        let files = self
+            .files
+            .iter()
+            .map(|file| {
+                let mut entry = HashMap::new();
+                entry.insert("size".to_string(), BValue::Integer(file.size as i64));
+                entry.insert("mtime".to_string(), BValue::Integer(file.mtime as i64));
+                BValue::Dict(entry)
+            })
+            .collect();
+        dict.insert("files".to_string(), BValue::List(files));
+        let trackers = self
+            .trackers
+            .iter()
+            .map(|tier| BValue::List(tier.iter().map(|url| BValue::ByteString(url.clone().into_bytes())).collect()))
+            .collect();
+        dict.insert("trackers".to_string(), BValue::List(trackers));
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let partial = path.with_extension("resume.part");
+        std::fs::write(&partial, encode_bvalue(&BValue::Dict(dict)))?;
+        std::fs::rename(&partial, path)
+    }
+}
+
+fn list<'a>(dict: &'a HashMap<String, BValue>, key: &str) -> Result<&'a [BValue], Box<dyn Error + Send + Sync>> {
+    match dict.get(key) {
+        Some(BValue::List(items)) => Ok(items),
+        _ => Err(format!("'{}' must be a List", key).into()),
+    }
+}
+
+/// Size and modification time of each file of the torrent as it is on disk.
+pub fn file_states(info: &TorrentInfo, output_path: &Path) -> Vec<FileState> {
+    file_io::file_paths(info, output_path)
+        .iter()
+        .map(|path| match std::fs::metadata(path) {
+            Ok(metadata) => FileState {
+                size: metadata.len(),
+                mtime: metadata
+                    .modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |since| since.as_secs()),
+            },
+            Err(_) => FileState { size: 0, mtime: 0 },
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    fn test_info() -> TorrentInfo {
+        TorrentInfo {
+            length: 10,
+            name: "data.bin".to_string(),
+            piece_length: 4,
+            pieces: vec![[0u8; 20]; 3],
+            files: None,
+            extra: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_round_trip_and_change_detection() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("data.bin");
+        std::fs::write(&output, b"0123456789").unwrap();
+        let output = output.to_str().unwrap();
+
+        let info = test_info();
+        let mut have = Bitfield::new(3);
+        have.set(0);
+        have.set(2);
+        let tiers = vec![vec!["udp://b".to_string(), "http://a".to_string()]];
+        let resume = ResumeData::capture([5u8; 20], &info, output, &have, &tiers);
+
+        let path = ResumeData::path(&dir.path().join("state"), &[5u8; 20]);
+        resume.save(&path).unwrap();
+        let loaded = ResumeData::load(&path).unwrap().unwrap();
+        assert_eq!(loaded, resume);
+        assert_eq!(loaded.verified_pieces(&info, output), Some(have));
+
+        // Touching the file, or downloading elsewhere, invalidates the pieces.
+        let file = std::fs::File::options().write(true).open(output).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60)).unwrap();
+        assert_eq!(loaded.verified_pieces(&info, output), None);
+        assert_eq!(resume.verified_pieces(&info, "elsewhere.bin"), None);
+
+        assert_eq!(ResumeData::load(&dir.path().join("missing.resume")).unwrap(), None);
+    }
+}
@@ -0,0 +1,306 @@
+// create.rs
+//! Builds .torrent files from a file or directory on disk.
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::bencode::{encode_bvalue, BValue};
+use crate::torrent::infohash::info_to_bvalue;
+use crate::torrent::{TorrentFile, TorrentInfo};
+
+/// Smallest and largest piece length picked automatically.
+const MIN_PIECE_LENGTH: usize = 16 * 1024;
+const MAX_PIECE_LENGTH: usize = 16 * 1024 * 1024;
+
+/// Number of pieces automatic piece lengths aim for (at most).
+const TARGET_PIECES: usize = 1500;
+
+/// Everything about a new torrent besides its content.
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    pub piece_length: Option<usize>, // Picked from the content size if unset
+    pub trackers: Vec<Vec<String>>,  // Tiers of tracker URLs (BEP 12)
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    pub creation_date: Option<i64>, // Seconds since the UNIX epoch
+    pub private: bool,              // BEP 27
+    pub source: Option<String>,
+    pub web_seeds: Vec<String>, // BEP 19 `url-list`
+}
+
+/// A power of two between 16 KiB and 16 MiB giving at most about
+/// `TARGET_PIECES` pieces for `total_length` bytes.
+pub fn auto_piece_length(total_length: u64) -> usize {
+    let wanted = total_length.div_ceil(TARGET_PIECES as u64) as usize;
+    wanted.next_power_of_two().clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+/// Creates a bencoded .torrent for the file or directory at `path`.
+///
+/// Directories are walked recursively, their files sorted by path, so the
+/// same content always gives the same info hash. Pieces are hashed on one
+/// thread per available core.
+pub fn create_torrent(path: &Path, options: &CreateOptions) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| format!("Cannot name a torrent after {}", path.display()))?
+        .to_string();
+
+    let metadata = std::fs::metadata(path)?;
+    let (sources, files) = if metadata.is_dir() {
+        let mut relative = Vec::new();
+        walk(path, &mut Vec::new(), &mut relative)?;
+        relative.sort();
+        if relative.is_empty() {
+            return Err(format!("{} contains no files", path.display()).into());
+        }
+        let mut sources = Vec::new();
+        let mut files = Vec::new();
+        for components in relative {
+            let source = components.iter().fold(path.to_path_buf(), |path, component| path.join(component));
+            let length = std::fs::metadata(&source)?.len();
+            sources.push((source, length));
+            files.push(TorrentFile { length: length as usize, path: components, extra: HashMap::new() });
+        }
+        (sources, Some(files))
+    } else {
+        (vec![(path.to_path_buf(), metadata.len())], None)
+    };
+    let length: u64 = sources.iter().map(|(_, length)| length).sum();
+
+    let piece_length = match options.piece_length {
+        Some(piece_length) if piece_length < MIN_PIECE_LENGTH || !piece_length.is_power_of_two() => {
+            return Err(format!("Piece length must be a power of two of at least {} bytes", MIN_PIECE_LENGTH).into());
+        }
+        Some(piece_length) => piece_length,
+        None => auto_piece_length(length),
+    };
+
+    let mut extra = HashMap::new();
+    if options.private {
+        extra.insert("private".to_string(), BValue::Integer(1));
+    }
+    if let Some(source) = &options.source {
+        extra.insert("source".to_string(), BValue::ByteString(source.clone().into_bytes()));
+    }
+    let info = TorrentInfo {
+        length: length as usize,
+        name,
+        piece_length,
+        pieces: hash_pieces(&sources, length, piece_length as u64)?,
+        files,
+        extra,
+    };
+
+    Ok(torrent_from_info(&encode_bvalue(&info_to_bvalue(&info)), options))
+}
+
+/// Builds a .torrent around an existing bencoded info dict, such as one
+/// fetched from peers. The dict is kept byte for byte, so the info hash does
+/// not change; only the options outside it (trackers, comment, created by,
+/// creation date, web seeds) apply.
+pub fn torrent_from_info(info_bytes: &[u8], options: &CreateOptions) -> Vec<u8> {
+    let mut root = HashMap::new();
+    let trackers: Vec<&Vec<String>> = options.trackers.iter().filter(|tier| !tier.is_empty()).collect();
+    if let Some(first) = trackers.first() {
+        root.insert("announce".to_string(), string(&first[0]));
+        if trackers.len() > 1 || first.len() > 1 {
+            let tiers = trackers
+                .iter()
+                .map(|tier| BValue::List(tier.iter().map(|url| string(url)).collect()))
+                .collect();
+            root.insert("announce-list".to_string(), BValue::List(tiers));
+        }
+    }
+    if let Some(comment) = &options.comment {
+        root.insert("comment".to_string(), string(comment));
+    }
+    if let Some(created_by) = &options.created_by {
+        root.insert("created by".to_string(), string(created_by));
+    }
+    if let Some(creation_date) = options.creation_date {
+        root.insert("creation date".to_string(), BValue::Integer(creation_date));
+    }
+    if !options.web_seeds.is_empty() {
+        let urls = options.web_seeds.iter().map(|url| string(url)).collect();
+        root.insert("url-list".to_string(), BValue::List(urls));
+    }
+
+    // Encode the dict by hand so the info bytes go in untouched, keys sorted.
+    let mut keys: Vec<&str> = root.keys().map(String::as_str).chain(["info"]).collect();
+    keys.sort();
+    let mut encoded = vec![b'd'];
+    for key in keys {
+        encoded.extend_from_slice(&encode_bvalue(&string(key)));
+        match root.get(key) {
+            Some(value) => encoded.extend_from_slice(&encode_bvalue(value)),
+            None => encoded.extend_from_slice(info_bytes),
+        }
+    }
+    encoded.push(b'e');
+    encoded
+}
+
+fn string(value: &str) -> BValue {
+    BValue::ByteString(value.as_bytes().to_vec())
+}
+
+/// Collects the path components of every file below `dir`.
+fn walk(dir: &Path, prefix: &mut Vec<String>, files: &mut Vec<Vec<String>>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| format!("File name not valid UTF-8: {:?}", name))?;
+        prefix.push(name);
+        // Follows symlinks, like other torrent creators.
+        let metadata = std::fs::metadata(entry.path())?;
+        if metadata.is_dir() {
+            walk(&entry.path(), prefix, files)?;
+        } else if metadata.is_file() {
+            files.push(prefix.clone());
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+/// Hashes the concatenation of `sources` in pieces of `piece_length` bytes,
+/// each thread taking a contiguous run of pieces.
+fn hash_pieces(sources: &[(PathBuf, u64)], length: u64, piece_length: u64) -> Result<Vec<[u8; 20]>, Box<dyn Error + Send + Sync>> {
+    let count = length.div_ceil(piece_length) as usize;
+    let threads = std::thread::available_parallelism().map_or(4, |cores| cores.get());
+    let per_thread = count.div_ceil(threads).max(1);
+
+    let mut pieces = vec![[0u8; 20]; count];
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = pieces
+            .chunks_mut(per_thread)
+            .enumerate()
+            .map(|(chunk, hashes)| {
+                scope.spawn(move || -> std::io::Result<()> {
+                    let mut buffer = vec![0u8; piece_length as usize];
+                    for (offset, hash) in hashes.iter_mut().enumerate() {
+                        let start = (chunk * per_thread + offset) as u64 * piece_length;
+                        let size = piece_length.min(length - start) as usize;
+                        read_at(sources, start, &mut buffer[..size])?;
+                        *hash = Sha1::digest(&buffer[..size]).into();
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().map_err(|_| std::io::Error::other("Hashing thread panicked"))?)
+    })?;
+    Ok(pieces)
+}
+
+/// Fills `buffer` from offset `start` of the concatenated files.
+fn read_at(sources: &[(PathBuf, u64)], start: u64, buffer: &mut [u8]) -> std::io::Result<()> {
+    let end = start + buffer.len() as u64;
+    let mut file_start = 0u64;
+    for (path, file_length) in sources {
+        let file_end = file_start + file_length;
+        let from = start.max(file_start);
+        let to = end.min(file_end);
+        if from < to {
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::Start(from - file_start))?;
+            file.read_exact(&mut buffer[(from - start) as usize..(to - start) as usize])?;
+        }
+        if file_end >= end {
+            break;
+        }
+        file_start = file_end;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::Torrent;
+
+    #[test]
+    fn test_directory_round_trips_with_the_canonical_info_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("dataset");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub").join("b.bin"), b"0123456789abc").unwrap();
+        std::fs::write(root.join("a.txt"), b"abcd").unwrap();
+
+        let options = CreateOptions {
+            trackers: vec![vec!["http://a/announce".to_string(), "http://b/announce".to_string()], vec!["udp://c:80".to_string()]],
+            comment: Some("test data".to_string()),
+            created_by: Some("rusbit".to_string()),
+            creation_date: Some(1_700_000_000),
+            private: true,
+            source: Some("LAB".to_string()),
+            web_seeds: vec!["http://seed/".to_string()],
+            ..CreateOptions::default()
+        };
+        let encoded = create_torrent(&root, &options).unwrap();
+        let torrent = Torrent::from_bytes(&encoded).unwrap();
+
+        // The info dict other tools write for the same content.
+        let mut info = b"d5:filesld6:lengthi4e4:pathl5:a.txteed6:lengthi13e4:pathl3:sub5:b.bineee4:name7:dataset12:piece lengthi16384e6:pieces20:".to_vec();
+        info.extend_from_slice(&Sha1::digest(b"abcd0123456789abc"));
+        info.extend_from_slice(b"7:privatei1e6:source3:LABe");
+        assert_eq!(torrent.info_bytes, info);
+        assert_eq!(torrent.info_hash, <[u8; 20]>::from(Sha1::digest(&info)));
+
+        assert_eq!(torrent.announce.as_deref(), Some("http://a/announce"));
+        assert_eq!(torrent.tracker_tiers(), options.trackers);
+        let text = String::from_utf8_lossy(&encoded);
+        for key in ["7:comment9:test data", "10:created by6:rusbit", "13:creation datei1700000000e", "8:url-listl12:http://seed/e"] {
+            assert!(text.contains(key), "missing {}", key);
+        }
+    }
+
+    #[test]
+    fn test_info_dict_is_kept_byte_for_byte() {
+        // Not canonical: the keys are out of order.
+        let info = b"d4:name1:x6:lengthi3e12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let options = CreateOptions {
+            trackers: vec![vec!["http://a/announce".to_string()]],
+            web_seeds: vec!["http://seed/x".to_string()],
+            ..CreateOptions::default()
+        };
+        let torrent = Torrent::from_bytes(&torrent_from_info(info, &options)).unwrap();
+        assert_eq!(torrent.info_bytes, info);
+        assert_eq!(torrent.info_hash, <[u8; 20]>::from(Sha1::digest(info)));
+        assert_eq!(torrent.tracker_tiers(), options.trackers);
+    }
+
+    #[test]
+    fn test_piece_length_and_multiple_pieces() {
+        assert_eq!(auto_piece_length(0), MIN_PIECE_LENGTH);
+        assert_eq!(auto_piece_length(4 << 30), 4 << 20);
+        assert_eq!(auto_piece_length(1 << 50), MAX_PIECE_LENGTH);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        let content: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+
+        let options = CreateOptions { piece_length: Some(MIN_PIECE_LENGTH), ..CreateOptions::default() };
+        let torrent = Torrent::from_bytes(&create_torrent(&path, &options).unwrap()).unwrap();
+        let expected: Vec<[u8; 20]> = content.chunks(MIN_PIECE_LENGTH).map(|piece| Sha1::digest(piece).into()).collect();
+        assert_eq!(torrent.info.pieces, expected);
+        assert_eq!(torrent.info.length, content.len());
+        assert!(torrent.announce.is_none());
+
+        let odd = CreateOptions { piece_length: Some(20_000), ..CreateOptions::default() };
+        assert!(create_torrent(&path, &odd).is_err());
+    }
+}
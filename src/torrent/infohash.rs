@@ -4,6 +4,20 @@ use crate::bencode::{encode_bvalue, BValue};
 
 use sha1::{Sha1, Digest};
 
+/// SHA-1 of the raw bencoded info dict. This is the info hash proper.
+pub fn calculate_info_hash(info_bytes: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.update(info_bytes);
+    let result = hasher.finalize();
+
+    let mut hash_bytes = [0u8; 20];
+    hash_bytes.copy_from_slice(&result);
+    hash_bytes
+}
+
+/// Re-encodes `info` and hashes it. Only matches the real info hash when the
+/// original dict was canonically encoded; prefer `calculate_info_hash` on the
+/// original bytes whenever they are available.
 pub fn calculate_info_hash_from_struct(info: &TorrentInfo) -> [u8; 20] {
     // Convert the struct to a BValue::Dict
     let info_bval = info_to_bvalue(info);
@@ -20,13 +34,33 @@ pub fn calculate_info_hash_from_struct(info: &TorrentInfo) -> [u8; 20] {
 }
 
 // turning `TorrentInfo` into a BValue::Dict
-fn info_to_bvalue(info: &TorrentInfo) -> BValue {
-    use std::collections::HashMap;
+pub fn info_to_bvalue(info: &TorrentInfo) -> BValue {
+    // Start from the keys we don't model so they survive the round trip
+    let mut map = info.extra.clone();
 
-    let mut map = HashMap::new();
-
-    // "length"
-    map.insert("length".to_string(), BValue::Integer(info.length as i64));
+    // "length" for a single file, "files" for a directory
+    match &info.files {
+        Some(files) => {
+            let entries = files
+                .iter()
+                .map(|file| {
+                    let mut entry = file.extra.clone();
+                    entry.insert("length".to_string(), BValue::Integer(file.length as i64));
+                    let path = file
+                        .path
+                        .iter()
+                        .map(|component| BValue::ByteString(component.clone().into_bytes()))
+                        .collect();
+                    entry.insert("path".to_string(), BValue::List(path));
+                    BValue::Dict(entry)
+                })
+                .collect();
+            map.insert("files".to_string(), BValue::List(entries));
+        }
+        None => {
+            map.insert("length".to_string(), BValue::Integer(info.length as i64));
+        }
+    }
 
     // "name"
     map.insert("name".to_string(), BValue::ByteString(info.name.clone().into_bytes()));
@@ -8,15 +8,24 @@ use std::{
 
 use serde::{Deserialize, Serialize};
 
-use crate::bencode::{decode_bencode, BValue};
-use crate::torrent::calculate_info_hash_from_struct;
+use crate::bencode::{decode_dict_with_spans, encode_bvalue, BValue};
+use crate::magnet_link::MagnetLink;
+use crate::torrent::calculate_info_hash;
+
+/// Keys of the info dictionary that `TorrentInfo` models explicitly.
+const INFO_KEYS: [&str; 5] = ["name", "piece length", "pieces", "length", "files"];
 
 /// Represents a .torrent file, including the announce URL and the associated info.
 #[derive(Serialize, Deserialize)]
 pub struct Torrent {
-    pub announce: String,       // The tracker URL
+    pub announce: Option<String>, // The tracker URL
+    #[serde(default)]
+    pub announce_list: Vec<Vec<String>>, // Tiers of tracker URLs (BEP 12)
     pub info: TorrentInfo,      // Torrent metadata
     pub info_hash: [u8; 20],      // Infohash 
+    pub info_bytes: Vec<u8>,    // The bencoded info dict exactly as it appeared in the file
+    #[serde(default)]
+    pub web_seeds: Vec<String>, // `url-list` (BEP 19)
 }
 
 /// Contains detailed metadata about the torrent's content.
@@ -26,6 +35,18 @@ pub struct TorrentInfo {
     pub name: String,           // Name of the file or folder
     pub piece_length: usize,    // Size of each piece
     pub pieces: Vec<[u8; 20]>,    // SHA-1 hashes are 20 bytes each
+    pub files: Option<Vec<TorrentFile>>, // File list, only present for multi-file torrents
+    #[serde(default)]
+    pub extra: HashMap<String, BValue>, // Info keys we don't model (private, source, ...)
+}
+
+/// A single entry of the `files` list in a multi-file torrent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TorrentFile {
+    pub length: usize,          // Size of this file
+    pub path: Vec<String>,      // Path components, relative to the root directory
+    #[serde(default)]
+    pub extra: HashMap<String, BValue>, // Entry keys we don't model (md5sum, ...)
 }
 
 impl Torrent {
@@ -42,16 +63,32 @@ impl Torrent {
         file.read_to_end(&mut buf)
             .map_err(|e| format!("I/O error while reading file: {}", e))?;
 
-        // Decode the bencoded data
-        let (_consumed, bvalue) = decode_bencode(&buf)
+        Self::from_bytes(&buf)
+    }
+
+    /// Parses the raw contents of a .torrent file.
+    ///
+    /// The info hash is computed over the original bytes of the `info` dict, so
+    /// torrents that aren't canonically encoded still hash correctly.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        // Decode the bencoded data, remembering where each top-level value lives
+        let (_consumed, bvalue, spans) = decode_dict_with_spans(buf)
             .map_err(|e| format!("Bencode error: {:?}", e))?;
 
         // Convert the BValue structure to a Torrent
-        Self::from_bvalue(&bvalue)
+        let mut torrent = Self::from_bvalue(&bvalue)?;
+        if let Some(span) = spans.get("info") {
+            torrent.info_bytes = buf[span.clone()].to_vec();
+            torrent.info_hash = calculate_info_hash(&torrent.info_bytes);
+        }
+        Ok(torrent)
     }
 
     /// Creates a `Torrent` from a `BValue` (the result of a bencode parse).
     ///
+    /// Without the original bytes at hand, the info dict is re-encoded, which
+    /// matches the original as long as it was canonically encoded.
+    ///
     /// Returns a boxed error if the required fields are missing or invalid.
     pub fn from_bvalue(value: &BValue) -> Result<Self, Box<dyn Error + Send + Sync>> {
         let root_dict = match value {
@@ -59,9 +96,19 @@ impl Torrent {
             _ => return Err("Root of .torrent must be a dictionary".into()),
         };
 
-        // Assume get_bytestring and the others are already converted to use Box<dyn Error + Send + Sync>
-        let announce: String = get_bytestring(&root_dict, "announce")?;
-
+        // Either tracker key may be missing, e.g. in trackerless torrents.
+        let announce = match root_dict.get("announce") {
+            Some(_) => Some(get_bytestring(root_dict, "announce")?),
+            None => None,
+        };
+        let announce_list = match root_dict.get("announce-list") {
+            Some(value) => parse_announce_list(value)?,
+            None => Vec::new(),
+        };
+        let web_seeds = match root_dict.get("url-list") {
+            Some(value) => parse_url_list(value)?,
+            None => Vec::new(),
+        };
 
         let value = root_dict
             .get("info")
@@ -72,23 +119,122 @@ impl Torrent {
             _ => return Err("'info' must be a dictionary".into()),
         };
 
-        let info: TorrentInfo = TorrentInfo::from_bvalue(&info_dict)?;
-        let info_hash = calculate_info_hash_from_struct(&info);
+        let info: TorrentInfo = TorrentInfo::from_bvalue(info_dict)?;
+        let info_bytes = encode_bvalue(value);
+        let info_hash = calculate_info_hash(&info_bytes);
 
         Ok(Torrent {
             announce,
+            announce_list,
             info,
             info_hash,
+            info_bytes,
+            web_seeds,
+        })
+    }
+}
+
+impl Torrent {
+    /// Returns the trackers to announce to, grouped into tiers.
+    ///
+    /// Per BEP 12, `announce-list` takes precedence over `announce` when present.
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        if !self.announce_list.is_empty() {
+            return self.announce_list.clone();
+        }
+        match &self.announce {
+            Some(announce) => vec![vec![announce.clone()]],
+            None => Vec::new(),
+        }
+    }
+
+    /// A magnet link for the torrent: its info hash, name and length, every
+    /// tracker of `announce` and `announce-list`, and its web seeds.
+    pub fn to_magnet(&self) -> String {
+        let mut trackers: Vec<String> = Vec::new();
+        for url in self.announce.iter().chain(self.announce_list.iter().flatten()) {
+            if !trackers.contains(url) {
+                trackers.push(url.clone());
+            }
+        }
+        let magnet = MagnetLink {
+            info_hash: Some(self.info_hash),
+            display_name: Some(self.info.name.clone()),
+            length: Some(self.info.length as u64),
+            trackers,
+            web_seeds: self.web_seeds.clone(),
+            ..MagnetLink::default()
+        };
+        magnet.to_uri()
+    }
+}
+
+/// Returns false for names that could escape the directory they are joined
+/// to: empty names, `.`, `..` and anything containing a path separator.
+fn is_safe_path_component(component: &str) -> bool {
+    !(component.is_empty()
+        || component == "."
+        || component == ".."
+        || component.contains('/')
+        || component.contains('\\'))
+}
+
+/// Parses `url-list`: a single URL or a list of them.
+fn parse_url_list(value: &BValue) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let urls = match value {
+        BValue::ByteString(_) => std::slice::from_ref(value),
+        BValue::List(urls) => urls,
+        _ => return Err("'url-list' must be a ByteString or a List".into()),
+    };
+    urls.iter()
+        .map(|url| match url {
+            BValue::ByteString(bytes) => String::from_utf8(bytes.clone())
+                .map_err(|_| "'url-list' URL not valid UTF-8".into()),
+            _ => Err("'url-list' URLs must be ByteStrings".into()),
         })
+        .collect()
+}
+
+/// Parses `announce-list`: a list of tiers, each a list of tracker URLs.
+/// Empty tiers are dropped.
+fn parse_announce_list(value: &BValue) -> Result<Vec<Vec<String>>, Box<dyn Error + Send + Sync>> {
+    let tiers = match value {
+        BValue::List(tiers) => tiers,
+        _ => return Err("'announce-list' must be a List".into()),
+    };
+
+    let mut result = Vec::with_capacity(tiers.len());
+    for tier in tiers {
+        let urls = match tier {
+            BValue::List(urls) => urls,
+            _ => return Err("'announce-list' tiers must be Lists".into()),
+        };
+        let mut tier_urls = Vec::with_capacity(urls.len());
+        for url in urls {
+            match url {
+                BValue::ByteString(bytes) => tier_urls.push(
+                    String::from_utf8(bytes.clone())
+                        .map_err(|_| "'announce-list' URL not valid UTF-8".to_string())?,
+                ),
+                _ => return Err("'announce-list' URLs must be ByteStrings".into()),
+            }
+        }
+        if !tier_urls.is_empty() {
+            result.push(tier_urls);
+        }
     }
+    Ok(result)
 }
 
 impl TorrentInfo {
     pub fn from_bvalue(info_dict: &HashMap<String, BValue>) -> Result<Self, Box<dyn Error + Send + Sync>> {
-        let name: String = get_bytestring(&info_dict, "name")?;
-        let length = get_integer(&info_dict, "length")?;
-        let piece_length = get_integer(&info_dict, "piece length")?;
-        let pieces_bytes = lookup_bytestring(&info_dict, "pieces")?;
+        let name: String = get_bytestring(info_dict, "name")?;
+        // The name becomes a file or directory below the output path.
+        if !is_safe_path_component(&name) {
+            return Err(format!("Invalid 'name': {:?}", name).into());
+        }
+        let piece_length = get_integer(info_dict, "piece length")?;
+        let pieces_bytes = lookup_bytestring(info_dict, "pieces")?;
 
         // Chunk the pieces bytes into 20-byte pieces.
         let pieces = pieces_bytes
@@ -100,13 +246,102 @@ impl TorrentInfo {
             })
             .collect();
 
+        // Single-file torrents carry a `length`, multi-file torrents a `files` list.
+        let (length, files) = match info_dict.get("files") {
+            Some(BValue::List(entries)) => {
+                let files = entries
+                    .iter()
+                    .map(TorrentFile::from_bvalue)
+                    .collect::<Result<Vec<_>, _>>()?;
+                if files.is_empty() {
+                    return Err("'files' must not be empty".into());
+                }
+                let length = files.iter().map(|file| file.length).sum();
+                (length, Some(files))
+            }
+            Some(_) => return Err("'files' must be a List".into()),
+            None => (get_integer(info_dict, "length")?, None),
+        };
+
+        // Keep everything else so that re-encoding reproduces the same info hash.
+        let extra = info_dict
+            .iter()
+            .filter(|(key, _)| !INFO_KEYS.contains(&key.as_str()))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+
         Ok(TorrentInfo {
             name,
             length,
             piece_length,
             pieces,
+            files,
+            extra,
         })
     }
+
+    /// Returns true if the torrent is private (BEP 27): peers may only come
+    /// from its trackers, not from the DHT or peer exchange.
+    pub fn is_private(&self) -> bool {
+        matches!(self.extra.get("private"), Some(BValue::Integer(1)))
+    }
+
+    /// Returns true if the torrent describes a directory of files.
+    pub fn is_multi_file(&self) -> bool {
+        self.files.is_some()
+    }
+
+    /// Size of a piece in bytes; the last piece may be shorter than the piece
+    /// length. Computed in `u64` so torrents larger than 4 GiB work.
+    pub fn piece_size(&self, piece_index: u32) -> u64 {
+        let piece_length = self.piece_length as u64;
+        let piece_start = piece_index as u64 * piece_length;
+        std::cmp::min(piece_length, (self.length as u64).saturating_sub(piece_start))
+    }
+}
+
+impl TorrentFile {
+    /// Parses one entry of the `files` list.
+    ///
+    /// Path components that could escape the root directory (`..`, separators,
+    /// empty names) are rejected.
+    pub fn from_bvalue(value: &BValue) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let dict = match value {
+            BValue::Dict(m) => m,
+            _ => return Err("'files' entries must be dictionaries".into()),
+        };
+
+        let length = get_integer(dict, "length")?;
+        let components = match dict.get("path") {
+            Some(BValue::List(items)) => items,
+            Some(_) => return Err("'path' must be a List".into()),
+            None => return Err("Missing 'path'".into()),
+        };
+
+        let mut path = Vec::with_capacity(components.len());
+        for component in components {
+            let component = match component {
+                BValue::ByteString(bytes) => String::from_utf8(bytes.clone())
+                    .map_err(|_| "'path' component not valid UTF-8".to_string())?,
+                _ => return Err("'path' components must be ByteStrings".into()),
+            };
+            if !is_safe_path_component(&component) {
+                return Err(format!("Invalid 'path' component: {:?}", component).into());
+            }
+            path.push(component);
+        }
+        if path.is_empty() {
+            return Err("'path' must not be empty".into());
+        }
+
+        let extra = dict
+            .iter()
+            .filter(|(key, _)| key.as_str() != "length" && key.as_str() != "path")
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+
+        Ok(TorrentFile { length, path, extra })
+    }
 }
 
 
@@ -156,4 +391,124 @@ pub fn get_integer(
         BValue::Integer(b) => Ok(*b as usize),
         _ => Err(format!("'{}' must be a Number", key).into()),
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::decode_bencode;
+    use crate::torrent::calculate_info_hash_from_struct;
+    use sha1::{Digest, Sha1};
+
+    const MULTI_FILE_INFO: &[u8] = b"d5:filesld6:lengthi4e4:pathl5:a.txteed6:lengthi13e4:pathl3:sub5:b.bineee4:name7:dataset12:piece lengthi10e6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbe";
+
+    #[test]
+    fn test_multi_file_torrent() {
+        let mut encoded = b"d8:announce23:http://tracker/announce4:info".to_vec();
+        encoded.extend_from_slice(MULTI_FILE_INFO);
+        encoded.push(b'e');
+
+        let torrent = Torrent::from_bytes(&encoded).unwrap();
+
+        assert!(torrent.info.is_multi_file());
+        assert_eq!(torrent.info.length, 17);
+        assert_eq!(torrent.info.pieces.len(), 2);
+        assert_eq!(
+            torrent.info.files.as_ref().unwrap(),
+            &vec![
+                TorrentFile { length: 4, path: vec!["a.txt".to_string()], extra: HashMap::new() },
+                TorrentFile { length: 13, path: vec!["sub".to_string(), "b.bin".to_string()], extra: HashMap::new() },
+            ]
+        );
+
+        let expected: [u8; 20] = Sha1::digest(MULTI_FILE_INFO).into();
+        assert_eq!(torrent.info_hash, expected);
+        assert_eq!(calculate_info_hash_from_struct(&torrent.info), expected);
+    }
+
+    #[test]
+    fn test_magnet_link_parses_back() {
+        let mut encoded = b"d8:announce8:http://a13:announce-listll8:http://ael8:http://b8:http://cee4:info".to_vec();
+        encoded.extend_from_slice(MULTI_FILE_INFO);
+        encoded.extend_from_slice(b"8:url-list13:http://seed/xe");
+        let torrent = Torrent::from_bytes(&encoded).unwrap();
+        assert_eq!(torrent.web_seeds, vec!["http://seed/x"]);
+
+        let magnet = crate::magnet_link::decode_magnet(&torrent.to_magnet()).unwrap();
+        assert_eq!(magnet.info_hash, Some(torrent.info_hash));
+        assert_eq!(magnet.display_name.as_deref(), Some("dataset"));
+        assert_eq!(magnet.length, Some(17));
+        assert_eq!(magnet.trackers, vec!["http://a", "http://b", "http://c"]);
+        assert_eq!(magnet.web_seeds, torrent.web_seeds);
+    }
+
+    #[test]
+    fn test_info_hash_keeps_unknown_keys() {
+        let info: &[u8] = b"d6:lengthi5e4:name1:a12:piece lengthi10e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1e6:source3:abce";
+        let mut encoded = b"d8:announce23:http://tracker/announce4:info".to_vec();
+        encoded.extend_from_slice(info);
+        encoded.push(b'e');
+
+        let torrent = Torrent::from_bytes(&encoded).unwrap();
+        let expected: [u8; 20] = Sha1::digest(info).into();
+        assert_eq!(torrent.info_hash, expected);
+        assert_eq!(torrent.info_bytes, info);
+        assert_eq!(torrent.info.extra.get("private"), Some(&BValue::Integer(1)));
+
+        // Re-encoding the parsed struct must reproduce the same hash.
+        assert_eq!(calculate_info_hash_from_struct(&torrent.info), expected);
+    }
+
+    #[test]
+    fn test_info_hash_uses_original_bytes() {
+        // Keys out of order: a re-encode would sort them and change the hash.
+        let info: &[u8] = b"d4:name1:a6:lengthi5e12:piece lengthi10e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let mut encoded = b"d8:announce23:http://tracker/announce4:info".to_vec();
+        encoded.extend_from_slice(info);
+        encoded.push(b'e');
+
+        let torrent = Torrent::from_bytes(&encoded).unwrap();
+        let expected: [u8; 20] = Sha1::digest(info).into();
+        assert_eq!(torrent.info_hash, expected);
+    }
+
+    #[test]
+    fn test_announce_list_tiers() {
+        let encoded = b"d8:announce5:http013:announce-listll5:http15:http2elel5:udp:3ee4:infod6:lengthi5e4:name1:a12:piece lengthi10e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let torrent = Torrent::from_bytes(encoded).unwrap();
+        assert_eq!(torrent.announce.as_deref(), Some("http0"));
+        // announce-list wins over announce, and the empty tier is dropped.
+        assert_eq!(torrent.tracker_tiers(), vec![
+            vec!["http1".to_string(), "http2".to_string()],
+            vec!["udp:3".to_string()],
+        ]);
+    }
+
+    #[test]
+    fn test_rejects_path_traversal() {
+        let encoded = b"d5:filesld6:lengthi4e4:pathl2:..6:passwdeee4:name3:abc12:piece lengthi10e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let (_consumed, bvalue) = decode_bencode(encoded).unwrap();
+        let info_dict = match bvalue {
+            BValue::Dict(m) => m,
+            _ => unreachable!(),
+        };
+        assert!(TorrentInfo::from_bvalue(&info_dict).is_err());
+    }
+
+    #[test]
+    fn test_rejects_unsafe_name() {
+        for name in ["..", "/etc", "a/../../x", "."] {
+            let encoded = format!(
+                "d6:lengthi5e4:name{}:{}12:piece lengthi10e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+                name.len(),
+                name
+            );
+            let (_consumed, bvalue) = decode_bencode(encoded.as_bytes()).unwrap();
+            let info_dict = match bvalue {
+                BValue::Dict(m) => m,
+                _ => unreachable!(),
+            };
+            assert!(TorrentInfo::from_bvalue(&info_dict).is_err(), "accepted {:?}", name);
+        }
+    }
+}
@@ -1,5 +1,7 @@
 pub mod metadata;
 pub mod infohash;
+pub mod create;
 
-pub use infohash::calculate_info_hash_from_struct;
-pub use metadata::{Torrent, TorrentInfo, get_integer };
+pub use infohash::{calculate_info_hash, calculate_info_hash_from_struct};
+pub use create::{create_torrent, torrent_from_info, auto_piece_length, CreateOptions};
+pub use metadata::{Torrent, TorrentInfo, TorrentFile, get_integer };
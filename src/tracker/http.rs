@@ -14,6 +14,7 @@ use std::error::Error;
 /// * `port`: Port number.
 ///
 /// Returns a vector of (ip, port) pairs or an error.
+#[allow(clippy::too_many_arguments)]
 pub async fn announce(
     client: &Client,
     announce: &str,
@@ -0,0 +1,274 @@
+pub mod http;
+pub mod udp;
+
+use log::{debug, warn};
+use rand::seq::SliceRandom;
+use reqwest::Client;
+use std::collections::HashSet;
+use std::error::Error;
+use std::time::Duration;
+
+/// How long one tracker gets to answer before the next one is tried.
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Announces to a tracker and returns a list of peers (IP+port).
+///
+/// Dispatches on the URL scheme: `udp://` trackers speak the BEP 15 protocol,
+/// everything else goes through HTTP(S).
+#[allow(clippy::too_many_arguments)]
+pub async fn announce(
+    client: &Client,
+    announce: &str,
+    info_hash: &[u8],
+    peer_id: &[u8; 20],
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
+    port: u16,
+) -> Result<Vec<(String, u16)>, Box<dyn Error + Send + Sync>> {
+    if announce.starts_with("udp://") {
+        udp::announce(announce, info_hash, peer_id, uploaded, downloaded, left, port).await
+    } else {
+        http::announce(client, announce, info_hash, peer_id, uploaded, downloaded, left, port).await
+    }
+}
+
+/// Trackers grouped into tiers, announced to following BEP 12.
+///
+/// Each tier is shuffled once on creation. Within a tier, trackers are tried in
+/// order until one answers, each for at most `ANNOUNCE_TIMEOUT`, and the one
+/// that answered moves to the front of its tier. The next tier is only tried
+/// when every tracker of the current one failed.
+#[derive(Debug, Clone)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+    timeout: Duration,
+}
+
+impl TrackerTiers {
+    pub fn new(tiers: Vec<Vec<String>>) -> Self {
+        let mut rng = rand::thread_rng();
+        let tiers = tiers
+            .into_iter()
+            .filter(|tier| !tier.is_empty())
+            .map(|mut tier| {
+                tier.shuffle(&mut rng);
+                tier
+            })
+            .collect();
+        Self { tiers, timeout: ANNOUNCE_TIMEOUT }
+    }
+
+    /// Like `new`, but keeps the tier order saved from an earlier run (with
+    /// the trackers that answered in front) if it covers the same trackers.
+    pub fn restore(tiers: Vec<Vec<String>>, saved: &[Vec<String>]) -> Self {
+        let sorted = |tiers: &[Vec<String>]| -> Vec<Vec<String>> {
+            tiers
+                .iter()
+                .filter(|tier| !tier.is_empty())
+                .map(|tier| {
+                    let mut tier = tier.clone();
+                    tier.sort();
+                    tier
+                })
+                .collect()
+        };
+        if sorted(&tiers) == sorted(saved) {
+            Self {
+                tiers: saved.iter().filter(|tier| !tier.is_empty()).cloned().collect(),
+                timeout: ANNOUNCE_TIMEOUT,
+            }
+        } else {
+            Self::new(tiers)
+        }
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    /// Announces tier by tier until a tracker answers and returns its
+    /// deduplicated peers.
+    ///
+    /// Fails only if no tracker in any tier answered.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn announce(
+        &mut self,
+        client: &Client,
+        info_hash: &[u8],
+        peer_id: &[u8; 20],
+        uploaded: u64,
+        downloaded: u64,
+        left: u64,
+        port: u16,
+    ) -> Result<Vec<(String, u16)>, Box<dyn Error + Send + Sync>> {
+        let mut last_error: Option<Box<dyn Error + Send + Sync>> = None;
+        for tier in self.tiers.iter_mut() {
+            match announce_tier(client, tier, info_hash, peer_id, uploaded, downloaded, left, port, self.timeout).await {
+                Ok((responsive, tracker_peers)) => {
+                    // Promote the tracker that answered.
+                    let responsive = tier.remove(responsive);
+                    tier.insert(0, responsive);
+
+                    let mut seen = HashSet::new();
+                    let peers = tracker_peers.into_iter().filter(|peer| seen.insert(peer.clone())).collect();
+                    return Ok(peers);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| "No trackers to announce to".into()))
+    }
+}
+
+/// Tries the trackers of one tier in order, each for at most `timeout`, and
+/// returns the position of the first one that answered along with its peers.
+#[allow(clippy::too_many_arguments)]
+async fn announce_tier(
+    client: &Client,
+    tier: &[String],
+    info_hash: &[u8],
+    peer_id: &[u8; 20],
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
+    port: u16,
+    timeout: Duration,
+) -> Result<(usize, Vec<(String, u16)>), Box<dyn Error + Send + Sync>> {
+    let mut last_error: Option<Box<dyn Error + Send + Sync>> = None;
+    for (i, url) in tier.iter().enumerate() {
+        let attempt = announce(client, url, info_hash, peer_id, uploaded, downloaded, left, port);
+        match tokio::time::timeout(timeout, attempt).await {
+            Ok(Ok(tracker_peers)) => {
+                debug!("Tracker {} returned {} peers", url, tracker_peers.len());
+                return Ok((i, tracker_peers));
+            }
+            Ok(Err(e)) => {
+                warn!("Tracker {} failed: {}", url, e);
+                last_error = Some(e);
+            }
+            Err(_) => {
+                warn!("Tracker {} did not answer within {:?}", url, timeout);
+                last_error = Some(format!("Tracker {} timed out", url).into());
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| "No trackers to announce to".into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serves a compact tracker response with the given peers to every request.
+    async fn spawn_http_tracker(peers: Vec<[u8; 6]>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+
+        let mut body = format!("d8:intervali1800e5:peers{}:", peers.len() * 6).into_bytes();
+        for peer in &peers {
+            body.extend_from_slice(peer);
+        }
+        body.push(b'e');
+
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 4096];
+                let _ = socket.read(&mut buf).await;
+                let mut response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .into_bytes();
+                response.extend_from_slice(&body);
+                let _ = socket.write_all(&response).await;
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_tiers_fall_back_and_promote() {
+        let good = spawn_http_tracker(vec![[127, 0, 0, 1, 0, 1], [127, 0, 0, 2, 0, 2], [127, 0, 0, 1, 0, 1]]).await;
+        let backup = spawn_http_tracker(vec![[127, 0, 0, 3, 0, 3]]).await;
+        // Nothing listens on port 1, so this tracker fails straight away.
+        let dead = "http://127.0.0.1:1/announce".to_string();
+
+        // The first tier answers, so the backup tier is never asked.
+        let mut tiers = TrackerTiers::new(vec![
+            vec![dead.clone(), good.clone()],
+            vec![backup.clone()],
+        ]);
+        let peers = tiers
+            .announce(&Client::new(), &[0u8; 20], &[0u8; 20], 0, 0, 10, 6881)
+            .await
+            .unwrap();
+        assert_eq!(peers, vec![
+            ("127.0.0.1".to_string(), 1),
+            ("127.0.0.2".to_string(), 2),
+        ]);
+        assert_eq!(tiers.tiers()[0], vec![good, dead.clone()]);
+
+        // A tier whose trackers all fail falls back to the next one.
+        let mut tiers = TrackerTiers::new(vec![vec![dead], vec![backup]]);
+        let peers = tiers
+            .announce(&Client::new(), &[0u8; 20], &[0u8; 20], 0, 0, 10, 6881)
+            .await
+            .unwrap();
+        assert_eq!(peers, vec![("127.0.0.3".to_string(), 3)]);
+    }
+
+    #[tokio::test]
+    async fn test_unresponsive_trackers_do_not_hold_up_other_tiers() {
+        let good = spawn_http_tracker(vec![[127, 0, 0, 1, 0, 1]]).await;
+        // These take the announce but never answer it.
+        let silent_http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent_udp = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let mut tiers = TrackerTiers::new(vec![
+            vec![format!("http://{}/announce", silent_http.local_addr().unwrap())],
+            vec![format!("udp://{}/announce", silent_udp.local_addr().unwrap())],
+            vec![good],
+        ]);
+        tiers.timeout = Duration::from_millis(200);
+        let started = std::time::Instant::now();
+        let peers = tiers
+            .announce(&Client::new(), &[0u8; 20], &[0u8; 20], 0, 0, 10, 6881)
+            .await
+            .unwrap();
+
+        assert_eq!(peers, vec![("127.0.0.1".to_string(), 1)]);
+        // Without the timeout the UDP tracker alone would hold us up for 15 s,
+        // and the HTTP one for good.
+        assert!(started.elapsed() < Duration::from_secs(2), "took {:?}", started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn test_tiers_fail_when_nobody_answers() {
+        let mut tiers = TrackerTiers::new(vec![vec!["http://127.0.0.1:1/announce".to_string()]]);
+        let result = tiers
+            .announce(&Client::new(), &[0u8; 20], &[0u8; 20], 0, 0, 10, 6881)
+            .await;
+        assert!(result.is_err());
+        assert!(TrackerTiers::new(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn test_restore_keeps_saved_order_of_the_same_trackers() {
+        let tiers = vec![vec!["a".to_string(), "b".to_string()], vec!["c".to_string()]];
+        let saved = vec![vec!["b".to_string(), "a".to_string()], vec!["c".to_string()]];
+        assert_eq!(TrackerTiers::restore(tiers.clone(), &saved).tiers(), &saved[..]);
+
+        // A tracker was added to the torrent since: the saved order is stale.
+        let changed = vec![vec!["b".to_string()], vec!["c".to_string()]];
+        let restored = TrackerTiers::restore(tiers, &changed);
+        assert_eq!(restored.tiers()[0].len(), 2);
+    }
+}
@@ -0,0 +1,462 @@
+// udp.rs
+//! UDP tracker protocol (BEP 15).
+//!
+//! Every exchange starts with a `connect` that yields a connection ID, which is
+//! then used for `announce` and `scrape` requests. Connection IDs are cached per
+//! tracker address for one minute, and requests are retransmitted with a
+//! timeout of `15 * 2^n` seconds, up to `n = 8`.
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use rand::Rng;
+use tokio::net::{lookup_host, UdpSocket};
+
+/// Magic constant that identifies a connect request.
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// How long a connection ID may be used by the client.
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+
+/// Base retransmission timeout, doubled on every retry.
+const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Highest `n` in `15 * 2^n`.
+const MAX_RETRANSMISSIONS: u32 = 8;
+
+/// Connection IDs handed out by trackers, keyed by tracker address.
+static CONNECTION_IDS: OnceLock<Mutex<HashMap<SocketAddr, (u64, Instant)>>> = OnceLock::new();
+
+fn connection_ids() -> &'static Mutex<HashMap<SocketAddr, (u64, Instant)>> {
+    CONNECTION_IDS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Swarm statistics for one info hash, as returned by a scrape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeStats {
+    pub seeders: u32,
+    pub completed: u32,
+    pub leechers: u32,
+}
+
+/// A UDP socket talking to a single tracker.
+pub struct UdpTracker {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    base_timeout: Duration,
+    max_retransmissions: u32,
+}
+
+/// Outcome of waiting for one response.
+enum Reply {
+    Data(Vec<u8>),
+    TimedOut,
+}
+
+impl UdpTracker {
+    /// Resolves a `udp://host:port[/path]` URL and binds a local socket for it.
+    pub async fn connect(url: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let host_port = url
+            .strip_prefix("udp://")
+            .ok_or_else(|| format!("Not a UDP tracker URL: {url}"))?;
+        // Drop any path such as "/announce".
+        let host_port = host_port.split('/').next().unwrap_or(host_port);
+
+        let addr = lookup_host(host_port)
+            .await
+            .map_err(|e| format!("Resolving UDP tracker {host_port} failed: {e}"))?
+            .next()
+            .ok_or_else(|| format!("UDP tracker {host_port} did not resolve"))?;
+
+        let bind_addr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.connect(addr).await?;
+
+        Ok(Self {
+            socket,
+            addr,
+            base_timeout: BASE_TIMEOUT,
+            max_retransmissions: MAX_RETRANSMISSIONS,
+        })
+    }
+
+    /// Sends an announce request and returns the peers the tracker handed out.
+    pub async fn announce(
+        &self,
+        info_hash: &[u8],
+        peer_id: &[u8; 20],
+        uploaded: u64,
+        downloaded: u64,
+        left: u64,
+        port: u16,
+    ) -> Result<Vec<(String, u16)>, Box<dyn Error + Send + Sync>> {
+        if info_hash.len() != 20 {
+            return Err("Info hash must be 20 bytes".into());
+        }
+        let key: u32 = rand::thread_rng().gen();
+
+        let response = self
+            .request(ACTION_ANNOUNCE, |connection_id, transaction_id| {
+                let mut msg = Vec::with_capacity(98);
+                msg.extend_from_slice(&connection_id.to_be_bytes());
+                msg.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+                msg.extend_from_slice(&transaction_id.to_be_bytes());
+                msg.extend_from_slice(info_hash);
+                msg.extend_from_slice(peer_id);
+                msg.extend_from_slice(&downloaded.to_be_bytes());
+                msg.extend_from_slice(&left.to_be_bytes());
+                msg.extend_from_slice(&uploaded.to_be_bytes());
+                msg.extend_from_slice(&0u32.to_be_bytes()); // event: none
+                msg.extend_from_slice(&0u32.to_be_bytes()); // ip: use the sender's address
+                msg.extend_from_slice(&key.to_be_bytes());
+                msg.extend_from_slice(&(-1i32).to_be_bytes()); // num_want: tracker default
+                msg.extend_from_slice(&port.to_be_bytes());
+                msg
+            })
+            .await?;
+
+        // action(4) transaction_id(4) interval(4) leechers(4) seeders(4), then
+        // 6 bytes per peer, or 18 when the tracker was reached over IPv6.
+        if response.len() < 20 {
+            return Err("UDP announce response too short".into());
+        }
+        let peers_bytes = &response[20..];
+        let entry_len = if self.addr.is_ipv6() { 18 } else { 6 };
+        if peers_bytes.len() % entry_len != 0 {
+            return Err("Invalid compact peers length".into());
+        }
+
+        let peers = peers_bytes
+            .chunks_exact(entry_len)
+            .map(|chunk| {
+                let (ip, port) = chunk.split_at(entry_len - 2);
+                // IPv6 hosts keep their brackets, as for `--peer`.
+                let ip = match <[u8; 4]>::try_from(ip) {
+                    Ok(ip) => Ipv4Addr::from(ip).to_string(),
+                    Err(_) => format!("[{}]", Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap())),
+                };
+                (ip, u16::from_be_bytes([port[0], port[1]]))
+            })
+            .collect();
+        Ok(peers)
+    }
+
+    /// Asks the tracker for swarm statistics of up to ~74 info hashes at once.
+    pub async fn scrape(
+        &self,
+        info_hashes: &[[u8; 20]],
+    ) -> Result<Vec<ScrapeStats>, Box<dyn Error + Send + Sync>> {
+        let response = self
+            .request(ACTION_SCRAPE, |connection_id, transaction_id| {
+                let mut msg = Vec::with_capacity(16 + 20 * info_hashes.len());
+                msg.extend_from_slice(&connection_id.to_be_bytes());
+                msg.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+                msg.extend_from_slice(&transaction_id.to_be_bytes());
+                for info_hash in info_hashes {
+                    msg.extend_from_slice(info_hash);
+                }
+                msg
+            })
+            .await?;
+
+        // action(4) transaction_id(4) then 12 bytes per info hash
+        let stats_bytes = &response[8..];
+        if stats_bytes.len() < 12 * info_hashes.len() {
+            return Err("UDP scrape response too short".into());
+        }
+        let stats = stats_bytes
+            .chunks_exact(12)
+            .take(info_hashes.len())
+            .map(|chunk| ScrapeStats {
+                seeders: read_u32(&chunk[0..4]),
+                completed: read_u32(&chunk[4..8]),
+                leechers: read_u32(&chunk[8..12]),
+            })
+            .collect();
+        Ok(stats)
+    }
+
+    /// Runs one request/response exchange, connecting first if there is no
+    /// valid connection ID, and retransmitting on timeouts.
+    async fn request<F>(&self, action: u32, build: F) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>>
+    where
+        F: Fn(u64, u32) -> Vec<u8>,
+    {
+        for n in 0..=self.max_retransmissions {
+            let timeout = self.base_timeout * 2u32.pow(n);
+
+            let connection_id = match self.cached_connection_id() {
+                Some(id) => id,
+                None => match self.exchange_connect(timeout).await? {
+                    Some(id) => id,
+                    None => continue,
+                },
+            };
+
+            let transaction_id: u32 = rand::thread_rng().gen();
+            self.socket.send(&build(connection_id, transaction_id)).await?;
+            match self.wait_for(action, transaction_id, timeout).await {
+                Ok(Reply::Data(data)) => return Ok(data),
+                Ok(Reply::TimedOut) => debug!("UDP tracker {} timed out after {:?}", self.addr, timeout),
+                Err(e) => {
+                    // The tracker may have rejected an expired connection ID.
+                    self.forget_connection_id();
+                    return Err(e);
+                }
+            }
+        }
+        Err(format!("UDP tracker {} did not respond", self.addr).into())
+    }
+
+    /// Sends a connect request; returns `None` if it timed out.
+    async fn exchange_connect(&self, timeout: Duration) -> Result<Option<u64>, Box<dyn Error + Send + Sync>> {
+        let transaction_id: u32 = rand::thread_rng().gen();
+        let mut msg = Vec::with_capacity(16);
+        msg.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+        msg.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+        msg.extend_from_slice(&transaction_id.to_be_bytes());
+        self.socket.send(&msg).await?;
+
+        match self.wait_for(ACTION_CONNECT, transaction_id, timeout).await? {
+            Reply::Data(data) => {
+                if data.len() < 16 {
+                    return Err("UDP connect response too short".into());
+                }
+                let connection_id = u64::from_be_bytes(data[8..16].try_into()?);
+                connection_ids()
+                    .lock()
+                    .unwrap()
+                    .insert(self.addr, (connection_id, Instant::now()));
+                Ok(Some(connection_id))
+            }
+            Reply::TimedOut => {
+                debug!("UDP tracker {} connect timed out after {:?}", self.addr, timeout);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Waits for the response to `transaction_id`, ignoring stray datagrams.
+    async fn wait_for(
+        &self,
+        action: u32,
+        transaction_id: u32,
+        timeout: Duration,
+    ) -> Result<Reply, Box<dyn Error + Send + Sync>> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let len = match tokio::time::timeout_at(deadline, self.socket.recv(&mut buf)).await {
+                Ok(received) => received?,
+                Err(_) => return Ok(Reply::TimedOut),
+            };
+            let data = &buf[..len];
+            if data.len() < 8 || read_u32(&data[4..8]) != transaction_id {
+                continue;
+            }
+            return match read_u32(&data[0..4]) {
+                ACTION_ERROR => {
+                    let message = String::from_utf8_lossy(&data[8..]);
+                    Err(format!("Tracker failure: {message}").into())
+                }
+                received if received == action => Ok(Reply::Data(data.to_vec())),
+                received => Err(format!("Unexpected UDP tracker action {received}").into()),
+            };
+        }
+    }
+
+    fn cached_connection_id(&self) -> Option<u64> {
+        let ids = connection_ids().lock().unwrap();
+        match ids.get(&self.addr) {
+            Some((id, obtained)) if obtained.elapsed() < CONNECTION_ID_TTL => Some(*id),
+            _ => None,
+        }
+    }
+
+    fn forget_connection_id(&self) {
+        connection_ids().lock().unwrap().remove(&self.addr);
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Announces to a `udp://` tracker and returns a list of peers (IP+port).
+pub async fn announce(
+    url: &str,
+    info_hash: &[u8],
+    peer_id: &[u8; 20],
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
+    port: u16,
+) -> Result<Vec<(String, u16)>, Box<dyn Error + Send + Sync>> {
+    let tracker = UdpTracker::connect(url).await?;
+    tracker
+        .announce(info_hash, peer_id, uploaded, downloaded, left, port)
+        .await
+}
+
+/// Scrapes a `udp://` tracker for the given info hashes.
+pub async fn scrape(
+    url: &str,
+    info_hashes: &[[u8; 20]],
+) -> Result<Vec<ScrapeStats>, Box<dyn Error + Send + Sync>> {
+    let tracker = UdpTracker::connect(url).await?;
+    tracker.scrape(info_hashes).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// A minimal tracker stand-in on `bind`. It hands out connection ID 42,
+    /// answers announces with two peers (in 18-byte entries when bound to
+    /// IPv6) and scrapes with fixed stats. It drops the first `drop_first`
+    /// datagrams to exercise retransmission.
+    async fn spawn_stand_in_at(bind: &str, drop_first: usize) -> (String, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind(bind).await.unwrap();
+        let ipv6 = socket.local_addr().unwrap().is_ipv6();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        let connects = Arc::new(AtomicUsize::new(0));
+        let connects_clone = Arc::clone(&connects);
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            let mut seen = 0;
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                seen += 1;
+                if seen <= drop_first {
+                    continue;
+                }
+                let req = &buf[..len];
+                let transaction_id = &req[12..16];
+                let mut resp = Vec::new();
+
+                if u64::from_be_bytes(req[0..8].try_into().unwrap()) == PROTOCOL_ID {
+                    connects_clone.fetch_add(1, Ordering::SeqCst);
+                    resp.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+                    resp.extend_from_slice(transaction_id);
+                    resp.extend_from_slice(&42u64.to_be_bytes());
+                } else if u64::from_be_bytes(req[0..8].try_into().unwrap()) != 42 {
+                    resp.extend_from_slice(&ACTION_ERROR.to_be_bytes());
+                    resp.extend_from_slice(transaction_id);
+                    resp.extend_from_slice(b"bad connection id");
+                } else {
+                    match read_u32(&req[8..12]) {
+                        ACTION_ANNOUNCE => {
+                            assert_eq!(req.len(), 98);
+                            resp.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+                            resp.extend_from_slice(transaction_id);
+                            resp.extend_from_slice(&1800u32.to_be_bytes());
+                            resp.extend_from_slice(&1u32.to_be_bytes());
+                            resp.extend_from_slice(&2u32.to_be_bytes());
+                            if ipv6 {
+                                resp.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+                                resp.extend_from_slice(&[0x1A, 0xE1]);
+                                resp.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+                                resp.extend_from_slice(&[0x1A, 0xE2]);
+                            } else {
+                                resp.extend_from_slice(&[127, 0, 0, 1, 0x1A, 0xE1]);
+                                resp.extend_from_slice(&[10, 0, 0, 2, 0x1A, 0xE2]);
+                            }
+                        }
+                        ACTION_SCRAPE => {
+                            resp.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+                            resp.extend_from_slice(transaction_id);
+                            for _ in 0..(req.len() - 16) / 20 {
+                                resp.extend_from_slice(&5u32.to_be_bytes());
+                                resp.extend_from_slice(&7u32.to_be_bytes());
+                                resp.extend_from_slice(&9u32.to_be_bytes());
+                            }
+                        }
+                        _ => {
+                            resp.extend_from_slice(&ACTION_ERROR.to_be_bytes());
+                            resp.extend_from_slice(transaction_id);
+                            resp.extend_from_slice(b"unknown action");
+                        }
+                    }
+                }
+                socket.send_to(&resp, from).await.unwrap();
+            }
+        });
+        (url, connects)
+    }
+
+    async fn spawn_stand_in(drop_first: usize) -> (String, Arc<AtomicUsize>) {
+        spawn_stand_in_at("127.0.0.1:0", drop_first).await
+    }
+
+    #[tokio::test]
+    async fn test_udp_announce_and_scrape() {
+        let (url, connects) = spawn_stand_in(0).await;
+
+        let peers = announce(&url, &[1u8; 20], &[2u8; 20], 0, 0, 100, 6881).await.unwrap();
+        assert_eq!(peers, vec![
+            ("127.0.0.1".to_string(), 6881),
+            ("10.0.0.2".to_string(), 6882),
+        ]);
+
+        let stats = scrape(&url, &[[1u8; 20], [3u8; 20]]).await.unwrap();
+        assert_eq!(stats, vec![ScrapeStats { seeders: 5, completed: 7, leechers: 9 }; 2]);
+
+        // The second exchange reused the cached connection ID.
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_udp_announce_over_ipv6() {
+        let (url, _connects) = spawn_stand_in_at("[::1]:0", 0).await;
+
+        let peers = announce(&url, &[1u8; 20], &[2u8; 20], 0, 0, 100, 6881).await.unwrap();
+        assert_eq!(peers, vec![
+            ("[::1]".to_string(), 6881),
+            ("[2001:db8::2]".to_string(), 6882),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_udp_retransmits_after_timeout() {
+        let (url, _connects) = spawn_stand_in(1).await;
+        let mut tracker = UdpTracker::connect(&url).await.unwrap();
+        tracker.base_timeout = Duration::from_millis(50);
+        tracker.max_retransmissions = 3;
+
+        let peers = tracker.announce(&[1u8; 20], &[2u8; 20], 0, 0, 100, 6881).await.unwrap();
+        assert_eq!(peers.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_udp_error_action() {
+        let (url, _connects) = spawn_stand_in(0).await;
+        let tracker = UdpTracker::connect(&url).await.unwrap();
+        // Poison the cache with a connection ID the stand-in rejects.
+        connection_ids().lock().unwrap().insert(tracker.addr, (7, Instant::now()));
+
+        let err = tracker.announce(&[1u8; 20], &[2u8; 20], 0, 0, 100, 6881).await.unwrap_err();
+        assert!(err.to_string().contains("bad connection id"));
+        assert!(tracker.cached_connection_id().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_udp_gives_up() {
+        let (url, _connects) = spawn_stand_in(usize::MAX).await;
+        let mut tracker = UdpTracker::connect(&url).await.unwrap();
+        tracker.base_timeout = Duration::from_millis(10);
+        tracker.max_retransmissions = 2;
+
+        assert!(tracker.announce(&[1u8; 20], &[2u8; 20], 0, 0, 100, 6881).await.is_err());
+    }
+}
@@ -61,9 +61,9 @@ pub fn url_encode_bytes(bytes: &[u8]) -> String {
 /// = ALPHA / DIGIT / "-" / "." / "_" / "~"
 /// https://datatracker.ietf.org/doc/html/rfc3986
 fn is_unreserved(byte: u8) -> bool {
-    	   (byte >= b'a' && byte <= b'z')
-        || (byte >= b'A' && byte <= b'Z')
-        || (byte >= b'0' && byte <= b'9')
+    	   byte.is_ascii_lowercase()
+        || byte.is_ascii_uppercase()
+        || byte.is_ascii_digit()
         || byte == b'.'
         || byte == b'-'
         || byte == b'_'
@@ -0,0 +1,137 @@
+// verify.rs
+//! Rechecks data on disk against the piece hashes of a torrent.
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use crate::bitfield::Bitfield;
+use crate::file_io;
+use crate::piece_manager::verify_piece_data;
+use crate::torrent::TorrentInfo;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceStatus {
+    Good,
+    /// On disk, but the data does not match the hash.
+    Bad,
+    /// Not on disk: a file is absent or too short, or the piece was never
+    /// written (all zeros).
+    Missing,
+}
+
+/// The status of every piece of a torrent.
+#[derive(Debug)]
+pub struct VerifyReport {
+    pub pieces: Vec<PieceStatus>,
+}
+
+impl VerifyReport {
+    /// Indices of the pieces with the given status.
+    pub fn with_status(&self, status: PieceStatus) -> Vec<u32> {
+        (0..self.pieces.len() as u32)
+            .filter(|index| self.pieces[*index as usize] == status)
+            .collect()
+    }
+
+    /// The good pieces.
+    pub fn have(&self) -> Bitfield {
+        let mut have = Bitfield::new(self.pieces.len());
+        for index in self.with_status(PieceStatus::Good) {
+            have.set(index);
+        }
+        have
+    }
+}
+
+/// Hashes every piece of `info` found at `output_path`, with one worker per
+/// available core. Reads run concurrently and the hashing itself happens on
+/// the blocking thread pool, so it is parallel even on a single-threaded
+/// runtime.
+pub async fn verify_pieces(info: &TorrentInfo, output_path: &Path) -> VerifyReport {
+    let workers = std::thread::available_parallelism().map_or(4, |cores| cores.get());
+    let info = Arc::new(info.clone());
+    let next = Arc::new(AtomicU32::new(0));
+
+    let mut handles = Vec::new();
+    for _ in 0..workers.min(info.pieces.len()) {
+        let info = Arc::clone(&info);
+        let next = Arc::clone(&next);
+        let output_path = output_path.to_path_buf();
+        handles.push(tokio::spawn(async move {
+            let mut checked = Vec::new();
+            loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                if index as usize >= info.pieces.len() {
+                    return checked;
+                }
+                let status = match file_io::read_piece_range(&info, index, 0, info.piece_size(index), &output_path).await {
+                    Ok(data) => {
+                        let info = Arc::clone(&info);
+                        tokio::task::spawn_blocking(move || {
+                            if verify_piece_data(&info, index, &data) {
+                                PieceStatus::Good
+                            } else if data.iter().all(|byte| *byte == 0) {
+                                PieceStatus::Missing
+                            } else {
+                                PieceStatus::Bad
+                            }
+                        })
+                        .await
+                        .unwrap_or(PieceStatus::Bad)
+                    }
+                    Err(_) => PieceStatus::Missing,
+                };
+                checked.push((index, status));
+            }
+        }));
+    }
+
+    let mut pieces = vec![PieceStatus::Missing; info.pieces.len()];
+    for handle in handles {
+        for (index, status) in handle.await.unwrap_or_default() {
+            pieces[index as usize] = status;
+        }
+    }
+    VerifyReport { pieces }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha1::{Digest, Sha1};
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_reports_good_bad_and_missing_pieces() {
+        let content: Vec<u8> = (1..=8u8).collect();
+        let info = TorrentInfo {
+            length: 14,
+            name: "data.bin".to_string(),
+            piece_length: 4,
+            pieces: vec![
+                Sha1::digest(&content[0..4]).into(),
+                Sha1::digest(b"else").into(),
+                Sha1::digest([7u8; 4]).into(),
+                [0u8; 20],
+            ],
+            files: None,
+            extra: HashMap::new(),
+        };
+        // Piece 2 was never written and piece 3 lies past the end of the file.
+        let mut on_disk = content.clone();
+        on_disk.extend_from_slice(&[0; 4]);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("data.bin");
+        std::fs::write(&output, &on_disk).unwrap();
+
+        let report = verify_pieces(&info, &output).await;
+        assert_eq!(
+            report.pieces,
+            vec![PieceStatus::Good, PieceStatus::Bad, PieceStatus::Missing, PieceStatus::Missing]
+        );
+        assert_eq!(report.have().pieces().collect::<Vec<_>>(), vec![0]);
+
+        let report = verify_pieces(&info, &dir.path().join("absent.bin")).await;
+        assert_eq!(report.with_status(PieceStatus::Missing), vec![0, 1, 2, 3]);
+    }
+}